@@ -0,0 +1,197 @@
+// Роли пользователей, права на действия бота и списки доступа к хостам
+
+use std::{collections::HashMap, fmt, sync::Mutex};
+
+use anyhow::Result;
+
+use crate::{
+    store::{self, GrantedUser, Store},
+    Config, HostConfig,
+};
+
+// Вид ожидающей операции в базе для запросов доступа
+pub const ACCESS_REQUEST: &str = "access_request";
+
+lazy_static::lazy_static! {
+    // Пользователи, которым админ выдал доступ из чата; конфиг имеет приоритет над ними
+    static ref GRANTED: Mutex<HashMap<i64, Role>> = Mutex::new(HashMap::new());
+}
+
+// Роли упорядочены: каждая следующая может всё, что и предыдущая
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Role {
+    // Только смотрит статус
+    Viewer,
+    // Ещё и включает хосты
+    Operator,
+    // Ещё и выключает их и управляет ботом
+    Admin,
+}
+
+impl Role {
+    pub fn from_name(name: &str) -> Option<Role> {
+        match name.trim().to_lowercase().as_str() {
+            "viewer" => Some(Role::Viewer),
+            "operator" => Some(Role::Operator),
+            "admin" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+
+    pub fn allows(self, permission: Permission) -> bool {
+        self >= permission.min_role()
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Role::Viewer => "viewer",
+            Role::Operator => "operator",
+            Role::Admin => "admin",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Permission {
+    // Меню, список хостов, статус, подробности ошибок
+    View,
+    // Wake-on-LAN
+    Wake,
+    // Выключение
+    Shutdown,
+    // Ключи хостов, журнал действий и прочее управление ботом
+    Manage,
+}
+
+impl Permission {
+    // Имена действий в списках доступа хостов; Manage к хостам не относится
+    pub fn from_name(name: &str) -> Option<Permission> {
+        match name.trim().to_lowercase().as_str() {
+            "view" => Some(Permission::View),
+            "wake" => Some(Permission::Wake),
+            "shutdown" => Some(Permission::Shutdown),
+            _ => None,
+        }
+    }
+
+    fn min_role(self) -> Role {
+        match self {
+            Permission::View => Role::Viewer,
+            Permission::Wake => Role::Operator,
+            Permission::Shutdown | Permission::Manage => Role::Admin,
+        }
+    }
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Permission::View => "view",
+            Permission::Wake => "wake",
+            Permission::Shutdown => "shutdown",
+            Permission::Manage => "manage",
+        })
+    }
+}
+
+// Какое право нужно для действия из callback data; None — действие неизвестно
+pub fn permission_for(action: &str) -> Option<Permission> {
+    match action {
+        "hosts" | "host" | "status" | "details" | "cancel" | "approval_cancel" => Some(Permission::View),
+        "wol" => Some(Permission::Wake),
+        "shutdown_confirm" | "shutdown_yes" => Some(Permission::Shutdown),
+        "hostkey_ok" | "hostkey_no" | "access_role" | "access_ok" | "access_no" | "approval_ok"
+        | "approval_no" => Some(Permission::Manage),
+        _ => None,
+    }
+}
+
+pub fn user_role(config: &Config, user_id: u64) -> Option<Role> {
+    let user_id = user_id as i64;
+    config.roles.get(&user_id).copied().or_else(|| GRANTED.lock().unwrap().get(&user_id).copied())
+}
+
+pub fn is_granted(user_id: i64) -> bool {
+    GRANTED.lock().unwrap().contains_key(&user_id)
+}
+
+// Админы из конфига и назначенные из чата
+pub fn admin_ids(config: &Config) -> Vec<i64> {
+    let mut ids = config.admins.clone();
+    for (id, role) in GRANTED.lock().unwrap().iter() {
+        if *role == Role::Admin && !config.roles.contains_key(id) {
+            ids.push(*id);
+        }
+    }
+    ids
+}
+
+// Поднимает при запуске доступ, выданный до перезапуска
+pub fn load_granted(store: &Store) {
+    match store.granted_users() {
+        Ok(users) => {
+            let mut granted = GRANTED.lock().unwrap();
+            for user in users {
+                match Role::from_name(&user.role) {
+                    Some(role) => {
+                        granted.insert(user.user_id, role);
+                    }
+                    None => log::warn!("Неизвестная роль '{}' у пользователя {} в базе", user.role, user.user_id),
+                }
+            }
+            log::info!("Пользователей с доступом из чата: {}", granted.len());
+        }
+        Err(e) => log::warn!("Не удалось прочитать выданный доступ из базы: {:#}", e),
+    }
+}
+
+// Выдаёт доступ сразу, без перезапуска, и сохраняет его в базе
+pub fn grant(store: &Store, user_id: i64, name: &str, role: Role, granted_by: i64) -> Result<()> {
+    store.grant_user(&GrantedUser {
+        user_id,
+        name: name.to_string(),
+        role: role.to_string(),
+        granted_by,
+        granted_at: store::now(),
+    })?;
+    GRANTED.lock().unwrap().insert(user_id, role);
+    Ok(())
+}
+
+pub fn allows(config: &Config, user_id: u64, permission: Permission) -> bool {
+    user_role(config, user_id).is_some_and(|role| role.allows(permission))
+}
+
+// Запись списка доступа хоста: пользователи (группы уже развёрнуты в ID) и разрешённые им действия
+#[derive(Clone, Debug, PartialEq)]
+pub struct AclEntry {
+    pub users: Vec<i64>,
+    pub permissions: Vec<Permission>,
+}
+
+// Право на действие с конкретным хостом: роль должна его позволять, а список доступа хоста,
+// если он задан, — разрешать. Админов списки не ограничивают. Любое разрешённое действие
+// подразумевает и просмотр: иначе хост не попал бы в меню.
+pub fn allows_on(config: &Config, user_id: u64, host: &HostConfig, permission: Permission) -> bool {
+    allows(config, user_id, permission) && acl_allows(config, user_id, host, permission)
+}
+
+// Пропускает ли пользователя список доступа хоста (без учёта роли)
+pub fn acl_allows(config: &Config, user_id: u64, host: &HostConfig, permission: Permission) -> bool {
+    let Some(acl) = &host.acl else {
+        return true;
+    };
+    if user_role(config, user_id) == Some(Role::Admin) {
+        return true;
+    }
+    acl.iter()
+        .filter(|entry| entry.users.contains(&(user_id as i64)))
+        .any(|entry| permission == Permission::View || entry.permissions.contains(&permission))
+}
+
+// Хосты, которые пользователь видит в меню
+pub fn visible_hosts(config: &Config, user_id: u64) -> Vec<&HostConfig> {
+    config.hosts.iter().filter(|host| allows_on(config, user_id, host, Permission::View)).collect()
+}
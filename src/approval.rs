@@ -0,0 +1,289 @@
+// Подтверждение опасных действий другими админами: запрос, голоса и кворум в пределах окна.
+// Запросы хранятся в pending_ops, поэтому их номера не повторяются после перезапуска.
+
+use std::{sync::Mutex, time::Duration};
+
+use anyhow::Result;
+use serde_json::{json, Value};
+use teloxide::types::{ChatId, MessageId, User, UserId};
+
+use crate::{
+    store::{PendingOp, Store},
+    tunnel,
+};
+
+pub const KIND: &str = "approval";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ApprovalPolicy {
+    // Сколько других админов должны подтвердить действие
+    pub quorum: u32,
+    // Сколько ждём подтверждений, прежде чем отменить запрос
+    pub window: Duration,
+}
+
+impl Default for ApprovalPolicy {
+    fn default() -> Self {
+        ApprovalPolicy { quorum: 1, window: Duration::from_secs(600) }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Status {
+    Waiting,
+    Approved,
+    Rejected { by: String },
+    Expired,
+}
+
+// Запрос, ожидающий подтверждения
+#[derive(Clone, Debug)]
+pub struct Approval {
+    pub action: String,
+    pub host: String,
+    pub requester: User,
+    // Сообщение запросившего, в котором виден ход подтверждения
+    pub message: Option<(ChatId, MessageId)>,
+    // Кто может подтвердить: админы, кроме самого запросившего
+    pub approvers: Vec<i64>,
+    pub policy: ApprovalPolicy,
+    // Unix-время, после которого голоса не принимаются
+    pub deadline: i64,
+    // Уже подтвердившие: ID и имя для сообщений
+    pub approvals: Vec<(i64, String)>,
+    // Сообщения с кнопками у админов, чтобы убрать кнопки после решения
+    pub notices: Vec<(ChatId, MessageId)>,
+}
+
+impl Approval {
+    pub fn new(
+        action: &str,
+        host: &str,
+        requester: User,
+        message: Option<(ChatId, MessageId)>,
+        approvers: Vec<i64>,
+        policy: ApprovalPolicy,
+        now: i64,
+    ) -> Self {
+        Approval {
+            action: action.to_string(),
+            host: host.to_string(),
+            requester,
+            message,
+            approvers,
+            policy,
+            deadline: now + policy.window.as_secs() as i64,
+            approvals: Vec::new(),
+            notices: Vec::new(),
+        }
+    }
+
+    // Голос админа; Err — голос не принят, с объяснением для всплывающего сообщения
+    pub fn vote(&mut self, admin: i64, name: &str, approve: bool, now: i64) -> Result<Status, String> {
+        if now >= self.deadline {
+            return Ok(Status::Expired);
+        }
+        if admin == self.requester.id.0 as i64 {
+            return Err("Нельзя подтвердить собственный запрос".to_string());
+        }
+        if !self.approvers.contains(&admin) {
+            return Err("Вы не можете подтверждать этот запрос".to_string());
+        }
+        if self.approvals.iter().any(|(id, _)| *id == admin) {
+            return Err("Вы уже подтвердили этот запрос".to_string());
+        }
+        if !approve {
+            return Ok(Status::Rejected { by: name.to_string() });
+        }
+        self.approvals.push((admin, name.to_string()));
+        if self.approvals.len() as u32 >= self.policy.quorum {
+            Ok(Status::Approved)
+        } else {
+            Ok(Status::Waiting)
+        }
+    }
+
+    // Ход подтверждения для запросившего: сколько голосов, чьи и сколько осталось времени.
+    // subject — что подтверждается, например «Выключение NAS».
+    pub fn progress(&self, subject: &str, now: i64) -> String {
+        let mut text = format!(
+            "🛡 {} ждёт подтверждения админов: {} из {}",
+            subject,
+            self.approvals.len(),
+            self.policy.quorum
+        );
+        for (_, name) in &self.approvals {
+            text.push_str(&format!("\n✅ {}", name));
+        }
+        text.push_str(&format!(
+            "\n\n⏳ Осталось {}",
+            tunnel::format_duration(self.remaining(now))
+        ));
+        text
+    }
+
+    pub fn remaining(&self, now: i64) -> Duration {
+        Duration::from_secs(self.deadline.saturating_sub(now).max(0) as u64)
+    }
+
+    pub fn approver_names(&self) -> String {
+        self.approvals.iter().map(|(_, name)| name.as_str()).collect::<Vec<_>>().join(", ")
+    }
+}
+
+// ------------------------------------------------------------------
+// Хранение в базе
+
+fn pair((chat, message): (ChatId, MessageId)) -> Value {
+    json!([chat.0, message.0])
+}
+
+fn unpair(value: &Value) -> Option<(ChatId, MessageId)> {
+    Some((ChatId(value[0].as_i64()?), MessageId(value[1].as_i64()?.try_into().ok()?)))
+}
+
+fn encode(approval: &Approval) -> String {
+    let requester = &approval.requester;
+    json!({
+        "action": approval.action,
+        "requester": {
+            "id": requester.id.0,
+            "first_name": requester.first_name,
+            "last_name": requester.last_name,
+            "username": requester.username,
+        },
+        "message": approval.message.map(pair),
+        "approvers": approval.approvers,
+        "quorum": approval.policy.quorum,
+        "window": approval.policy.window.as_secs(),
+        "approvals": approval.approvals,
+        "notices": approval.notices.iter().copied().map(pair).collect::<Vec<_>>(),
+    })
+    .to_string()
+}
+
+fn decode(op: &PendingOp) -> Option<Approval> {
+    let payload = serde_json::from_str::<Value>(&op.payload).ok()?;
+    let requester = &payload["requester"];
+    let text = |value: &Value| value.as_str().map(str::to_string);
+    Some(Approval {
+        action: payload["action"].as_str()?.to_string(),
+        host: op.host.clone()?,
+        requester: User {
+            id: UserId(requester["id"].as_u64()?),
+            is_bot: false,
+            first_name: text(&requester["first_name"])?,
+            last_name: text(&requester["last_name"]),
+            username: text(&requester["username"]),
+            language_code: None,
+            is_premium: false,
+            added_to_attachment_menu: false,
+        },
+        message: unpair(&payload["message"]),
+        approvers: payload["approvers"].as_array()?.iter().filter_map(Value::as_i64).collect(),
+        policy: ApprovalPolicy {
+            quorum: payload["quorum"].as_u64()?.try_into().ok()?,
+            window: Duration::from_secs(payload["window"].as_u64()?),
+        },
+        deadline: op.expires_at?,
+        approvals: payload["approvals"]
+            .as_array()?
+            .iter()
+            .filter_map(|vote| Some((vote[0].as_i64()?, vote[1].as_str()?.to_string())))
+            .collect(),
+        notices: payload["notices"].as_array()?.iter().filter_map(unpair).collect(),
+    })
+}
+
+lazy_static::lazy_static! {
+    // Голос читает запрос и переписывает его; без блокировки одновременные голоса затрут друг друга
+    static ref LOCK: Mutex<()> = Mutex::new(());
+}
+
+fn load(store: &Store, id: u64) -> Result<Option<Approval>> {
+    let Some(op) = store.pending_op(id as i64)?.filter(|op| op.kind == KIND) else {
+        return Ok(None);
+    };
+    match decode(&op) {
+        Some(approval) => Ok(Some(approval)),
+        None => {
+            log::warn!("Повреждённый запрос подтверждения {} удалён", id);
+            store.remove_pending_op(op.id)?;
+            Ok(None)
+        }
+    }
+}
+
+pub fn open(store: &Store, approval: &Approval) -> Result<u64> {
+    let id = store.add_pending_op(&PendingOp {
+        id: 0,
+        kind: KIND.to_string(),
+        host: Some(approval.host.clone()),
+        requested_by: approval.requester.id.0 as i64,
+        created_at: approval.deadline - approval.policy.window.as_secs() as i64,
+        expires_at: Some(approval.deadline),
+        payload: encode(approval),
+    })?;
+    Ok(id as u64)
+}
+
+pub fn add_notice(store: &Store, id: u64, notice: (ChatId, MessageId)) -> Result<()> {
+    let _lock = LOCK.lock().unwrap();
+    if let Some(mut approval) = load(store, id)? {
+        approval.notices.push(notice);
+        store.update_pending_op(id as i64, &encode(&approval))?;
+    }
+    Ok(())
+}
+
+pub fn get(store: &Store, id: u64) -> Result<Option<Approval>> {
+    load(store, id)
+}
+
+// Голосует и, если запрос решён, убирает его из ожидающих. None — запроса уже нет.
+pub fn vote(
+    store: &Store,
+    id: u64,
+    admin: i64,
+    name: &str,
+    approve: bool,
+    now: i64,
+) -> Result<Option<(Approval, Result<Status, String>)>> {
+    let _lock = LOCK.lock().unwrap();
+    let Some(mut approval) = load(store, id)? else {
+        return Ok(None);
+    };
+    let result = approval.vote(admin, name, approve, now);
+    match result {
+        Ok(Status::Waiting) => {
+            store.update_pending_op(id as i64, &encode(&approval))?;
+        }
+        Err(_) => {}
+        Ok(_) => {
+            if !store.remove_pending_op(id as i64)? {
+                return Ok(None);
+            }
+        }
+    }
+    Ok(Some((approval, result)))
+}
+
+pub fn take(store: &Store, id: u64) -> Result<Option<Approval>> {
+    let _lock = LOCK.lock().unwrap();
+    let approval = load(store, id)?;
+    if approval.is_some() && !store.remove_pending_op(id as i64)? {
+        return Ok(None);
+    }
+    Ok(approval)
+}
+
+// Запросы, оставшиеся от прошлого запуска: следить за ними уже некому, поэтому забираем все
+pub fn take_all(store: &Store) -> Result<Vec<(u64, Approval)>> {
+    let mut taken = Vec::new();
+    for op in store.pending_ops(KIND)? {
+        if let Some(approval) = take(store, op.id as u64)? {
+            taken.push((op.id as u64, approval));
+        }
+    }
+    Ok(taken)
+}
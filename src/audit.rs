@@ -0,0 +1,263 @@
+// Журнал привилегированных действий: кто, что, с каким хостом, когда и с каким итогом
+
+use anyhow::Result;
+use chrono::{NaiveDate, NaiveDateTime, TimeZone, Utc};
+use teloxide::types::User;
+
+use crate::{store, HostConfig};
+
+// Сколько записей показывать в чате по умолчанию и максимум
+const DEFAULT_LIMIT: usize = 20;
+const MAX_CHAT_LIMIT: usize = 50;
+// Выгрузка файлом ограничена, чтобы не упереться в размер документа Telegram
+const MAX_EXPORT_LIMIT: usize = 10_000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+    Ok,
+    Failed,
+    Denied,
+}
+
+impl Outcome {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Outcome::Ok => "ok",
+            Outcome::Failed => "error",
+            Outcome::Denied => "denied",
+        }
+    }
+
+    fn icon(name: &str) -> &'static str {
+        match name {
+            "ok" => "✅",
+            "denied" => "⛔",
+            _ => "❌",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuditEvent {
+    pub at: i64,
+    pub user_id: i64,
+    pub user_name: String,
+    pub action: String,
+    pub host: Option<String>,
+    pub result: String,
+    pub details: String,
+}
+
+// Фильтр /audit; пустые поля не ограничивают выборку
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AuditFilter {
+    // Числовой ID или имя пользователя (без @)
+    pub user: Option<String>,
+    pub host: Option<String>,
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+    pub limit: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExportFormat {
+    Csv,
+    Jsonl,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuditQuery {
+    pub filter: AuditFilter,
+    pub format: Option<ExportFormat>,
+}
+
+// Как показывать пользователя в журнале: @username, иначе полное имя
+pub fn user_name(user: &User) -> String {
+    match &user.username {
+        Some(username) => format!("@{}", username),
+        None => user.full_name(),
+    }
+}
+
+// Записывает действие в журнал. Сбой записи не должен мешать самому действию — только логируем.
+pub fn record(user: &User, action: &str, host: Option<&HostConfig>, outcome: Outcome, details: &str) {
+    let event = AuditEvent {
+        at: store::now(),
+        user_id: user.id.0 as i64,
+        user_name: user_name(user),
+        action: action.to_string(),
+        host: host.map(|h| h.id.clone()),
+        result: outcome.name().to_string(),
+        details: details.to_string(),
+    };
+    log::info!(
+        "Аудит: {} ({}) {} {} -> {} {}",
+        event.user_name,
+        event.user_id,
+        event.action,
+        event.host.as_deref().unwrap_or("-"),
+        event.result,
+        event.details
+    );
+    match store::get() {
+        Some(store) => {
+            if let Err(e) = store.record_audit(&event) {
+                log::error!("Не удалось записать событие аудита: {:#}", e);
+            }
+        }
+        None => log::warn!("База не открыта, событие аудита не сохранено"),
+    }
+}
+
+// Разбирает аргументы /audit: user=alice host=server since=24h until=2024-05-01 limit=20 format=csv
+pub fn parse_query(args: &str, now: i64) -> Result<AuditQuery> {
+    let mut filter = AuditFilter { limit: DEFAULT_LIMIT, ..AuditFilter::default() };
+    let mut format = None;
+    let mut limit = None;
+
+    for arg in args.split_whitespace() {
+        let (key, value) = arg
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("'{}': ожидалось ключ=значение", arg))?;
+        match key {
+            "user" => filter.user = Some(value.trim_start_matches('@').to_string()),
+            "host" => filter.host = Some(value.to_lowercase()),
+            "since" => filter.since = Some(parse_time(value, now)?),
+            "until" => filter.until = Some(parse_time(value, now)?),
+            "limit" => {
+                limit = Some(
+                    value
+                        .parse::<usize>()
+                        .ok()
+                        .filter(|l| *l > 0)
+                        .ok_or_else(|| anyhow::anyhow!("limit: ожидалось положительное число, а не '{}'", value))?,
+                )
+            }
+            "format" => {
+                format = Some(match value.to_lowercase().as_str() {
+                    "csv" => ExportFormat::Csv,
+                    "jsonl" => ExportFormat::Jsonl,
+                    other => anyhow::bail!("format: неизвестный формат '{}', допустимы csv и jsonl", other),
+                })
+            }
+            other => anyhow::bail!("неизвестный фильтр '{}', допустимы user, host, since, until, limit, format", other),
+        }
+    }
+
+    filter.limit = match (format, limit) {
+        (Some(_), limit) => limit.unwrap_or(MAX_EXPORT_LIMIT).min(MAX_EXPORT_LIMIT),
+        (None, limit) => limit.unwrap_or(DEFAULT_LIMIT).min(MAX_CHAT_LIMIT),
+    };
+    Ok(AuditQuery { filter, format })
+}
+
+// Время в фильтре: относительное (30m, 24h, 7d) или дата/дата и время в UTC
+fn parse_time(value: &str, now: i64) -> Result<i64> {
+    let usage = || anyhow::anyhow!("'{}': ожидалось 30m, 24h, 7d, 2024-05-01 или 2024-05-01T12:30", value);
+    let units = [('m', 60), ('h', 3600), ('d', 86400)];
+    if let Some((count, unit)) = units
+        .iter()
+        .find_map(|(suffix, secs)| value.strip_suffix(*suffix).map(|count| (count, *secs)))
+    {
+        if let Ok(count) = count.parse::<i64>() {
+            // Слишком большой срок не должен переполнить арифметику
+            return count.checked_mul(unit).and_then(|d| now.checked_sub(d)).ok_or_else(usage);
+        }
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return Ok(Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0).unwrap()).timestamp());
+    }
+    for pattern in ["%Y-%m-%dT%H:%M", "%Y-%m-%dT%H:%M:%S"] {
+        if let Ok(time) = NaiveDateTime::parse_from_str(value, pattern) {
+            return Ok(Utc.from_utc_datetime(&time).timestamp());
+        }
+    }
+    Err(usage())
+}
+
+pub fn format_time(at: i64) -> String {
+    Utc.timestamp_opt(at, 0)
+        .single()
+        .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_else(|| at.to_string())
+}
+
+// Список для чата, новые записи сверху
+pub fn render(events: &[AuditEvent]) -> String {
+    if events.is_empty() {
+        return "📜 Записей не найдено.".to_string();
+    }
+    let lines = events
+        .iter()
+        .map(|e| {
+            let mut line = format!(
+                "{} {} {} {} {}",
+                Outcome::icon(&e.result),
+                format_time(e.at),
+                e.user_name,
+                e.action,
+                e.host.as_deref().unwrap_or("-")
+            );
+            if !e.details.is_empty() {
+                line.push_str(&format!(" — {}", e.details));
+            }
+            line
+        })
+        .collect::<Vec<_>>();
+    format!("📜 Журнал действий (UTC), записей: {}\n\n{}", events.len(), lines.join("\n"))
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+pub fn export(events: &[AuditEvent], format: ExportFormat) -> String {
+    match format {
+        ExportFormat::Csv => {
+            let mut out = String::from("time,user_id,user_name,action,host,result,details\n");
+            for e in events {
+                let fields = [
+                    format_time(e.at),
+                    e.user_id.to_string(),
+                    e.user_name.clone(),
+                    e.action.clone(),
+                    e.host.clone().unwrap_or_default(),
+                    e.result.clone(),
+                    e.details.clone(),
+                ];
+                out.push_str(&fields.iter().map(|f| csv_field(f)).collect::<Vec<_>>().join(","));
+                out.push('\n');
+            }
+            out
+        }
+        ExportFormat::Jsonl => events
+            .iter()
+            .map(|e| {
+                serde_json::json!({
+                    "time": format_time(e.at),
+                    "at": e.at,
+                    "user_id": e.user_id,
+                    "user_name": e.user_name,
+                    "action": e.action,
+                    "host": e.host,
+                    "result": e.result,
+                    "details": e.details,
+                })
+                .to_string()
+                    + "\n"
+            })
+            .collect(),
+    }
+}
+
+pub fn file_name(format: ExportFormat, now: i64) -> String {
+    let stamp = Utc.timestamp_opt(now, 0).single().map(|t| t.format("%Y%m%d-%H%M").to_string()).unwrap_or_default();
+    match format {
+        ExportFormat::Csv => format!("audit-{}.csv", stamp),
+        ExportFormat::Jsonl => format!("audit-{}.jsonl", stamp),
+    }
+}
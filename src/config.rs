@@ -0,0 +1,1235 @@
+// Загрузка конфигурации: TOML-файл + переопределения из переменных окружения.
+//
+// Сначала читается файл (CONFIG_PATH или /app/config.toml, если он есть), затем
+// поверх него накладываются переменные окружения старого формата (BOT_TOKEN,
+// SERVER_MAC, ROUTER_SSH_* ...), и только после этого вся таблица проверяется
+// целиком — так пользователь видит сразу все ошибки с путями к ключам.
+
+use std::{
+    collections::HashMap,
+    env,
+    fmt::Display,
+    net::{IpAddr, Ipv4Addr},
+    path::Path,
+    time::Duration,
+};
+
+use anyhow::Result;
+use toml::{Table, Value};
+
+use crate::{
+    access::{AclEntry, Permission, Role},
+    approval::ApprovalPolicy,
+    is_valid_host_id, is_valid_mac,
+    monitor::MonitorConfig,
+    report, store,
+    totp::TotpConfig,
+    ssh::{AuthMethod, HostKeyPolicy, Timeouts},
+    tunnel::{self, Direction, TunnelConfig},
+    wol::{self, CommandTemplate, RouterCommand, SecureOn, UdpSender, WakeRetry, WolTransport},
+    Config, HostConfig, SshTarget,
+};
+
+const DEFAULT_CONFIG_PATH: &str = "/app/config.toml";
+
+const WOL_FIELDS: [(&str, &str); 9] = [
+    ("TRANSPORT", "transport"),
+    ("BROADCAST", "broadcast"),
+    ("PORT", "port"),
+    ("COMMAND", "command"),
+    ("INTERFACE", "interface"),
+    ("TEMPLATE", "template"),
+    ("ATTEMPTS", "attempts"),
+    ("GRACE_PERIOD", "grace_period"),
+    ("RETRY_INTERVAL", "retry_interval"),
+];
+
+const SSH_FIELDS: [(&str, &str); 7] = [
+    ("HOST", "host"),
+    ("PORT", "port"),
+    ("USER", "user"),
+    ("KEY_PATH", "key"),
+    ("KEY_PASSPHRASE_FILE", "passphrase_file"),
+    ("AUTH", "auth"),
+    ("JUMP", "jump"),
+];
+
+impl Config {
+    // Основная точка входа: файл конфигурации + переменные окружения
+    pub fn load() -> Result<Self> {
+        let (path, required) = match env::var("CONFIG_PATH") {
+            Ok(path) => (path, true),
+            Err(_) => (DEFAULT_CONFIG_PATH.to_string(), false),
+        };
+
+        let table = if Path::new(&path).exists() {
+            println!("Читаю файл конфигурации {}...", path);
+            log::info!("Читаю файл конфигурации {}", path);
+            let text = std::fs::read_to_string(&path)
+                .map_err(|e| anyhow::anyhow!("Не удалось прочитать {}: {}", path, e))?;
+            text.parse::<Table>()
+                .map_err(|e| anyhow::anyhow!("Ошибка синтаксиса в {}: {}", path, e))?
+        } else if required {
+            anyhow::bail!("Файл конфигурации {} не найден", path);
+        } else {
+            println!("Файл конфигурации не найден, используются только переменные окружения");
+            log::info!("Файл {} не найден, используются только переменные окружения", path);
+            Table::new()
+        };
+
+        Self::from_sources(table, &|key| env::var(key).ok())
+    }
+
+    // Накладывает переменные окружения на таблицу из файла и проверяет результат
+    pub fn from_sources(mut table: Table, env: &dyn Fn(&str) -> Option<String>) -> Result<Self> {
+        let mut loader = Loader::default();
+        loader.apply_env(&mut table, env);
+
+        match loader.build(&table) {
+            Some(config) if loader.errors.is_empty() => {
+                for w in &config.warnings {
+                    println!("ПРЕДУПРЕЖДЕНИЕ КОНФИГУРАЦИИ: {}", w);
+                    log::warn!("Предупреждение конфигурации: {}", w);
+                }
+                println!(
+                    "Конфигурация: {} хост(ов), {} пользовател(ей)",
+                    config.hosts.len(),
+                    config.allowed_users.len()
+                );
+                for host in &config.hosts {
+                    println!("Хост '{}' ({}): MAC {}", host.id, host.name, host.mac);
+                    log::info!("Хост '{}' ({}): MAC {}", host.id, host.name, host.mac);
+                }
+                Ok(config)
+            }
+            _ => {
+                for e in &loader.errors {
+                    println!("ОШИБКА КОНФИГУРАЦИИ: {}", e);
+                    log::error!("Ошибка конфигурации: {}", e);
+                }
+                Err(anyhow::anyhow!(
+                    "Некорректная конфигурация:\n  - {}",
+                    loader.errors.join("\n  - ")
+                ))
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct Loader {
+    errors: Vec<String>,
+    // Не мешают запуску, но о них стоит знать
+    warnings: Vec<String>,
+    // Путь ключа -> переменная окружения, из которой он пришёл (для сообщений об ошибках)
+    sources: HashMap<String, String>,
+    // Промежуточные хосты из [jump_hosts], на которые ссылаются цепочки jump
+    jump_hosts: HashMap<String, SshTarget>,
+    // Имена пользователей из [users] -> их ID, для групп и списков доступа
+    user_ids: HashMap<String, i64>,
+    // Группы пользователей из [groups], уже развёрнутые в ID
+    groups: HashMap<String, Vec<i64>>,
+}
+
+fn join(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::String(_) => "строка",
+        Value::Integer(_) => "целое число",
+        Value::Float(_) => "дробное число",
+        Value::Boolean(_) => "логическое значение",
+        Value::Datetime(_) => "дата",
+        Value::Array(_) => "массив",
+        Value::Table(_) => "таблица",
+    }
+}
+
+impl Loader {
+    fn error(&mut self, path: &str, msg: impl Display) {
+        match self.sources.get(path) {
+            Some(var) => self.errors.push(format!("{} (из {}): {}", path, var, msg)),
+            None => self.errors.push(format!("{}: {}", path, msg)),
+        }
+    }
+
+    fn warning(&mut self, path: &str, msg: impl Display) {
+        match self.sources.get(path) {
+            Some(var) => self.warnings.push(format!("{} (из {}): {}", path, var, msg)),
+            None => self.warnings.push(format!("{}: {}", path, msg)),
+        }
+    }
+
+    // ------------------------------------------------------------------
+    // Переопределения из окружения
+
+    fn set(&mut self, table: &mut Table, path: &[&str], value: Value, var: &str) {
+        let (last, parents) = path.split_last().expect("путь не может быть пустым");
+        let mut current = table;
+        for key in parents {
+            let entry = current
+                .entry(key.to_string())
+                .or_insert_with(|| Value::Table(Table::new()));
+            if !entry.is_table() {
+                *entry = Value::Table(Table::new());
+            }
+            current = entry.as_table_mut().expect("только что создана таблица");
+        }
+        current.insert(last.to_string(), value);
+        self.sources.insert(path.join("."), var.to_string());
+    }
+
+    fn ensure_table(&mut self, table: &mut Table, path: &[&str]) {
+        let mut current = table;
+        for key in path {
+            let entry = current
+                .entry(key.to_string())
+                .or_insert_with(|| Value::Table(Table::new()));
+            match entry.as_table_mut() {
+                Some(t) => current = t,
+                None => return,
+            }
+        }
+    }
+
+    fn apply_env(&mut self, table: &mut Table, env: &dyn Fn(&str) -> Option<String>) {
+        let set_var = |loader: &mut Self, table: &mut Table, var: &str, path: &[&str]| {
+            if let Some(value) = env(var) {
+                loader.set(table, path, Value::String(value), var);
+                true
+            } else {
+                false
+            }
+        };
+
+        set_var(self, table, "BOT_TOKEN", &["bot_token"]);
+        set_var(self, table, "SSH_TIMEOUT", &["timeouts", "ssh"]);
+        set_var(self, table, "SSH_CONNECT_TIMEOUT", &["timeouts", "connect"]);
+        set_var(self, table, "SSH_HANDSHAKE_TIMEOUT", &["timeouts", "handshake"]);
+        set_var(self, table, "SSH_AUTH_TIMEOUT", &["timeouts", "auth"]);
+        set_var(self, table, "SSH_EXEC_TIMEOUT", &["timeouts", "exec"]);
+        set_var(self, table, "NC_TIMEOUT", &["timeouts", "status"]);
+        set_var(self, table, "BOOT_TIMEOUT", &["timeouts", "boot"]);
+        set_var(self, table, "KNOWN_HOSTS_PATH", &["known_hosts", "path"]);
+        set_var(self, table, "SSH_TRUST_ON_FIRST_USE", &["known_hosts", "trust_on_first_use"]);
+        set_var(self, table, "MONITOR_INTERVAL", &["monitor", "interval"]);
+        set_var(self, table, "DATABASE_PATH", &["storage", "path"]);
+        set_var(self, table, "MONITOR_CONFIRMATIONS", &["monitor", "confirmations"]);
+        set_var(self, table, "TOTP_ACTIONS", &["totp", "actions"]);
+        set_var(self, table, "TOTP_ISSUER", &["totp", "issuer"]);
+
+        // ALLOWED_USERS добавляет пользователей к описанным в файле
+        if let Some(list) = env("ALLOWED_USERS") {
+            for id in list.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+                self.set(table, &["users", id, "id"], Value::String(id.to_string()), "ALLOWED_USERS");
+            }
+        }
+        if let Some(list) = env("ADMIN_USERS") {
+            for id in list.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+                self.set(table, &["users", id, "id"], Value::String(id.to_string()), "ADMIN_USERS");
+                self.set(table, &["users", id, "admin"], Value::Boolean(true), "ADMIN_USERS");
+            }
+        }
+        for (var, role) in [("OPERATOR_USERS", "operator"), ("VIEWER_USERS", "viewer")] {
+            if let Some(list) = env(var) {
+                for id in list.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+                    self.set(table, &["users", id, "id"], Value::String(id.to_string()), var);
+                    self.set(table, &["users", id, "role"], Value::String(role.to_string()), var);
+                }
+            }
+        }
+        if let Some(list) = env("NOTIFY_USERS") {
+            for id in list.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+                self.set(table, &["users", id, "id"], Value::String(id.to_string()), "NOTIFY_USERS");
+                self.set(table, &["users", id, "notify"], Value::Boolean(true), "NOTIFY_USERS");
+            }
+        }
+
+        for (suffix, field) in SSH_FIELDS {
+            set_var(self, table, &format!("ROUTER_SSH_{}", suffix), &["routers", "default", field]);
+        }
+
+        let listed = env("HOSTS").map(|list| {
+            list.split(',')
+                .map(|s| s.trim().to_lowercase())
+                .filter(|s| !s.is_empty())
+                .collect::<Vec<_>>()
+        });
+
+        // HOST_<ID>_* применяются и к хостам из файла, и к перечисленным в HOSTS
+        let mut ids = table
+            .get("hosts")
+            .and_then(Value::as_table)
+            .map(|t| t.keys().cloned().collect::<Vec<_>>())
+            .unwrap_or_default();
+        for id in listed.iter().flatten() {
+            if !ids.contains(id) {
+                ids.push(id.clone());
+            }
+        }
+
+        for id in &ids {
+            let in_list = listed.as_ref().is_some_and(|l| l.contains(id));
+            let prefix = format!("HOST_{}_", id.to_uppercase().replace('-', "_"));
+            if in_list {
+                self.ensure_table(table, &["hosts", id]);
+            }
+            set_var(self, table, &format!("{}NAME", prefix), &["hosts", id, "name"]);
+            set_var(self, table, &format!("{}MAC", prefix), &["hosts", id, "mac"]);
+            set_var(self, table, &format!("{}SECUREON", prefix), &["hosts", id, "secureon"]);
+            set_var(self, table, &format!("{}BOOT_TIMEOUT", prefix), &["hosts", id, "boot_timeout"]);
+            set_var(self, table, &format!("{}LAN_IP", prefix), &["hosts", id, "lan_ip"]);
+            set_var(self, table, &format!("{}MOUNTS", prefix), &["hosts", id, "mounts"]);
+            set_var(self, table, &format!("{}MONITOR_INTERVAL", prefix), &["hosts", id, "monitor_interval"]);
+            set_var(self, table, &format!("{}APPROVAL_QUORUM", prefix), &["hosts", id, "approval", "quorum"]);
+            set_var(self, table, &format!("{}APPROVAL_WINDOW", prefix), &["hosts", id, "approval", "window"]);
+            for (suffix, field) in WOL_FIELDS {
+                set_var(self, table, &format!("{}WOL_{}", prefix, suffix), &["hosts", id, "wol", field]);
+            }
+
+            // SERVER_SSH_* служат значениями по умолчанию для всех хостов из HOSTS
+            for (suffix, field) in SSH_FIELDS {
+                if !set_var(self, table, &format!("{}SSH_{}", prefix, suffix), &["hosts", id, "ssh", field])
+                    && in_list
+                {
+                    set_var(self, table, &format!("SERVER_SSH_{}", suffix), &["hosts", id, "ssh", field]);
+                }
+            }
+
+            // Собственный роутер хоста: копия общего с переопределёнными полями
+            let router_vars = SSH_FIELDS
+                .iter()
+                .filter_map(|(suffix, field)| {
+                    let var = format!("{}ROUTER_SSH_{}", prefix, suffix);
+                    env(&var).map(|value| (var, *field, value))
+                })
+                .collect::<Vec<_>>();
+            if !router_vars.is_empty() {
+                let base = table
+                    .get("routers")
+                    .and_then(|r| r.get("default"))
+                    .cloned()
+                    .unwrap_or_else(|| Value::Table(Table::new()));
+                self.set(table, &["routers", id], base, &format!("{}ROUTER_SSH_*", prefix));
+                for (var, field, value) in router_vars {
+                    self.set(table, &["routers", id, field], Value::String(value), &var);
+                }
+                self.set(table, &["hosts", id, "router"], Value::String(id.clone()), &format!("{}ROUTER_SSH_*", prefix));
+            }
+        }
+
+        if listed.is_none() {
+            // Старый формат с единственным сервером
+            let mut any = set_var(self, table, "SERVER_MAC", &["hosts", "server", "mac"]);
+            any |= set_var(self, table, "SERVER_NAME", &["hosts", "server", "name"]);
+            any |= set_var(self, table, "SERVER_SECUREON", &["hosts", "server", "secureon"]);
+            any |= set_var(self, table, "SERVER_BOOT_TIMEOUT", &["hosts", "server", "boot_timeout"]);
+            any |= set_var(self, table, "SERVER_LAN_IP", &["hosts", "server", "lan_ip"]);
+            any |= set_var(self, table, "SERVER_MOUNTS", &["hosts", "server", "mounts"]);
+            any |= set_var(self, table, "SERVER_MONITOR_INTERVAL", &["hosts", "server", "monitor_interval"]);
+            any |= set_var(self, table, "SERVER_APPROVAL_QUORUM", &["hosts", "server", "approval", "quorum"]);
+            any |= set_var(self, table, "SERVER_APPROVAL_WINDOW", &["hosts", "server", "approval", "window"]);
+            for (suffix, field) in WOL_FIELDS {
+                any |= set_var(self, table, &format!("SERVER_WOL_{}", suffix), &["hosts", "server", "wol", field]);
+            }
+            for (suffix, field) in SSH_FIELDS {
+                any |= set_var(self, table, &format!("SERVER_SSH_{}", suffix), &["hosts", "server", "ssh", field]);
+            }
+            let server = table.get("hosts").and_then(|h| h.get("server"));
+            if any && server.and_then(|s| s.get("name")).is_none() {
+                self.set(table, &["hosts", "server", "name"], Value::String("Сервер".into()), "SERVER_MAC");
+            }
+        }
+    }
+
+    // ------------------------------------------------------------------
+    // Проверка и сборка
+
+    fn check_keys(&mut self, table: &Table, path: &str, known: &[&str]) {
+        for key in table.keys() {
+            if !known.contains(&key.as_str()) {
+                self.error(&join(path, key), "неизвестный ключ");
+            }
+        }
+    }
+
+    fn table<'a>(&mut self, parent: &'a Table, path: &str, key: &str) -> Option<&'a Table> {
+        match parent.get(key) {
+            None => None,
+            Some(Value::Table(t)) => Some(t),
+            Some(other) => {
+                self.error(&join(path, key), format!("ожидалась таблица, а не {}", type_name(other)));
+                None
+            }
+        }
+    }
+
+    fn opt_str(&mut self, parent: &Table, path: &str, key: &str) -> Option<String> {
+        match parent.get(key) {
+            None => None,
+            Some(Value::String(s)) if s.trim().is_empty() => {
+                self.error(&join(path, key), "пустая строка");
+                None
+            }
+            Some(Value::String(s)) => Some(s.clone()),
+            Some(other) => {
+                self.error(&join(path, key), format!("ожидалась строка, а не {}", type_name(other)));
+                None
+            }
+        }
+    }
+
+    fn str(&mut self, parent: &Table, path: &str, key: &str) -> Option<String> {
+        if !parent.contains_key(key) {
+            self.error(&join(path, key), "обязательный ключ отсутствует");
+            return None;
+        }
+        self.opt_str(parent, path, key)
+    }
+
+    fn opt_bool(&mut self, parent: &Table, path: &str, key: &str) -> Option<bool> {
+        match parent.get(key)? {
+            Value::Boolean(b) => Some(*b),
+            Value::String(s) if s == "true" || s == "false" => Some(s == "true"),
+            other => {
+                self.error(&join(path, key), format!("ожидалось true или false, а не {}", type_name(other)));
+                None
+            }
+        }
+    }
+
+    // Целые числа принимаются и как строки — значения из окружения всегда строковые
+    fn opt_int(&mut self, parent: &Table, path: &str, key: &str, min: i64, max: i64) -> Option<i64> {
+        let value = match parent.get(key)? {
+            Value::Integer(i) => *i,
+            Value::String(s) => match s.trim().parse::<i64>() {
+                Ok(i) => i,
+                Err(_) => {
+                    self.error(&join(path, key), format!("ожидалось целое число, а не '{}'", s));
+                    return None;
+                }
+            },
+            other => {
+                self.error(&join(path, key), format!("ожидалось целое число, а не {}", type_name(other)));
+                return None;
+            }
+        };
+        if value < min || value > max {
+            self.error(&join(path, key), format!("значение {} вне диапазона {}..={}", value, min, max));
+            return None;
+        }
+        Some(value)
+    }
+
+    fn opt_secs(&mut self, parent: &Table, path: &str, key: &str) -> Option<Duration> {
+        self.opt_int(parent, path, key, 1, 3600).map(|s| Duration::from_secs(s as u64))
+    }
+
+    fn ssh_target(&mut self, table: &Table, path: &str) -> Option<SshTarget> {
+        self.check_keys(table, path, &["host", "port", "user", "key", "passphrase_file", "auth", "jump"]);
+        let host = self.str(table, path, "host");
+        let port = self.opt_int(table, path, "port", 1, 65535).unwrap_or(22) as u16;
+        let user = self.str(table, path, "user");
+        let auth = self.auth_methods(table, path)?;
+
+        // Ключ обязателен, только если вход по ключу входит в список способов
+        let key = if auth.contains(&AuthMethod::Key) {
+            Some(self.str(table, path, "key")?)
+        } else {
+            self.opt_str(table, path, "key")
+        };
+        let passphrase_file = self.opt_str(table, path, "passphrase_file");
+        if passphrase_file.is_some() && key.is_none() {
+            self.error(&join(path, "passphrase_file"), "имеет смысл только вместе с key");
+        }
+
+        let jump = self.jump_chain(table, path)?;
+        Some(SshTarget { host: host?, port, user: user?, key, passphrase_file, auth, jump })
+    }
+
+    // Порядок способов входа: массив или строка через запятую (из окружения), по умолчанию только ключ
+    fn auth_methods(&mut self, table: &Table, path: &str) -> Option<Vec<AuthMethod>> {
+        let path = join(path, "auth");
+        let names = match table.get("auth") {
+            None => return Some(vec![AuthMethod::Key]),
+            Some(Value::String(list)) => list.split(',').map(|s| s.trim().to_string()).collect::<Vec<_>>(),
+            Some(Value::Array(items)) => items
+                .iter()
+                .map(|item| item.as_str().unwrap_or_default().to_string())
+                .collect(),
+            Some(other) => {
+                self.error(&path, format!("ожидался массив способов, а не {}", type_name(other)));
+                return None;
+            }
+        };
+
+        let mut methods = Vec::new();
+        for name in names {
+            match AuthMethod::from_name(&name) {
+                Some(method) if !methods.contains(&method) => methods.push(method),
+                Some(_) => {}
+                None => {
+                    self.error(&path, format!("неизвестный способ '{}', допустимы agent и key", name));
+                    return None;
+                }
+            }
+        }
+        if methods.is_empty() {
+            self.error(&path, "нужен хотя бы один способ входа");
+            return None;
+        }
+        Some(methods)
+    }
+
+    // role = "admin" | "operator" | "viewer"; прежний admin = true означает роль admin
+    fn user_role(&mut self, user: &Table, path: &str) -> Option<Role> {
+        let admin = self.opt_bool(user, path, "admin").unwrap_or(false);
+        let role = self.opt_str(user, path, "role").and_then(|name| match Role::from_name(&name) {
+            Some(role) => Some(role),
+            None => {
+                self.error(&join(path, "role"), format!("неизвестная роль '{}', допустимы admin, operator и viewer", name));
+                None
+            }
+        });
+        match (admin, role) {
+            (true, Some(role)) if role != Role::Admin => {
+                self.error(&join(path, "admin"), format!("admin = true противоречит role = \"{}\"", role));
+                Some(role)
+            }
+            (true, _) => Some(Role::Admin),
+            (false, role) => role,
+        }
+    }
+
+    // Участники группы или записи списка доступа: имена из [users] или числовые ID
+    fn members(&mut self, table: &Table, path: &str, key: &str) -> Option<Vec<i64>> {
+        let path = join(path, key);
+        let items = match table.get(key) {
+            None => return Some(Vec::new()),
+            Some(Value::Array(items)) => items,
+            Some(other) => {
+                self.error(&path, format!("ожидался массив пользователей, а не {}", type_name(other)));
+                return None;
+            }
+        };
+        let mut ids = Vec::new();
+        for item in items {
+            let id = match item {
+                Value::String(name) => self.user_ids.get(name).copied(),
+                Value::Integer(id) => self.user_ids.values().find(|known| *known == id).copied(),
+                other => {
+                    self.error(&path, format!("ожидалось имя или ID пользователя, а не {}", type_name(other)));
+                    return None;
+                }
+            };
+            match id {
+                Some(id) if !ids.contains(&id) => ids.push(id),
+                Some(_) => {}
+                None => {
+                    self.error(&path, format!("пользователь {} не описан в users", item));
+                    return None;
+                }
+            }
+        }
+        Some(ids)
+    }
+
+    // acl = [{ users = [...], groups = [...], allow = ["view", "wake", "shutdown"] }, ...]
+    fn acl(&mut self, host: &Table, path: &str) -> Option<Vec<AclEntry>> {
+        let path = join(path, "acl");
+        let entries = match host.get("acl")? {
+            Value::Array(entries) => entries,
+            other => {
+                self.error(&path, format!("ожидался массив записей, а не {}", type_name(other)));
+                return None;
+            }
+        };
+        let mut acl = Vec::new();
+        for (index, entry) in entries.iter().enumerate() {
+            let path = format!("{}[{}]", path, index);
+            let Some(entry) = entry.as_table() else {
+                self.error(&path, format!("ожидалась таблица, а не {}", type_name(entry)));
+                continue;
+            };
+            self.check_keys(entry, &path, &["users", "groups", "allow"]);
+            if !entry.contains_key("users") && !entry.contains_key("groups") {
+                self.error(&path, "нужен хотя бы один из ключей users или groups");
+            }
+            let mut users = self.members(entry, &path, "users").unwrap_or_default();
+            match entry.get("groups") {
+                None => {}
+                Some(Value::Array(names)) => {
+                    for name in names {
+                        match name.as_str().and_then(|name| self.groups.get(name)) {
+                            Some(members) => {
+                                for id in members {
+                                    if !users.contains(id) {
+                                        users.push(*id);
+                                    }
+                                }
+                            }
+                            None => self.error(&join(&path, "groups"), format!("группа {} не описана в groups", name)),
+                        }
+                    }
+                }
+                Some(other) => {
+                    self.error(&join(&path, "groups"), format!("ожидался массив групп, а не {}", type_name(other)))
+                }
+            }
+
+            let mut permissions = Vec::new();
+            match entry.get("allow") {
+                Some(Value::Array(names)) if !names.is_empty() => {
+                    for name in names {
+                        match name.as_str().and_then(Permission::from_name) {
+                            Some(permission) => permissions.push(permission),
+                            None => self.error(
+                                &join(&path, "allow"),
+                                format!("неизвестное действие {}, допустимы view, wake и shutdown", name),
+                            ),
+                        }
+                    }
+                }
+                Some(Value::Array(_)) => self.error(&join(&path, "allow"), "не задано ни одного действия"),
+                Some(other) => {
+                    self.error(&join(&path, "allow"), format!("ожидался массив действий, а не {}", type_name(other)))
+                }
+                None => self.error(&join(&path, "allow"), "обязательный ключ отсутствует"),
+            }
+            acl.push(AclEntry { users, permissions });
+        }
+        Some(acl)
+    }
+
+    // approval = { quorum = 1, window = 600 }: выключение только после подтверждения других админов
+    fn approval(&mut self, host: &Table, path: &str) -> Option<ApprovalPolicy> {
+        let approval = self.table(host, path, "approval")?;
+        let path = join(path, "approval");
+        self.check_keys(approval, &path, &["quorum", "window"]);
+        let default = ApprovalPolicy::default();
+        let quorum = self.opt_int(approval, &path, "quorum", 1, 10).map_or(default.quorum, |q| q as u32);
+        let window = self
+            .opt_int(approval, &path, "window", 30, 86400)
+            .map_or(default.window, |secs| Duration::from_secs(secs as u64));
+        Some(ApprovalPolicy { quorum, window })
+    }
+
+    // Действия под кодом TOTP: массив или строка через запятую (из окружения); пустой список отключает проверку
+    fn totp_actions(&mut self, table: &Table, path: &str) -> Option<Vec<Permission>> {
+        let path = join(path, "actions");
+        let names = match table.get("actions")? {
+            Value::String(list) => list
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(str::to_string)
+                .collect::<Vec<_>>(),
+            Value::Array(items) => {
+                let names = items.iter().filter_map(Value::as_str).map(str::to_string).collect::<Vec<_>>();
+                if names.len() != items.len() {
+                    self.error(&path, "ожидался массив действий");
+                    return None;
+                }
+                names
+            }
+            other => {
+                self.error(&path, format!("ожидался массив действий, а не {}", type_name(other)));
+                return None;
+            }
+        };
+
+        let mut actions = Vec::new();
+        for name in names {
+            match Permission::from_name(&name) {
+                Some(permission @ (Permission::Wake | Permission::Shutdown)) => {
+                    if !actions.contains(&permission) {
+                        actions.push(permission);
+                    }
+                }
+                _ => {
+                    self.error(&path, format!("неизвестное действие '{}', допустимы wake и shutdown", name));
+                    return None;
+                }
+            }
+        }
+        Some(actions)
+    }
+
+    // Точки монтирования для отчёта о статусе: массив путей или строка через запятую (из окружения)
+    fn mounts(&mut self, table: &Table, path: &str) -> Option<Vec<String>> {
+        let path = join(path, "mounts");
+        let mounts = match table.get("mounts") {
+            None => return Some(report::DEFAULT_MOUNTS.iter().map(|m| m.to_string()).collect()),
+            Some(Value::String(list)) => list
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(str::to_string)
+                .collect::<Vec<_>>(),
+            Some(Value::Array(items)) => {
+                let mounts = items.iter().filter_map(Value::as_str).map(str::to_string).collect::<Vec<_>>();
+                if mounts.len() != items.len() {
+                    self.error(&path, "ожидался массив путей");
+                    return None;
+                }
+                mounts
+            }
+            Some(other) => {
+                self.error(&path, format!("ожидался массив путей, а не {}", type_name(other)));
+                return None;
+            }
+        };
+
+        if let Some(bad) = mounts.iter().find(|m| !m.starts_with('/')) {
+            self.error(&path, format!("путь '{}' должен быть абсолютным", bad));
+            return None;
+        }
+        Some(mounts)
+    }
+
+    // Цепочка jump: массив имён из [jump_hosts] или строка через запятую (из окружения)
+    fn jump_chain(&mut self, table: &Table, path: &str) -> Option<Vec<SshTarget>> {
+        let path = join(path, "jump");
+        let names = match table.get("jump") {
+            None => return Some(Vec::new()),
+            Some(Value::String(list)) => list
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(str::to_string)
+                .collect::<Vec<_>>(),
+            Some(Value::Array(items)) => {
+                let names = items.iter().filter_map(Value::as_str).map(str::to_string).collect::<Vec<_>>();
+                if names.len() != items.len() {
+                    self.error(&path, "ожидался массив имён из [jump_hosts]");
+                    return None;
+                }
+                names
+            }
+            Some(other) => {
+                self.error(&path, format!("ожидался массив имён, а не {}", type_name(other)));
+                return None;
+            }
+        };
+
+        let mut chain = Vec::new();
+        for name in names {
+            match self.jump_hosts.get(&name) {
+                Some(hop) => chain.push(hop.clone()),
+                None => {
+                    self.error(&path, format!("промежуточный хост '{}' не описан в [jump_hosts]", name));
+                    return None;
+                }
+            }
+        }
+        Some(chain)
+    }
+
+    fn wol_transport(&mut self, table: &Table, path: &str) -> Option<WolTransport> {
+        self.check_keys(
+            table,
+            path,
+            &[
+                "transport",
+                "broadcast",
+                "port",
+                "command",
+                "interface",
+                "template",
+                "attempts",
+                "grace_period",
+                "retry_interval",
+            ],
+        );
+        match self.opt_str(table, path, "transport").as_deref() {
+            None | Some("router") => {
+                self.only_for(table, path, &["broadcast", "port"], "udp");
+                let interface = self.opt_str(table, path, "interface");
+                if let Some(interface) = interface.as_deref().filter(|i| !wol::is_valid_interface(i)) {
+                    self.error(&join(path, "interface"), format!("некорректное имя интерфейса '{}'", interface));
+                }
+                let name = self.opt_str(table, path, "command").unwrap_or_else(|| "etherwake".into());
+                let template = self.opt_str(table, path, "template");
+                if template.is_some() && name != "custom" {
+                    self.error(&join(path, "template"), "используется только с command = \"custom\"");
+                }
+                let template = match CommandTemplate::from_name(&name, template) {
+                    Ok(template) => Some(template),
+                    Err(e) => {
+                        let key = if table.contains_key("template") { "template" } else { "command" };
+                        self.error(&join(path, key), e);
+                        None
+                    }
+                }?;
+                let Some(interface) = interface.or_else(|| template.default_interface().map(str::to_string)) else {
+                    self.error(
+                        &join(path, "interface"),
+                        format!("для command = \"{}\" интерфейс нужно указать явно (например, bridge)", name),
+                    );
+                    return None;
+                };
+                Some(WolTransport::Router(RouterCommand { template, interface }))
+            }
+            Some("udp") => {
+                self.only_for(table, path, &["command", "interface", "template"], "router");
+                let broadcast = match self.opt_str(table, path, "broadcast") {
+                    None => Some(Ipv4Addr::BROADCAST),
+                    Some(addr) => match addr.parse::<Ipv4Addr>() {
+                        Ok(ip) => Some(ip),
+                        Err(_) => {
+                            self.error(&join(path, "broadcast"), format!("некорректный IPv4-адрес '{}'", addr));
+                            None
+                        }
+                    },
+                };
+                let port = match self.opt_int(table, path, "port", 1, 65535) {
+                    None => Some(wol::DEFAULT_UDP_PORT),
+                    Some(port @ (7 | 9)) => Some(port as u16),
+                    Some(port) => {
+                        self.error(&join(path, "port"), format!("порт Wake-on-LAN должен быть 7 или 9, а не {}", port));
+                        None
+                    }
+                };
+                Some(WolTransport::Udp(UdpSender { broadcast: broadcast?, port: port? }))
+            }
+            Some(other) => {
+                self.error(&join(path, "transport"), format!("неизвестный транспорт '{}', допустимы router и udp", other));
+                None
+            }
+        }
+    }
+
+    fn tunnel(&mut self, name: &str, table: &Table, path: &str) -> Option<TunnelConfig> {
+        self.check_keys(table, path, &["ssh", "direction", "listen", "connect"]);
+
+        let ssh = match self.table(table, path, "ssh") {
+            Some(ssh) => self.ssh_target(ssh, &join(path, "ssh")),
+            None => {
+                if !table.contains_key("ssh") {
+                    self.error(&join(path, "ssh"), "обязательный ключ отсутствует");
+                }
+                None
+            }
+        };
+        let direction = match self.opt_str(table, path, "direction").as_deref() {
+            None | Some("local") => Some(Direction::Local),
+            Some("remote") => Some(Direction::Remote),
+            Some(other) => {
+                self.error(
+                    &join(path, "direction"),
+                    format!("неизвестное направление '{}', допустимы local и remote", other),
+                );
+                None
+            }
+        };
+        let address = |loader: &mut Self, key: &str| {
+            let value = loader.str(table, path, key)?;
+            tunnel::parse_address(&value)
+                .map_err(|e| loader.error(&join(path, key), e))
+                .ok()
+        };
+        let listen = address(self, "listen");
+        let connect = address(self, "connect");
+
+        Some(TunnelConfig {
+            name: name.to_string(),
+            ssh: ssh?,
+            direction: direction?,
+            listen: listen?,
+            connect: connect?,
+        })
+    }
+
+    // Повторная отправка magic packet, общая для всех транспортов
+    fn wake_retry(&mut self, table: &Table, path: &str) -> WakeRetry {
+        let defaults = WakeRetry::default();
+        WakeRetry {
+            attempts: self
+                .opt_int(table, path, "attempts", 1, 10)
+                .map(|n| n as u32)
+                .unwrap_or(defaults.attempts),
+            grace_period: self.opt_secs(table, path, "grace_period").unwrap_or(defaults.grace_period),
+            interval: self.opt_secs(table, path, "retry_interval").unwrap_or(defaults.interval),
+        }
+    }
+
+    // Ключи, которые имеют смысл только для другого транспорта
+    fn only_for(&mut self, table: &Table, path: &str, keys: &[&str], transport: &str) {
+        for key in keys {
+            if table.contains_key(*key) {
+                self.error(&join(path, key), format!("используется только с transport = \"{}\"", transport));
+            }
+        }
+    }
+
+    fn build(&mut self, root: &Table) -> Option<Config> {
+        self.check_keys(
+            root,
+            "",
+            &[
+                "bot_token",
+                "users",
+                "groups",
+                "timeouts",
+                "known_hosts",
+                "monitor",
+                "storage",
+                "totp",
+                "jump_hosts",
+                "tunnels",
+                "routers",
+                "hosts",
+            ],
+        );
+
+        let bot_token = self.str(root, "", "bot_token");
+
+        let (mut allowed_users, mut subscribers) = (Vec::new(), Vec::new());
+        // Явно заданные роли; None — роль по умолчанию
+        let mut explicit_roles: Vec<(i64, Option<Role>)> = Vec::new();
+        match self.table(root, "", "users") {
+            Some(users) => {
+                for name in users.keys() {
+                    let path = join("users", name);
+                    let Some(user) = self.table(users, "users", name) else { continue };
+                    self.check_keys(user, &path, &["id", "role", "admin", "notify"]);
+                    let role = self.user_role(user, &path);
+                    let notify = self.opt_bool(user, &path, "notify").unwrap_or(false);
+                    if !user.contains_key("id") {
+                        self.error(&join(&path, "id"), "обязательный ключ отсутствует");
+                    } else if let Some(id) = self.opt_int(user, &path, "id", 1, i64::MAX) {
+                        if !allowed_users.contains(&id) {
+                            allowed_users.push(id);
+                        }
+                        self.user_ids.insert(name.clone(), id);
+                        // Один ID может прийти и из файла, и из окружения: берём старшую роль
+                        match explicit_roles.iter_mut().find(|(known, _)| *known == id) {
+                            Some((_, known)) => *known = (*known).max(role),
+                            None => explicit_roles.push((id, role)),
+                        }
+                        if notify && !subscribers.contains(&id) {
+                            subscribers.push(id);
+                        }
+                    }
+                }
+                if users.is_empty() {
+                    self.error("users", "не задано ни одного пользователя");
+                }
+            }
+            None if !root.contains_key("users") => self.error("users", "не задано ни одного пользователя"),
+            None => {}
+        }
+
+        // Пока ни у кого нет роли ниже admin, все пользователи — админы, как до появления ролей:
+        // admin = true (и ADMIN_USERS) лишь выбирает, кому идут оповещения. Стоит назначить
+        // кому-то operator или viewer, и остальные по умолчанию становятся операторами.
+        let default_role = if explicit_roles.iter().any(|(_, role)| role.is_some_and(|role| role < Role::Admin)) {
+            Role::Operator
+        } else {
+            Role::Admin
+        };
+        // Назначив роль одному пользователю, легко не заметить, что остальные потеряли права админа
+        if default_role == Role::Operator {
+            let mut demoted = self
+                .user_ids
+                .iter()
+                .filter(|(_, id)| explicit_roles.iter().any(|(known, role)| known == *id && role.is_none()))
+                .map(|(name, _)| name.clone())
+                .collect::<Vec<_>>();
+            if !demoted.is_empty() {
+                demoted.sort();
+                self.warning(
+                    "users",
+                    format!(
+                        "у {} роль не задана, поэтому они получают роль operator, а не admin, как до появления ролей; \
+                         укажите role явно",
+                        demoted.join(", ")
+                    ),
+                );
+            }
+        }
+        let roles = explicit_roles
+            .iter()
+            .map(|(id, role)| (*id, role.unwrap_or(default_role)))
+            .collect::<HashMap<_, _>>();
+        // Оповещения админам получают явно отмеченные админы, а если таких нет — все, у кого роль admin
+        let marked = |id: &i64| explicit_roles.iter().any(|(known, role)| known == id && *role == Some(Role::Admin));
+        let admins = if allowed_users.iter().any(marked) {
+            allowed_users.iter().copied().filter(marked).collect::<Vec<_>>()
+        } else {
+            allowed_users.iter().copied().filter(|id| roles.get(id) == Some(&Role::Admin)).collect::<Vec<_>>()
+        };
+
+        // Группы пользователей для списков доступа хостов
+        if let Some(table) = self.table(root, "", "groups") {
+            for name in table.keys() {
+                if let Some(members) = self.members(table, "groups", name) {
+                    self.groups.insert(name.clone(), members);
+                }
+            }
+        }
+
+        let mut ssh_timeouts = Timeouts::uniform(Duration::from_secs(15));
+        let mut nc_timeout = Duration::from_secs(5);
+        let mut boot_timeout = Duration::from_secs(180);
+        if let Some(timeouts) = self.table(root, "", "timeouts") {
+            self.check_keys(
+                timeouts,
+                "timeouts",
+                &["ssh", "connect", "handshake", "auth", "exec", "status", "boot"],
+            );
+            // ssh задаёт все этапы сразу, отдельные ключи уточняют конкретный этап
+            if let Some(ssh) = self.opt_secs(timeouts, "timeouts", "ssh") {
+                ssh_timeouts = Timeouts::uniform(ssh);
+            }
+            for (key, phase) in [
+                ("connect", &mut ssh_timeouts.connect),
+                ("handshake", &mut ssh_timeouts.handshake),
+                ("auth", &mut ssh_timeouts.auth),
+                ("exec", &mut ssh_timeouts.exec),
+            ] {
+                if let Some(secs) = self.opt_secs(timeouts, "timeouts", key) {
+                    *phase = secs;
+                }
+            }
+            nc_timeout = self.opt_secs(timeouts, "timeouts", "status").unwrap_or(nc_timeout);
+            boot_timeout = self.opt_secs(timeouts, "timeouts", "boot").unwrap_or(boot_timeout);
+        }
+
+        let mut host_keys = HostKeyPolicy::default();
+        if let Some(known_hosts) = self.table(root, "", "known_hosts") {
+            self.check_keys(known_hosts, "known_hosts", &["path", "trust_on_first_use"]);
+            if let Some(path) = self.opt_str(known_hosts, "known_hosts", "path") {
+                host_keys.known_hosts = path;
+            }
+            if let Some(tofu) = self.opt_bool(known_hosts, "known_hosts", "trust_on_first_use") {
+                host_keys.trust_on_first_use = tofu;
+            }
+        }
+
+        let mut monitor = MonitorConfig::default();
+        if let Some(table) = self.table(root, "", "monitor") {
+            self.check_keys(table, "monitor", &["interval", "confirmations"]);
+            if let Some(secs) = self.opt_int(table, "monitor", "interval", 0, 86400) {
+                monitor.interval = Duration::from_secs(secs as u64);
+            }
+            if let Some(count) = self.opt_int(table, "monitor", "confirmations", 1, 100) {
+                monitor.confirmations = count as u32;
+            }
+        }
+
+        let mut database = store::DEFAULT_PATH.to_string();
+        if let Some(table) = self.table(root, "", "storage") {
+            self.check_keys(table, "storage", &["path"]);
+            if let Some(path) = self.opt_str(table, "storage", "path") {
+                database = path;
+            }
+        }
+
+        let mut totp = TotpConfig::default();
+        if let Some(table) = self.table(root, "", "totp") {
+            self.check_keys(table, "totp", &["actions", "issuer"]);
+            if let Some(actions) = self.totp_actions(table, "totp") {
+                totp.actions = actions;
+            }
+            if let Some(issuer) = self.opt_str(table, "totp", "issuer") {
+                totp.issuer = issuer;
+            }
+        }
+
+        // Промежуточные хосты сами подключаются напрямую: цепочка задаётся у цели
+        if let Some(table) = self.table(root, "", "jump_hosts") {
+            for name in table.keys() {
+                let path = join("jump_hosts", name);
+                if let Some(hop) = self.table(table, "jump_hosts", name) {
+                    if hop.contains_key("jump") {
+                        self.error(&join(&path, "jump"), "у промежуточного хоста не может быть своей цепочки");
+                        continue;
+                    }
+                    if let Some(target) = self.ssh_target(hop, &path) {
+                        self.jump_hosts.insert(name.clone(), target);
+                    }
+                }
+            }
+        }
+
+        let mut tunnels = Vec::new();
+        if let Some(table) = self.table(root, "", "tunnels") {
+            for name in table.keys() {
+                let path = join("tunnels", name);
+                if let Some(tunnel) = self.table(table, "tunnels", name) {
+                    if let Some(tunnel) = self.tunnel(name, tunnel, &path) {
+                        tunnels.push(tunnel);
+                    }
+                }
+            }
+        }
+
+        let mut routers = HashMap::new();
+        if let Some(table) = self.table(root, "", "routers") {
+            for id in table.keys() {
+                let path = join("routers", id);
+                if let Some(router) = self.table(table, "routers", id) {
+                    if let Some(target) = self.ssh_target(router, &path) {
+                        routers.insert(id.clone(), target);
+                    }
+                }
+            }
+        }
+        let router_ids = root
+            .get("routers")
+            .and_then(Value::as_table)
+            .map(|t| t.keys().cloned().collect::<Vec<_>>())
+            .unwrap_or_default();
+
+        let mut hosts = Vec::new();
+        match self.table(root, "", "hosts") {
+            Some(table) if !table.is_empty() => {
+                for id in table.keys() {
+                    let path = join("hosts", id);
+                    let Some(host) = self.table(table, "hosts", id) else { continue };
+                    if !is_valid_host_id(id) {
+                        self.error(&path, "идентификатор хоста должен состоять из a-z, 0-9, '_' и '-' (до 32 символов)");
+                    }
+                    self.check_keys(
+                        host,
+                        &path,
+                        &["name", "mac", "secureon", "router", "ssh", "wol", "boot_timeout", "lan_ip", "mounts", "monitor_interval", "acl", "approval"],
+                    );
+                    let host_boot_timeout = self.opt_secs(host, &path, "boot_timeout").unwrap_or(boot_timeout);
+                    let monitor_interval = self
+                        .opt_int(host, &path, "monitor_interval", 0, 86400)
+                        .map(|secs| Duration::from_secs(secs as u64));
+
+                    let name = self.opt_str(host, &path, "name").unwrap_or_else(|| id.clone());
+                    let mac = self.str(host, &path, "mac");
+                    if let Some(mac) = &mac {
+                        if !is_valid_mac(mac) {
+                            self.error(&join(&path, "mac"), format!("некорректный MAC-адрес '{}'", mac));
+                        }
+                    }
+
+                    let empty = Table::new();
+                    let wol_table = self.table(host, &path, "wol").unwrap_or(&empty);
+                    let transport = self.wol_transport(wol_table, &join(&path, "wol"));
+                    let retry = self.wake_retry(wol_table, &join(&path, "wol"));
+                    let needs_router = matches!(transport, Some(WolTransport::Router(_)));
+
+                    let secureon = self.opt_str(host, &path, "secureon").and_then(|password| {
+                        match SecureOn::parse(&password) {
+                            Ok(password) => Some(password),
+                            Err(e) => {
+                                self.error(&join(&path, "secureon"), e);
+                                None
+                            }
+                        }
+                    });
+                    if let Some(WolTransport::Router(command)) = &transport {
+                        match (&command.template, secureon.is_some() || host.contains_key("secureon")) {
+                            (template, true) if !template.supports_password() => self.error(
+                                &join(&path, "secureon"),
+                                "выбранная команда роутера не поддерживает пароль SecureOn",
+                            ),
+                            (CommandTemplate::Custom(template), false) if template.contains("{password}") => self.error(
+                                &join(&path, "wol.template"),
+                                "шаблон использует {password}, но secureon не задан",
+                            ),
+                            _ => {}
+                        }
+                    }
+
+                    let router = match self.opt_str(host, &path, "router") {
+                        Some(router_id) if router_ids.contains(&router_id) => routers.get(&router_id).cloned(),
+                        Some(router_id) => {
+                            self.error(&join(&path, "router"), format!("роутер '{}' не описан в routers", router_id));
+                            None
+                        }
+                        None if !needs_router => None,
+                        None if router_ids.len() == 1 => routers.values().next().cloned(),
+                        None if router_ids.is_empty() => {
+                            self.error(&join(&path, "router"), "не описано ни одного роутера в routers");
+                            None
+                        }
+                        None => {
+                            self.error(&join(&path, "router"), "обязателен, если роутеров несколько");
+                            None
+                        }
+                    };
+
+                    let ssh = match self.table(host, &path, "ssh") {
+                        Some(ssh) => self.ssh_target(ssh, &join(&path, "ssh")),
+                        None => {
+                            if !host.contains_key("ssh") {
+                                self.error(&join(&path, "ssh"), "обязательный ключ отсутствует");
+                            }
+                            None
+                        }
+                    };
+
+                    let lan_ip = self.opt_str(host, &path, "lan_ip").and_then(|ip| match ip.parse::<IpAddr>() {
+                        Ok(ip) => Some(ip),
+                        Err(_) => {
+                            self.error(&join(&path, "lan_ip"), format!("некорректный IP-адрес '{}'", ip));
+                            None
+                        }
+                    });
+                    if lan_ip.is_some() && router.is_none() && !host.contains_key("router") {
+                        self.error(&join(&path, "lan_ip"), "проверять LAN-адрес можно только через роутер (ключ router)");
+                    }
+
+                    let mounts = self.mounts(host, &path);
+                    let acl = self.acl(host, &path);
+                    let approval = self.approval(host, &path);
+
+                    if needs_router && router.is_none() {
+                        continue;
+                    }
+                    if let (Some(mac), Some(ssh), Some(wol), Some(mounts)) = (mac, ssh, transport, mounts) {
+                        hosts.push(HostConfig {
+                            id: id.clone(),
+                            name,
+                            mac,
+                            ssh,
+                            router,
+                            wol,
+                            secureon,
+                            boot_timeout: host_boot_timeout,
+                            retry,
+                            lan_ip,
+                            mounts,
+                            monitor_interval,
+                            acl,
+                            approval,
+                        });
+                    }
+                }
+            }
+            Some(_) => self.error("hosts", "не описано ни одного хоста"),
+            None if !root.contains_key("hosts") => self.error("hosts", "не описано ни одного хоста"),
+            None => {}
+        }
+
+        Some(Config {
+            bot_token: bot_token?,
+            allowed_users,
+            admins,
+            roles,
+            hosts,
+            ssh_timeouts,
+            nc_timeout,
+            host_keys,
+            monitor,
+            subscribers,
+            database,
+            tunnels,
+            totp,
+            warnings: std::mem::take(&mut self.warnings),
+        })
+    }
+}
@@ -50,10 +50,26 @@ async fn message_handler(bot: Bot, msg: Message, cfg: Arc<Config>) -> ResponseRe
     if !crate::is_allowed(&cfg, user_id) {
         println!("❌ Пользователь {:?} не авторизован", user_id);
         log::warn!("Неавторизованный пользователь: {:?}", user_id);
+        if let Err(e) = crate::offer_access_request(&bot, &msg).await {
+            log::error!("Не удалось предложить запрос доступа пользователю {:?}: {}", user_id, e);
+        }
         return Ok(());
     }
     
     if let Some(text) = msg.text() {
+        // Код TOTP, которого ждёт отложенное действие; сам код в лог не пишем
+        if let (Some(code), Some(uid)) = (crate::totp::parse_code(text), user_id) {
+            let pending = crate::store::get().map_or(Ok(false), |store| crate::totp::has_pending(&store, uid));
+            if pending.unwrap_or_else(|e| {
+                log::error!("Не удалось проверить ожидание кода TOTP от пользователя {}: {:#}", uid, e);
+                false
+            }) {
+                if let Err(e) = crate::handle_totp_code(&bot, &msg, &cfg, &code).await {
+                    log::error!("Ошибка проверки кода TOTP от пользователя {}: {}", uid, e);
+                }
+                return Ok(());
+            }
+        }
         println!("📝 Текст сообщения: '{}'", text);
         match text.to_lowercase().as_str() {
             "/start" | "/wol" => {
@@ -69,6 +85,26 @@ async fn message_handler(bot: Bot, msg: Message, cfg: Arc<Config>) -> ResponseRe
                     },
                 }
             }
+            "/subscribe" | "/unsubscribe" => {
+                println!("🔔 Обрабатываем команду {}", text);
+                if let Err(e) = crate::set_subscription(&bot, &msg, text.eq_ignore_ascii_case("/subscribe")).await {
+                    log::error!("Не удалось изменить подписку пользователя {:?}: {}", user_id, e);
+                }
+            }
+            command if command == "/audit" || command.starts_with("/audit ") => {
+                println!("📜 Обрабатываем команду /audit");
+                let args = text.split_once(' ').map(|(_, args)| args).unwrap_or_default();
+                if let Err(e) = crate::handle_audit_command(&bot, &msg, &cfg, args).await {
+                    log::error!("Ошибка команды /audit от пользователя {:?}: {}", user_id, e);
+                }
+            }
+            command if command == "/totp" || command.starts_with("/totp ") => {
+                println!("🔐 Обрабатываем команду /totp");
+                let args = text.split_once(' ').map(|(_, args)| args).unwrap_or_default();
+                if let Err(e) = crate::handle_totp_command(&bot, &msg, &cfg, args).await {
+                    log::error!("Ошибка команды /totp от пользователя {:?}: {}", user_id, e);
+                }
+            }
             _ => {
                 println!("⚠️ Неизвестная команда: '{}'", text);
                 log::warn!("Неизвестная команда: '{}' от пользователя {:?}", text, user_id);
@@ -91,6 +127,14 @@ async fn callback_handler(bot: Bot, q: CallbackQuery, cfg: Arc<Config>) -> Respo
         println!("❌ Пользователь {} не авторизован для callback", q.from.id.0);
         log::warn!("Неавторизованный callback от пользователя: {}", q.from.id.0);
         
+        // Единственное, что доступно неизвестному пользователю, — запросить доступ
+        if q.data.as_deref() == Some(crate::access::ACCESS_REQUEST) {
+            if let Err(e) = crate::handle_access_request(&bot, &q, &cfg).await {
+                log::error!("Не удалось обработать запрос доступа от {}: {}", q.from.id.0, e);
+            }
+            return Ok(());
+        }
+        
         // Все равно отвечаем на callback query, чтобы убрать индикатор загрузки
         if let Err(e) = crate::safe_answer_callback_query(&bot, &q.id).await {
             log::error!("Не удалось ответить на неавторизованный callback: {}", e);
@@ -102,26 +146,77 @@ async fn callback_handler(bot: Bot, q: CallbackQuery, cfg: Arc<Config>) -> Respo
         println!("🎯 Обрабатываем callback data: '{}'", data);
         log::info!("Обрабатываем callback query: '{}' от пользователя {}", data, q.from.id.0);
         
-        let result = match data {
-            "wol" => {
+        let (action, host) = crate::parse_callback_data(&cfg, data);
+        if let Some(permission) = crate::access::permission_for(action) {
+            let permitted = match host {
+                Some(host) => crate::access::allows_on(&cfg, q.from.id.0, host, permission),
+                None => crate::access::allows(&cfg, q.from.id.0, permission),
+            };
+            if !permitted {
+                if let Err(e) = crate::deny_callback(&bot, &q, &cfg, action, host).await {
+                    log::error!("Не удалось сообщить об отказе в доступе: {}", e);
+                }
+                return Ok(());
+            }
+        }
+
+        let result = match (action, host) {
+            ("details", _) => {
+                println!("🔍 Показываем подробности ошибки");
+                let details_id = data.split_once(':').map(|(_, id)| id).unwrap_or_default();
+                crate::show_error_details(&bot, &q, &cfg, details_id).await
+            },
+            ("hostkey_ok", _) | ("hostkey_no", _) => {
+                println!("🔑 Решение по ключу хоста");
+                let request_id = data.split_once(':').map(|(_, id)| id).unwrap_or_default();
+                crate::handle_host_key_decision(&bot, &q, &cfg, request_id, action == "hostkey_ok").await
+            },
+            ("access_request", _) => {
+                println!("🙋 Запрос доступа от пользователя с доступом");
+                crate::handle_access_request(&bot, &q, &cfg).await
+            },
+            ("access_role", _) | ("access_ok", _) | ("access_no", _) => {
+                println!("🙋 Решение по запросу доступа");
+                let args = data.split_once(':').map(|(_, args)| args).unwrap_or_default();
+                crate::handle_access_decision(&bot, &q, &cfg, action, args).await
+            },
+            ("approval_ok", _) | ("approval_no", _) => {
+                println!("🛡 Голос по запросу подтверждения");
+                let id = data.split_once(':').map(|(_, id)| id).unwrap_or_default();
+                crate::handle_approval_vote(&bot, &q, &cfg, id, action == "approval_ok").await
+            },
+            ("approval_cancel", _) => {
+                println!("🛡 Отмена запроса подтверждения");
+                let id = data.split_once(':').map(|(_, id)| id).unwrap_or_default();
+                crate::handle_approval_cancel(&bot, &q, &cfg, id).await
+            },
+            ("hosts", _) => {
+                println!("🖥 Показываем список хостов");
+                crate::show_hosts(&bot, &q, &cfg).await
+            },
+            ("host", Some(host)) => {
+                println!("🖥 Выбран хост '{}'", host.id);
+                crate::show_host_menu(&bot, &q, &cfg, host).await
+            },
+            ("wol", Some(host)) => {
                 println!("🔌 Запуск WOL handler");
-                crate::handle_wol(&bot, &q, &cfg).await
+                crate::handle_wol(&bot, &q, &cfg, host).await
             },
-            "shutdown_confirm" => {
+            ("shutdown_confirm", Some(host)) => {
                 println!("🔴 Запуск shutdown confirm handler");
-                crate::ask_shutdown_confirm(&bot, &q).await
+                crate::ask_shutdown_confirm(&bot, &q, host).await
             },
-            "shutdown_yes" => {
+            ("shutdown_yes", Some(host)) => {
                 println!("💀 Запуск shutdown handler");
-                crate::handle_shutdown(&bot, &q, &cfg).await
+                crate::handle_shutdown(&bot, &q, &cfg, host).await
             },
-            "status" => {
+            ("status", Some(host)) => {
                 println!("🟢 Запуск status handler");
-                crate::handle_status(&bot, &q, &cfg).await
+                crate::handle_status(&bot, &q, &cfg, host).await
             },
-            "cancel" => {
+            ("cancel", Some(host)) => {
                 println!("❌ Запуск cancel handler");
-                crate::cancel(&bot, &q).await
+                crate::cancel(&bot, &q, &cfg, host).await
             },
             _ => {
                 println!("⚠️ Неизвестный callback data: '{}'", data);
@@ -139,7 +234,7 @@ async fn callback_handler(bot: Bot, q: CallbackQuery, cfg: Arc<Config>) -> Respo
                         msg.id,
                         "❌ Неизвестная команда. Используйте /start для возврата в главное меню."
                     )
-                    .reply_markup(crate::main_keyboard())
+                    .reply_markup(crate::start_keyboard(&cfg, q.from.id.0))
                     .await {
                         log::error!("Не удалось отправить сообщение об ошибке неизвестной команды: {}", e);
                     }
@@ -165,7 +260,7 @@ async fn callback_handler(bot: Bot, q: CallbackQuery, cfg: Arc<Config>) -> Respo
                         msg.id,
                         "❌ Произошла ошибка при выполнении команды.\nПопробуйте позже или обратитесь к администратору."
                     )
-                    .reply_markup(crate::main_keyboard())
+                    .reply_markup(crate::start_keyboard(&cfg, q.from.id.0))
                     .await {
                         log::error!("Не удалось отправить сообщение об общей ошибке: {}", edit_err);
                     }
@@ -1,21 +1,52 @@
-use std::{env, net::TcpStream, path::Path, time::Duration, io::Read, collections::HashMap, sync::Mutex};
+use std::{
+    env, net::IpAddr, time::Duration, collections::{HashMap, VecDeque},
+    sync::{atomic::{AtomicU64, Ordering}, Mutex},
+};
 
 use anyhow::{Result};
-use ssh2::Session;
 use teloxide::{
     prelude::*,
-    types::{InlineKeyboardButton, InlineKeyboardMarkup, ParseMode, CallbackQuery},
+    types::{InlineKeyboardButton, InlineKeyboardMarkup, InputFile, ParseMode, CallbackQuery, MessageId, User},
     utils::command::BotCommands,
 };
 use std::sync::Arc;
 
+mod access;
+mod approval;
+mod audit;
+mod config;
 mod handler;
+mod monitor;
+mod report;
+mod ssh;
+mod store;
+mod totp;
+mod tunnel;
+mod wol;
+
+// Как часто опрашиваем хост после отправки magic packet
+const BOOT_POLL_INTERVAL: Duration = Duration::from_secs(5);
 
 // Добавляем глобальное состояние для предотвращения спама кнопок
 lazy_static::lazy_static! {
     static ref BUTTON_LOCKS: Arc<Mutex<HashMap<u64, std::time::Instant>>> = Arc::new(Mutex::new(HashMap::new()));
+    // Когда неизвестный пользователь последний раз запрашивал доступ
+    static ref ACCESS_REQUESTS: Mutex<HashMap<u64, std::time::Instant>> = Mutex::new(HashMap::new());
+    // Подробности последних ошибок для кнопки «Подробнее»: (id, хост, текст)
+    static ref ERROR_DETAILS: Mutex<VecDeque<(u64, String, String)>> = Mutex::new(VecDeque::new());
 }
 
+static NEXT_ERROR_ID: AtomicU64 = AtomicU64::new(1);
+
+// Как часто неизвестный пользователь может беспокоить админов запросом доступа
+const ACCESS_REQUEST_COOLDOWN: Duration = Duration::from_secs(600);
+
+// Как часто обновляем у запросившего оставшееся на подтверждение время
+const APPROVAL_REFRESH: Duration = Duration::from_secs(30);
+
+// Сколько подробностей ошибок держим в памяти
+const ERROR_DETAILS_LIMIT: usize = 100;
+
 #[cfg(test)]
 mod tests;
 
@@ -40,9 +71,12 @@ async fn main() {
         Err(_) => println!("ALLOWED_USERS: НЕ НАЙДЕН!"),
     }
     
-    match env::var("SERVER_MAC") {
-        Ok(mac) => println!("SERVER_MAC: '{}'", mac),
-        Err(_) => println!("SERVER_MAC: НЕ НАЙДЕН!"),
+    match env::var("HOSTS") {
+        Ok(hosts) => println!("HOSTS: '{}'", hosts),
+        Err(_) => match env::var("SERVER_MAC") {
+            Ok(mac) => println!("SERVER_MAC: '{}'", mac),
+            Err(_) => println!("SERVER_MAC: НЕ НАЙДЕН!"),
+        },
     }
     println!("=== КОНЕЦ ПРОВЕРКИ ПЕРЕМЕННЫХ ===");
     
@@ -60,8 +94,8 @@ async fn run() -> Result<()> {
     log::info!("Запуск WakeOnLanBot...");
 
     println!("=== ЧТЕНИЕ КОНФИГУРАЦИИ ===");
-    // Читаем конфигурацию из переменных окружения
-    let config = Config::from_env()?;
+    // Читаем конфигурацию из файла и переменных окружения
+    let config = Config::load()?;
     println!("Конфигурация загружена успешно!");
     log::info!("Конфигурация успешно загружена!");
 
@@ -77,7 +111,7 @@ async fn run() -> Result<()> {
         Ok(me) => {
             println!("✅ Связь с Telegram API работает!");
             println!("   Имя бота: {}", me.first_name);
-            println!("   Username: @{}", me.username.as_ref().map(|s| s.as_str()).unwrap_or("НЕТ"));
+            println!("   Username: @{}", me.username.as_deref().unwrap_or("НЕТ"));
             log::info!("Telegram API отвечает, бот: {}", me.first_name);
         },
         Err(e) => {
@@ -87,7 +121,24 @@ async fn run() -> Result<()> {
         }
     }
 
+    if let Err(e) = bot.set_my_commands(Command::bot_commands()).await {
+        log::warn!("Не удалось зарегистрировать список команд: {}", e);
+    }
+
+    store::init(&config.database)?;
+    log::info!("База состояния: {}", config.database);
+    if let Some(store) = store::get() {
+        access::load_granted(&store);
+    }
+
     let cfg = Arc::new(config);
+    tokio::spawn(expire_stale_approvals(bot.clone(), cfg.clone()));
+    tokio::spawn(forward_host_key_events(bot.clone(), cfg.clone()));
+    tokio::spawn(ssh::keep_alive_sessions());
+    tunnel::start(cfg.clone());
+    tokio::spawn(forward_tunnel_events(bot.clone(), cfg.clone()));
+    monitor::start(cfg.clone());
+    tokio::spawn(forward_monitor_events(bot.clone(), cfg.clone()));
 
     println!("=== ЗАПУСК ОБРАБОТЧИКА ===");
     log::info!("Запускаем обработчик событий...");
@@ -107,118 +158,89 @@ async fn run() -> Result<()> {
 struct Config {
     bot_token: String,
     allowed_users: Vec<i64>,
-    server_mac: String,
+    // Пользователи с ролью admin: им приходят служебные оповещения
+    admins: Vec<i64>,
+    // Роль каждого пользователя из allowed_users
+    roles: HashMap<i64, access::Role>,
+    hosts: Vec<HostConfig>,
 
-    router_ssh_host: String,
-    router_ssh_port: u16,
-    router_ssh_user: String,
-    router_ssh_key: String,
+    // Таймауты этапов SSH: подключение, рукопожатие, аутентификация, выполнение
+    ssh_timeouts: ssh::Timeouts,
+    // Таймаут проверки, открыт ли SSH-порт
+    nc_timeout: Duration,
+    host_keys: ssh::HostKeyPolicy,
+    // Фоновый опрос хостов и кто получает оповещения о смене их состояния
+    monitor: monitor::MonitorConfig,
+    subscribers: Vec<i64>,
+    // Файл SQLite с состоянием, которое переживает перезапуск
+    database: String,
+    // Туннели, которые бот держит сам
+    tunnels: Vec<tunnel::TunnelConfig>,
+    // Какие действия требуют кода TOTP у пользователей, которые его включили
+    totp: totp::TotpConfig,
+    // Замечания к конфигурации, которые не мешают запуску
+    warnings: Vec<String>,
+}
 
-    server_ssh_host: String,
-    server_ssh_port: u16,
-    server_ssh_user: String,
-    server_ssh_key: String,
+// Параметры SSH-подключения к роутеру или серверу
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct SshTarget {
+    host: String,
+    port: u16,
+    user: String,
+    // Приватный ключ; нужен, если среди способов входа есть key
+    key: Option<String>,
+    // Файл с паролем от зашифрованного ключа
+    passphrase_file: Option<String>,
+    // Способы аутентификации в порядке попыток
+    auth: Vec<ssh::AuthMethod>,
+    // Промежуточные хосты (как ProxyJump), от ближнего к дальнему
+    jump: Vec<SshTarget>,
+}
 
-    ssh_timeout: Duration,
-    nc_timeout: Duration,
+// Машина из инвентаря: её MAC, SSH до неё самой и способ её разбудить
+#[derive(Clone, Debug)]
+struct HostConfig {
+    id: String,
+    name: String,
+    mac: String,
+    ssh: SshTarget,
+    // Роутер нужен только для команд на роутере (WolTransport::Router)
+    router: Option<SshTarget>,
+    wol: wol::WolTransport,
+    secureon: Option<wol::SecureOn>,
+    // Сколько ждать ответа по SSH после последнего magic packet
+    boot_timeout: Duration,
+    retry: wol::WakeRetry,
+    // Адрес сервера в LAN: по нему роутер проверяет, жив ли сервер, когда туннель молчит
+    lan_ip: Option<IpAddr>,
+    // Точки монтирования, занятость которых показывается в статусе
+    mounts: Vec<String>,
+    // Свой период фонового опроса вместо monitor.interval; ноль отключает опрос у хоста
+    monitor_interval: Option<Duration>,
+    // Список доступа: None — хост доступен всем по их ролям
+    acl: Option<Vec<access::AclEntry>>,
+    // Выключение только с подтверждения других админов
+    approval: Option<approval::ApprovalPolicy>,
 }
 
 impl Config {
-    fn from_env() -> Result<Self> {
-        println!("=== ДЕТАЛЬНОЕ ЧТЕНИЕ КОНФИГУРАЦИИ ===");
-        log::info!("Начинаю чтение конфигурации из переменных окружения...");
-        
-        println!("Читаю BOT_TOKEN...");
-        let bot_token = match env::var("BOT_TOKEN") {
-            Ok(token) => {
-                println!("BOT_TOKEN прочитан успешно (длина: {})", token.len());
-                token
-            },
-            Err(e) => {
-                println!("ОШИБКА: BOT_TOKEN не найден: {}", e);
-                return Err(anyhow::anyhow!("BOT_TOKEN пуст"));
-            }
-        };
-        log::info!("BOT_TOKEN прочитан успешно");
-        
-        println!("Читаю ALLOWED_USERS...");
-        let allowed_users_str = env::var("ALLOWED_USERS").unwrap_or_default();
-        println!("ALLOWED_USERS строка: '{}'", allowed_users_str);
-        log::info!("ALLOWED_USERS строка: '{}'", allowed_users_str);
-        
-        let allowed_users = allowed_users_str
-            .split(',')
-            .filter_map(|s| s.trim().parse::<i64>().ok())
-            .collect::<Vec<_>>();
-        println!("ALLOWED_USERS распарсены: {:?}", allowed_users);
-        log::info!("ALLOWED_USERS распарсены: {:?}", allowed_users);
-        
-        println!("Читаю SERVER_MAC...");
-        let server_mac = match env::var("SERVER_MAC") {
-            Ok(mac) => {
-                // Валидируем MAC адрес для безопасности
-                if !is_valid_mac(&mac) {
-                    println!("ОШИБКА: SERVER_MAC имеет некорректный формат: '{}'", mac);
-                    return Err(anyhow::anyhow!("SERVER_MAC имеет некорректный формат"));
-                }
-                println!("SERVER_MAC прочитан и валиден: '{}'", mac);
-                mac
-            },
-            Err(e) => {
-                println!("ОШИБКА: SERVER_MAC не найден: {}", e);
-                return Err(anyhow::anyhow!("SERVER_MAC пуст"));
-            }
-        };
-        log::info!("SERVER_MAC прочитан: '{}'", server_mac);
-
-        if allowed_users.is_empty() {
-            println!("ОШИБКА: ALLOWED_USERS список пуст после парсинга!");
-            anyhow::bail!("ALLOWED_USERS пуст");
-        }
-
-        println!("Все обязательные переменные прочитаны успешно");
-        log::info!("Все обязательные переменные прочитаны успешно");
-
-        Ok(Self {
-            bot_token,
-            allowed_users,
-            server_mac,
-
-            router_ssh_host: env::var("ROUTER_SSH_HOST").unwrap_or_else(|_| "localhost".into()),
-            router_ssh_port: env::var("ROUTER_SSH_PORT")
-                .ok()
-                .and_then(|s| s.parse().ok())
-                .unwrap_or(2223),
-            router_ssh_user: env::var("ROUTER_SSH_USER").unwrap_or_else(|_| "root".into()),
-            router_ssh_key: env::var("ROUTER_SSH_KEY_PATH").unwrap_or_else(|_| "/app/keys/id_router_vps_rsa_legacy".into()),
-
-            server_ssh_host: env::var("SERVER_SSH_HOST").unwrap_or_else(|_| "localhost".into()),
-            server_ssh_port: env::var("SERVER_SSH_PORT")
-                .ok()
-                .and_then(|s| s.parse().ok())
-                .unwrap_or(2222),
-            server_ssh_user: env::var("SERVER_SSH_USER").unwrap_or_else(|_| "friedcerebrum".into()),
-            server_ssh_key: env::var("SERVER_SSH_KEY_PATH").unwrap_or_else(|_| "/app/keys/id_rsa".into()),
-
-            ssh_timeout: Duration::from_secs(
-                env::var("SSH_TIMEOUT")
-                    .ok()
-                    .and_then(|s| s.parse().ok())
-                    .unwrap_or(15), // Увеличиваем таймаут
-            ),
-            nc_timeout: Duration::from_secs(
-                env::var("NC_TIMEOUT")
-                    .ok()
-                    .and_then(|s| s.parse().ok())
-                    .unwrap_or(5), // Увеличиваем таймаут
-            ),
-        })
+    fn host(&self, id: &str) -> Option<&HostConfig> {
+        self.hosts.iter().find(|h| h.id == id)
     }
 }
 
+// Идентификатор хоста попадает в callback data, поэтому держим его коротким и простым
+fn is_valid_host_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 32
+        && id.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-')
+}
+
 // Функция валидации MAC адреса для безопасности
 fn is_valid_mac(mac: &str) -> bool {
-    let mac_regex = regex::Regex::new(r"^([0-9A-Fa-f]{2}[:-]){5}([0-9A-Fa-f]{2})$").unwrap();
+    // Разделитель должен быть одинаковым по всему адресу
+    let mac_regex = regex::Regex::new(r"^(([0-9A-Fa-f]{2}:){5}|([0-9A-Fa-f]{2}-){5})[0-9A-Fa-f]{2}$").unwrap();
     mac_regex.is_match(mac)
 }
 
@@ -243,13 +265,21 @@ fn check_button_debounce(user_id: u64) -> bool {
 enum Command {
     #[command(description = "Показать главное меню")]
     Start,
+    #[command(description = "Получать оповещения о включении и выключении хостов")]
+    Subscribe,
+    #[command(description = "Отказаться от оповещений о хостах")]
+    Unsubscribe,
+    #[command(description = "Журнал действий: user=, host=, since=24h, until=, limit=, format=csv|jsonl")]
+    Audit,
+    #[command(description = "Код TOTP перед опасными действиями: /totp on — включить, /totp off — выключить")]
+    Totp,
 }
 
 fn is_allowed(config: &Config, user_id: Option<u64>) -> bool {
     match user_id {
         Some(uid) => {
             let uid = uid as i64;
-            let allowed = config.allowed_users.contains(&uid);
+            let allowed = config.allowed_users.contains(&uid) || access::is_granted(uid);
             println!("🔐 Проверка авторизации: пользователь {} -> {}", uid, allowed);
             log::info!("Проверка авторизации: пользователь {} -> {}", uid, allowed);
             allowed
@@ -262,13 +292,19 @@ fn is_allowed(config: &Config, user_id: Option<u64>) -> bool {
     }
 }
 
-async fn send_main_menu(bot: &Bot, msg: &Message, _config: &Config) -> Result<()> {
+async fn send_main_menu(bot: &Bot, msg: &Message, config: &Config) -> Result<()> {
     println!("📤 Отправляем главное меню");
-    let keyboard = main_keyboard();
+    let user_id = msg.from().map(|u| u.id.0).unwrap_or_default();
+    let keyboard = start_keyboard(config, user_id);
     println!("⌨️ Клавиатура создана: {:?}", keyboard);
     log::info!("Отправляем главное меню с клавиатурой");
     
-    bot.send_message(msg.chat.id, "🚀 Серверный менеджер\n\nВыберите действие:")
+    let text = if access::visible_hosts(config, user_id).is_empty() {
+        "🚀 Серверный менеджер\n\nНет серверов, к которым у вас есть доступ\\."
+    } else {
+        "🚀 Серверный менеджер\n\nВыберите действие:"
+    };
+    bot.send_message(msg.chat.id, text)
         .parse_mode(ParseMode::MarkdownV2)
         .reply_markup(keyboard)
         .await?;
@@ -276,54 +312,189 @@ async fn send_main_menu(bot: &Bot, msg: &Message, _config: &Config) -> Result<()
     Ok(())
 }
 
-fn main_keyboard() -> InlineKeyboardMarkup {
-    InlineKeyboardMarkup::new(vec![
-        vec![
-            InlineKeyboardButton::callback("🔌 Включить", "wol"),
-            InlineKeyboardButton::callback("🔴 Выключить", "shutdown_confirm"),
-        ],
-        vec![InlineKeyboardButton::callback("🟢 Статус", "status")],
-    ])
+// Стартовая клавиатура: при единственном доступном хосте сразу его действия, иначе список хостов
+fn start_keyboard(config: &Config, user_id: u64) -> InlineKeyboardMarkup {
+    match access::visible_hosts(config, user_id).as_slice() {
+        [host] => main_keyboard(config, host, user_id),
+        _ => hosts_keyboard(config, user_id),
+    }
 }
 
-// Централизованная функция установления SSH соединения
-fn establish_ssh_connection(
-    host: &str,
-    port: u16,
-    user: &str,
-    key_path: &str,
-    timeout: Duration,
-) -> Result<Session> {
-    log::info!("Устанавливаем SSH соединение с {}:{}", host, port);
-    
-    // Преобразуем localhost в 127.0.0.1 для корректного парсинга
-    let resolved_host = if host == "localhost" {
-        "127.0.0.1"
+// Список хостов, которые пользователь может видеть
+fn hosts_keyboard(config: &Config, user_id: u64) -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new(access::visible_hosts(config, user_id).into_iter().map(|host| {
+        vec![InlineKeyboardButton::callback(
+            format!("🖥 {}", host.name),
+            format!("host:{}", host.id),
+        )]
+    }))
+}
+
+// Действия с хостом; кнопки, на которые у пользователя нет прав, не показываются
+fn main_keyboard(config: &Config, host: &HostConfig, user_id: u64) -> InlineKeyboardMarkup {
+    let allows = |permission| access::allows_on(config, user_id, host, permission);
+    let mut power = Vec::new();
+    if allows(access::Permission::Wake) {
+        power.push(InlineKeyboardButton::callback("🔌 Включить", format!("wol:{}", host.id)));
+    }
+    if allows(access::Permission::Shutdown) {
+        power.push(InlineKeyboardButton::callback("🔴 Выключить", format!("shutdown_confirm:{}", host.id)));
+    }
+    let mut rows = vec![power, vec![InlineKeyboardButton::callback("🟢 Статус", format!("status:{}", host.id))]];
+    rows.retain(|row| !row.is_empty());
+    if access::visible_hosts(config, user_id).len() > 1 {
+        rows.push(vec![InlineKeyboardButton::callback("⬅️ К списку хостов", "hosts")]);
+    }
+    InlineKeyboardMarkup::new(rows)
+}
+
+// Действия, у которых после ':' стоит id хоста. У остальных там номер запроса или ошибки,
+// и искать по нему хост нельзя: хост с id "5" подменил бы проверку прав
+const HOST_ACTIONS: &[&str] = &["host", "wol", "shutdown_confirm", "shutdown_yes", "status", "cancel"];
+
+// Разбирает callback data вида "action:host_id".
+// Кнопки без хоста (из сообщений до появления инвентаря) относятся к единственному хосту.
+fn parse_callback_data<'a>(config: &'a Config, data: &'a str) -> (&'a str, Option<&'a HostConfig>) {
+    let (action, host_id) = match data.split_once(':') {
+        Some((action, arg)) => (action, Some(arg)),
+        None => (data, None),
+    };
+    if !HOST_ACTIONS.contains(&action) {
+        return (action, None);
+    }
+    match host_id {
+        Some(host_id) => (action, config.host(host_id)),
+        None if config.hosts.len() == 1 => (action, config.hosts.first()),
+        None => (action, None),
+    }
+}
+
+// Убирает управляющие символы и обрезает текст, чтобы ошибку можно было показать в чате
+fn sanitize_for_chat(text: &str, max_chars: usize) -> String {
+    let clean = text
+        .chars()
+        .filter(|c| !c.is_control() || *c == '\n')
+        .collect::<String>();
+    let clean = clean.trim();
+    if clean.chars().count() > max_chars {
+        format!("{}…", clean.chars().take(max_chars).collect::<String>())
     } else {
-        host
+        clean.to_string()
+    }
+}
+
+// Короткое описание ошибки для сообщения и полные подробности для кнопки «Подробнее»
+fn describe_error(e: &anyhow::Error) -> (String, String) {
+    if let Some(remote) = e.downcast_ref::<ssh::RemoteCommandError>() {
+        let output = &remote.output;
+        let details = format!(
+            "Команда: {}\nКод завершения: {}\n\nstderr:\n{}\n\nstdout:\n{}",
+            remote.command,
+            output.exit_status,
+            if output.stderr.trim().is_empty() { "—" } else { output.stderr.trim() },
+            if output.stdout.trim().is_empty() { "—" } else { output.stdout.trim() },
+        );
+        return (sanitize_for_chat(&remote.to_string(), 150), sanitize_for_chat(&details, 3000));
+    }
+
+    let root = e.root_cause().to_string();
+    let summary = root.lines().next().unwrap_or_default();
+    (sanitize_for_chat(summary, 150), sanitize_for_chat(&format!("{:#}", e), 3000))
+}
+
+// Запоминает подробности ошибки и возвращает краткий текст и id для кнопки
+fn record_error(host: &HostConfig, e: &anyhow::Error) -> (String, u64) {
+    let (summary, details) = describe_error(e);
+    let id = NEXT_ERROR_ID.fetch_add(1, Ordering::Relaxed);
+
+    let mut stored = ERROR_DETAILS.lock().unwrap();
+    stored.push_back((id, host.id.clone(), details));
+    while stored.len() > ERROR_DETAILS_LIMIT {
+        stored.pop_front();
+    }
+    (summary, id)
+}
+
+// Клавиатура под отчётом о статусе: сначала кнопка его обновления
+fn status_keyboard(config: &Config, host: &HostConfig, user_id: u64) -> InlineKeyboardMarkup {
+    let mut kb = main_keyboard(config, host, user_id);
+    kb.inline_keyboard.insert(
+        0,
+        vec![InlineKeyboardButton::callback("🔄 Обновить", format!("status:{}", host.id))],
+    );
+    kb
+}
+
+// Текст для сообщений с ParseMode::Html
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+fn failure_keyboard(config: &Config, host: &HostConfig, user_id: u64, details_id: u64) -> InlineKeyboardMarkup {
+    let mut kb = main_keyboard(config, host, user_id);
+    kb.inline_keyboard.insert(
+        0,
+        vec![InlineKeyboardButton::callback("🔍 Подробнее", format!("details:{}", details_id))],
+    );
+    kb
+}
+
+// Что показать по кнопке «Подробнее»
+enum ErrorDetails<'a> {
+    Found { host: &'a HostConfig, details: String },
+    // Хост ошибки закрыт для пользователя списком доступа
+    Denied(&'a HostConfig),
+    // Запись вытеснена новыми ошибками или хост убран из конфига
+    Missing,
+}
+
+// В кнопке только номер ошибки, без хоста, поэтому общей проверки прав при нажатии мало:
+// список доступа проверяем по хосту, сохранённому вместе с ошибкой
+fn error_details<'a>(config: &'a Config, user_id: u64, details_id: &str) -> ErrorDetails<'a> {
+    let found = details_id.parse::<u64>().ok().and_then(|id| {
+        ERROR_DETAILS
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(stored, _, _)| *stored == id)
+            .map(|(_, host, details)| (host.clone(), details.clone()))
+    });
+    let Some((host, details)) = found else {
+        return ErrorDetails::Missing;
     };
-    
-    let addr = format!("{}:{}", resolved_host, port);
-    log::debug!("Подключаемся к адресу: {}", addr);
-    
-    let tcp = TcpStream::connect_timeout(
-        &addr.parse()?,
-        timeout,
-    )?;
+    match config.host(&host) {
+        Some(host) if access::allows_on(config, user_id, host, access::Permission::View) => {
+            ErrorDetails::Found { host, details }
+        }
+        Some(host) => ErrorDetails::Denied(host),
+        None => ErrorDetails::Missing,
+    }
+}
 
-    let mut sess = Session::new()?;
-    sess.set_tcp_stream(tcp);
-    sess.handshake()?;
-    
-    sess.userauth_pubkey_file(user, None, Path::new(key_path), None)?;
+async fn show_error_details(bot: &Bot, q: &CallbackQuery, config: &Config, details_id: &str) -> Result<()> {
+    let (host, details) = match error_details(config, q.from.id.0, details_id) {
+        ErrorDetails::Found { host, details } => (Some(host), details),
+        ErrorDetails::Denied(host) => return deny_callback(bot, q, config, "details", Some(host)).await,
+        ErrorDetails::Missing => (None, String::new()),
+    };
+    safe_answer_callback_query(bot, &q.id).await?;
 
-    if !sess.authenticated() {
-        log::error!("SSH аутентификация не удалась для {}@{}:{}", user, host, port);
-        anyhow::bail!("SSH аутентификация не удалась");
+    if let Some(msg) = &q.message {
+        match host {
+            Some(host) => {
+                let text = format!("{}\n\n🔍 Подробности:\n{}", msg.text().unwrap_or_default(), details);
+                bot.edit_message_text(msg.chat.id, msg.id, sanitize_for_chat(&text, 4000))
+                    .reply_markup(main_keyboard(config, host, q.from.id.0))
+                    .await?;
+            }
+            None => {
+                bot.edit_message_text(msg.chat.id, msg.id, "⚠️ Подробности ошибки больше недоступны.")
+                    .reply_markup(start_keyboard(config, q.from.id.0))
+                    .await?;
+            }
+        }
     }
-    
-    log::info!("SSH соединение установлено успешно");
-    Ok(sess)
+    Ok(())
 }
 
 // Улучшенная обработка callback query с защитой от ошибок
@@ -341,10 +512,38 @@ async fn safe_answer_callback_query(bot: &Bot, callback_id: &str) -> Result<()>
     }
 }
 
-async fn handle_wol(bot: &Bot, q: &CallbackQuery, config: &Config) -> Result<()> {
+async fn show_hosts(bot: &Bot, q: &CallbackQuery, config: &Config) -> Result<()> {
+    safe_answer_callback_query(bot, &q.id).await?;
+
+    if let Some(msg) = &q.message {
+        let text = if access::visible_hosts(config, q.from.id.0).is_empty() {
+            "🚀 Серверный менеджер\n\nНет серверов, к которым у вас есть доступ."
+        } else {
+            "🚀 Серверный менеджер\n\nВыберите сервер:"
+        };
+        bot.edit_message_text(msg.chat.id, msg.id, text)
+            .reply_markup(hosts_keyboard(config, q.from.id.0))
+            .await?;
+    }
+    Ok(())
+}
+
+async fn show_host_menu(bot: &Bot, q: &CallbackQuery, config: &Config, host: &HostConfig) -> Result<()> {
+    log::info!("Пользователь {} выбрал хост '{}'", q.from.id.0, host.id);
+    safe_answer_callback_query(bot, &q.id).await?;
+
+    if let Some(msg) = &q.message {
+        bot.edit_message_text(msg.chat.id, msg.id, format!("🖥 {}\n\nВыберите действие:", host.name))
+            .reply_markup(main_keyboard(config, host, q.from.id.0))
+            .await?;
+    }
+    Ok(())
+}
+
+async fn handle_wol(bot: &Bot, q: &CallbackQuery, config: &Config, host: &HostConfig) -> Result<()> {
     let user_id = q.from.id.0;
-    println!("🔌 WOL Handler: Начало обработки для пользователя {}", user_id);
-    log::info!("Обрабатываем WOL запрос от пользователя {}", user_id);
+    println!("🔌 WOL Handler: Начало обработки для пользователя {}, хост '{}'", user_id, host.id);
+    log::info!("Обрабатываем WOL запрос от пользователя {} для хоста '{}'", user_id, host.id);
     
     // Проверяем дебаунсинг
     if !check_button_debounce(user_id) {
@@ -352,6 +551,7 @@ async fn handle_wol(bot: &Bot, q: &CallbackQuery, config: &Config) -> Result<()>
         safe_answer_callback_query(bot, &q.id).await?;
         if let Some(msg) = &q.message {
             bot.edit_message_text(msg.chat.id, msg.id, "⏳ Пожалуйста, подождите перед повторным нажатием")
+                .reply_markup(main_keyboard(config, host, q.from.id.0))
                 .await?;
         }
         return Ok(());
@@ -359,37 +559,63 @@ async fn handle_wol(bot: &Bot, q: &CallbackQuery, config: &Config) -> Result<()>
     
     safe_answer_callback_query(bot, &q.id).await?;
     
-    if let Some(msg) = &q.message {
-        bot.edit_message_text(msg.chat.id, msg.id, "⏳ Отправляю команду на включение...")
+    if totp_required(config, user_id, access::Permission::Wake) {
+        return ask_totp_code(bot, q, config, host, access::Permission::Wake).await;
+    }
+    run_wol(bot, &q.from, q.message.as_ref().map(|msg| (msg.chat.id, msg.id)), config, host).await
+}
+
+// Отправка magic packet после всех проверок: сразу по кнопке или после ввода кода TOTP
+async fn run_wol(
+    bot: &Bot,
+    user: &User,
+    message: Option<(ChatId, MessageId)>,
+    config: &Config,
+    host: &HostConfig,
+) -> Result<()> {
+    let user_id = user.id.0;
+    if let Some((chat_id, message_id)) = message {
+        bot.edit_message_text(chat_id, message_id, format!("⏳ Отправляю команду на включение {}...", host.name))
             .await?;
     }
 
-    match tokio::task::spawn_blocking({
-        let cfg = config.clone();
-        move || send_wol(&cfg)
-    })
-    .await?
-    {
+    match send_wol(config, host).await {
         Ok(_) => {
-            if let Some(msg) = &q.message {
+            audit::record(user, "wol", Some(host), audit::Outcome::Ok, "");
+            if let Some((chat_id, message_id)) = message {
+                let deadline = host.retry.deadline(host.boot_timeout);
                 bot.edit_message_text(
-                    msg.chat.id,
-                    msg.id,
-                    "🔌 Magic packet отправлен!\n\nСервер должен запуститься в течение 30 секунд.",
+                    chat_id,
+                    message_id,
+                    boot_progress_text(host, 1, Duration::ZERO, deadline, None, false),
                 )
-                .reply_markup(main_keyboard())
                 .await?;
+
+                // Следим за загрузкой в отдельной задаче, чтобы не блокировать другие кнопки в чате
+                tokio::spawn({
+                    let bot = bot.clone();
+                    let (chat_id, message_id) = (chat_id, message_id);
+                    let cfg = config.clone();
+                    let host = host.clone();
+                    async move {
+                        if let Err(e) = wait_for_boot(&bot, chat_id, message_id, &cfg, &host, user_id).await {
+                            log::error!("Ошибка ожидания загрузки хоста '{}': {}", host.id, e);
+                        }
+                    }
+                });
             }
         }
         Err(e) => {
-            log::error!("Ошибка WOL: {}", e);
-            if let Some(msg) = &q.message {
+            log::error!("Ошибка WOL для хоста '{}': {:#}", host.id, e);
+            audit::record(user, "wol", Some(host), audit::Outcome::Failed, &describe_error(&e).0);
+            if let Some((chat_id, message_id)) = message {
+                let (summary, details_id) = record_error(host, &e);
                 bot.edit_message_text(
-                    msg.chat.id, 
-                    msg.id, 
-                    "❌ Не удалось отправить команду включения.\nПроверьте настройки сети."
+                    chat_id,
+                    message_id,
+                    format!("❌ Не удалось отправить команду включения.\n\n{}", summary)
                 )
-                .reply_markup(main_keyboard())
+                .reply_markup(failure_keyboard(config, host, user_id, details_id))
                 .await?;
             }
         }
@@ -398,234 +624,1469 @@ async fn handle_wol(bot: &Bot, q: &CallbackQuery, config: &Config) -> Result<()>
     Ok(())
 }
 
-fn send_wol(config: &Config) -> Result<()> {
-    let sess = establish_ssh_connection(
-        &config.router_ssh_host,
-        config.router_ssh_port,
-        &config.router_ssh_user,
-        &config.router_ssh_key,
-        config.ssh_timeout,
-    )?;
-
-    let mut ch = sess.channel_session()?;
-    
-    // Используем безопасное форматирование команды
-    let safe_mac = config.server_mac.replace(|c: char| !c.is_ascii_hexdigit() && c != ':' && c != '-', "");
-    let command = format!("etherwake -i br-lan {}", safe_mac);
-    
-    log::info!("Выполняем WOL команду: {}", command);
-    ch.exec(&command)?;
-    ch.close()?;
-    
-    Ok(())
+async fn send_wol(config: &Config, host: &HostConfig) -> Result<()> {
+    host.wol.backend().wake(config, host).await
 }
 
-async fn ask_shutdown_confirm(bot: &Bot, q: &CallbackQuery) -> Result<()> {
-    let user_id = q.from.id.0;
-    println!("🔴 Shutdown Confirm Handler: Начало обработки для пользователя {}", user_id);
-    log::info!("Запрос подтверждения выключения от пользователя {}", user_id);
-    
-    // Проверяем дебаунсинг
-    if !check_button_debounce(user_id) {
-        log::warn!("Пользователь {} нажимает кнопки слишком быстро", user_id);
-        safe_answer_callback_query(bot, &q.id).await?;
-        return Ok(());
+fn boot_progress_text(
+    host: &HostConfig,
+    attempt: u32,
+    elapsed: Duration,
+    deadline: Duration,
+    state: Option<&HostState>,
+    resend_failed: bool,
+) -> String {
+    let attempts = match host.retry.attempts {
+        1 => String::new(),
+        total => format!(" (попытка {} из {})", attempt, total),
+    };
+    let mut detail = String::new();
+    if let Some(HostState::PortOpen { .. }) = state {
+        detail.push_str("\nSSH-порт открыт, жду готовности системы.");
     }
-    
-    safe_answer_callback_query(bot, &q.id).await?;
-    
-    if let Some(msg) = &q.message {
-        let kb = InlineKeyboardMarkup::new(vec![vec![
-            InlineKeyboardButton::callback("✅ Да, выключить", "shutdown_yes"),
-            InlineKeyboardButton::callback("❌ Отмена", "cancel"),
-        ]]);
-        bot.edit_message_text(msg.chat.id, msg.id, "⚠️ Подтверждение\n\nВы уверены, что хотите выключить сервер?")
-            .parse_mode(ParseMode::MarkdownV2)
-            .reply_markup(kb)
-            .await?;
+    if resend_failed {
+        detail.push_str("\n⚠️ Не удалось повторно отправить magic packet.");
     }
-    Ok(())
+    format!(
+        "🔌 Magic packet отправлен{}!\n\n⏳ Жду загрузки {}… {}с из {}с{}",
+        attempts,
+        host.name,
+        elapsed.as_secs(),
+        deadline.as_secs(),
+        detail
+    )
 }
 
-async fn handle_shutdown(bot: &Bot, q: &CallbackQuery, config: &Config) -> Result<()> {
-    let user_id = q.from.id.0;
-    log::info!("Обрабатываем запрос выключения от пользователя {}", user_id);
-    
-    // Проверяем дебаунсинг
-    if !check_button_debounce(user_id) {
-        log::warn!("Пользователь {} нажимает кнопки слишком быстро", user_id);
-        safe_answer_callback_query(bot, &q.id).await?;
-        return Ok(());
-    }
-    
-    safe_answer_callback_query(bot, &q.id).await?;
-    
-    if let Some(msg) = &q.message {
-        bot.edit_message_text(msg.chat.id, msg.id, "⏳ Отправляю команду на выключение...")
+// Оповещение админов о хосте, который не поднялся ни после одной из попыток
+fn boot_failure_alert(host: &HostConfig, attempts: u32, user_id: u64) -> String {
+    let attempts = match attempts {
+        1 => "Wake-on-LAN".to_string(),
+        n => format!("{} попыток Wake-on-LAN", n),
+    };
+    format!("🚨 {} не включился после {}.\nЗапросил пользователь {}.", host.name, attempts, user_id)
+}
+
+// Опрашивает хост той же проверкой, что и кнопка «Статус», пока он не ответит по SSH.
+// Если хост не поднялся за grace period, magic packet отправляется повторно по расписанию
+// host.retry; после последней попытки ждём ещё boot_timeout и сдаёмся, оповещая админов.
+async fn wait_for_boot(
+    bot: &Bot,
+    chat_id: ChatId,
+    message_id: MessageId,
+    config: &Config,
+    host: &HostConfig,
+    user_id: u64,
+) -> Result<()> {
+    let started = std::time::Instant::now();
+    let schedule = host.retry.schedule();
+    let deadline = host.retry.deadline(host.boot_timeout);
+    let mut attempt = 1;
+    let mut resend_failed = false;
+    log::info!(
+        "Ждём загрузки хоста '{}' (попыток: {}, до {}с)",
+        host.id,
+        host.retry.attempts,
+        deadline.as_secs()
+    );
+
+    loop {
+        tokio::time::sleep(BOOT_POLL_INTERVAL).await;
+
+        let state = probe_host(config.clone(), host.clone(), false).await.state;
+        let elapsed = started.elapsed();
+        log::debug!("Хост '{}' через {:?}: {:?}", host.id, elapsed, state);
+
+        if let HostState::Online { report } = &state {
+            log::info!("Хост '{}' загрузился за {}с, попыток: {}", host.id, elapsed.as_secs(), attempt);
+            bot.edit_message_text(
+                chat_id,
+                message_id,
+                format!(
+                    "🟢 {} онлайн через {}с\n\n<pre>{}</pre>",
+                    escape_html(&host.name),
+                    elapsed.as_secs(),
+                    escape_html(&report::render(report))
+                ),
+            )
+            .parse_mode(ParseMode::Html)
+            .reply_markup(status_keyboard(config, host, user_id))
             .await?;
-    }
+            return Ok(());
+        }
 
-    match tokio::task::spawn_blocking({
-        let cfg = config.clone();
-        move || send_shutdown(&cfg)
-    })
-    .await?
-    {
-        Ok(_) => {
-            if let Some(msg) = &q.message {
-                bot.edit_message_text(
-                    msg.chat.id, 
-                    msg.id, 
-                    "🔴 Команда выключения отправлена!"
-                )
-                .reply_markup(main_keyboard())
-                .await?;
-            }
+        if elapsed >= deadline {
+            log::warn!("Хост '{}' не загрузился за {}с после {} попыток", host.id, deadline.as_secs(), attempt);
+            bot.edit_message_text(
+                chat_id,
+                message_id,
+                format!(
+                    "❌ {} не ответил по SSH за {}с (попыток: {}).\nПроверьте питание и настройки Wake-on-LAN.",
+                    host.name,
+                    deadline.as_secs(),
+                    attempt
+                ),
+            )
+            .reply_markup(main_keyboard(config, host, user_id))
+            .await?;
+            notify_admins(bot, config, &boot_failure_alert(host, attempt, user_id)).await;
+            return Ok(());
         }
-        Err(e) => {
-            log::error!("Ошибка выключения: {}", e);
-            if let Some(msg) = &q.message {
-                bot.edit_message_text(
-                    msg.chat.id, 
-                    msg.id, 
-                    "❌ Не удалось отправить команду выключения.\nПроверьте настройки SSH."
-                )
-                .reply_markup(main_keyboard())
-                .await?;
+
+        // Пора отправить следующий пакет
+        if let Some(next) = schedule.get(attempt as usize) {
+            if elapsed >= *next {
+                attempt += 1;
+                log::info!("Повторно отправляем magic packet хосту '{}', попытка {} из {}", host.id, attempt, host.retry.attempts);
+                resend_failed = match send_wol(config, host).await {
+                    Ok(_) => false,
+                    Err(e) => {
+                        log::error!("Попытка {} WOL для хоста '{}' не удалась: {}", attempt, host.id, e);
+                        true
+                    }
+                };
             }
         }
+
+        if let Err(e) = bot
+            .edit_message_text(
+                chat_id,
+                message_id,
+                boot_progress_text(host, attempt, elapsed, deadline, Some(&state), resend_failed),
+            )
+            .await
+        {
+            log::warn!("Не удалось обновить прогресс загрузки: {}", e);
+        }
     }
-    Ok(())
 }
 
-fn send_shutdown(config: &Config) -> Result<()> {
-    let sess = establish_ssh_connection(
-        &config.server_ssh_host,
-        config.server_ssh_port,
-        &config.server_ssh_user,
-        &config.server_ssh_key,
-        config.ssh_timeout,
-    )?;
+// Пользователи с ролью admin, включая назначенных из чата; без ролей в конфиге ими считаются все
+fn admin_ids(config: &Config) -> Vec<i64> {
+    access::admin_ids(config)
+}
 
-    let mut ch = sess.channel_session()?;
-    
-    log::info!("Выполняем команду выключения");
-    ch.exec("sudo /sbin/shutdown -h now")?;
-    ch.close()?;
-    
+fn is_admin(config: &Config, user_id: u64) -> bool {
+    access::allows(config, user_id, access::Permission::Manage)
+}
+
+// Отказ в действии, на которое у пользователя нет прав: всплывающее сообщение и запись в журнал
+async fn deny_callback(
+    bot: &Bot,
+    q: &CallbackQuery,
+    config: &Config,
+    action: &str,
+    host: Option<&HostConfig>,
+) -> Result<()> {
+    let role = access::user_role(config, q.from.id.0).map(|r| r.to_string()).unwrap_or_default();
+    // Роль позволяет действие, но хост закрыт списком доступа
+    let acl_host = host.filter(|host| {
+        access::permission_for(action).is_some_and(|permission| !access::acl_allows(config, q.from.id.0, host, permission))
+    });
+    let (details, text) = match acl_host {
+        Some(host) => ("acl".to_string(), format!("⛔ Нет доступа к {}", host.name)),
+        None => (role.clone(), format!("⛔ Недостаточно прав (роль {})", role)),
+    };
+    log::warn!("Пользователю {} ({}) запрещено действие '{}': {}", q.from.id.0, role, action, details);
+    audit::record(&q.from, action, host, audit::Outcome::Denied, &details);
+    bot.answer_callback_query(&q.id)
+        .text(text)
+        .show_alert(true)
+        .await?;
     Ok(())
 }
 
-async fn handle_status(bot: &Bot, q: &CallbackQuery, config: &Config) -> Result<()> {
-    let user_id = q.from.id.0;
-    println!("🟢 Status Handler: Начало обработки для пользователя {}", user_id);
-    log::info!("Проверяем статус сервера по запросу пользователя {}", user_id);
-    
-    // Проверяем дебаунсинг
-    if !check_button_debounce(user_id) {
-        log::warn!("Пользователь {} нажимает кнопки слишком быстро", user_id);
-        safe_answer_callback_query(bot, &q.id).await?;
-        return Ok(());
-    }
-    
-    safe_answer_callback_query(bot, &q.id).await?;
-    
-    if let Some(msg) = &q.message {
-        bot.edit_message_text(msg.chat.id, msg.id, "⏳ Проверяю статус сервера...")
-            .await?;
+// Оповещение админам в личные чаты
+async fn notify_admins(bot: &Bot, config: &Config, text: &str) {
+    for admin in admin_ids(config) {
+        if let Err(e) = bot.send_message(ChatId(admin), text).await {
+            log::warn!("Не удалось отправить оповещение админу {}: {}", admin, e);
+        }
     }
+}
 
-    match tokio::time::timeout(config.nc_timeout, check_status(config.clone())).await {
-        Ok(Ok(info)) => {
-            if let Some(msg) = &q.message {
-                bot.edit_message_text(msg.chat.id, msg.id, info)
-                    .reply_markup(main_keyboard())
-                    .await?;
-            }
-        }
-        Ok(Err(e)) => {
-            log::error!("Ошибка проверки статуса: {}", e);
-            if let Some(msg) = &q.message {
-                bot.edit_message_text(
-                    msg.chat.id, 
-                    msg.id, 
-                    "❌ Не удалось проверить статус сервера.\nПроверьте настройки SSH."
-                )
-                .reply_markup(main_keyboard())
-                .await?;
+// Пересылает админам события проверки ключей хостов: новые ключи с кнопками подтверждения
+// и несовпадения ключей
+async fn forward_host_key_events(bot: Bot, config: Arc<Config>) {
+    let Some(mut events) = ssh::take_host_key_events() else {
+        return;
+    };
+    while let Some(event) = events.recv().await {
+        match event {
+            ssh::HostKeyEvent::NewKey { id, host, fingerprint } => {
+                let text = format!(
+                    "🔑 Новый ключ SSH у {}\n{}\n\nДоверять этому ключу? До подтверждения подключения к хосту заблокированы.",
+                    host, fingerprint
+                );
+                let keyboard = InlineKeyboardMarkup::new(vec![vec![
+                    InlineKeyboardButton::callback("✅ Доверять", format!("hostkey_ok:{}", id)),
+                    InlineKeyboardButton::callback("❌ Отклонить", format!("hostkey_no:{}", id)),
+                ]]);
+                for admin in admin_ids(&config) {
+                    if let Err(e) = bot.send_message(ChatId(admin), &text).reply_markup(keyboard.clone()).await {
+                        log::warn!("Не удалось отправить запрос на подтверждение ключа админу {}: {}", admin, e);
+                    }
+                }
             }
-        }
-        Err(_) => {
-            if let Some(msg) = &q.message {
-                bot.edit_message_text(
-                    msg.chat.id, 
-                    msg.id, 
-                    "⏱️ Таймаут проверки статуса!"
-                )
-                .reply_markup(main_keyboard())
-                .await?;
+            ssh::HostKeyEvent::Mismatch { host, fingerprint } => {
+                let text = format!(
+                    "🚨 Ключ SSH у {} не совпадает с известным!\nПолучен {}\n\n\
+                     Действия с хостом заблокированы. Если ключ сменили намеренно, обновите known_hosts.",
+                    host, fingerprint
+                );
+                notify_admins(&bot, &config, &text).await;
             }
         }
     }
+}
 
-    Ok(())
+async fn forward_tunnel_events(bot: Bot, config: Arc<Config>) {
+    let Some(mut events) = tunnel::take_events() else {
+        return;
+    };
+    while let Some(event) = events.recv().await {
+        notify_admins(&bot, &config, &event.to_string()).await;
+    }
 }
 
-async fn check_status(config: Config) -> Result<String> {
-    // Преобразуем localhost в 127.0.0.1 для корректного соединения
-    let resolved_host = if config.server_ssh_host == "localhost" {
-        "127.0.0.1"
-    } else {
-        &config.server_ssh_host
+// Рассылает подписчикам подтверждённые мониторингом переходы хостов, которые им доступны
+async fn forward_monitor_events(bot: Bot, config: Arc<Config>) {
+    let Some(mut events) = monitor::take_events() else {
+        return;
     };
-    
-    let addr = format!("{}:{}", resolved_host, config.server_ssh_port);
-    log::debug!("Проверяем статус по адресу: {}", addr);
-    
-    match tokio::net::TcpStream::connect(addr.clone()).await {
-        Ok(_) => {
-            // Пробуем более детально получить uptime
-            match tokio::task::spawn_blocking(move || {
-                let sess = establish_ssh_connection(
-                    &config.server_ssh_host,
-                    config.server_ssh_port,
-                    &config.server_ssh_user,
-                    &config.server_ssh_key,
-                    config.ssh_timeout,
-                )?;
-                
-                let mut ch = sess.channel_session()?;
-                ch.exec("uptime")?;
-                let mut s = String::new();
-                ch.read_to_string(&mut s)?;
-                ch.close()?;
-                Ok::<_, anyhow::Error>(format!("🟢 Сервер онлайн\n\n{}", s.trim()))
-            })
-            .await
-            {
-                Ok(Ok(s)) => Ok(s),
-                Ok(Err(e)) => {
-                    log::warn!("Не удалось получить uptime: {}", e);
-                    Ok("🟢 Сервер онлайн\n\nSSH-туннель активен.".into())
-                },
-                Err(_) => Ok("🟢 Сервер онлайн\n\nSSH-туннель активен.".into()),
+    while let Some(event) = events.recv().await {
+        let text = event.to_string();
+        let host = config.host(&event.host_id);
+        for user in monitor::subscribers() {
+            if host.is_some_and(|host| !access::allows_on(&config, user as u64, host, access::Permission::View)) {
+                continue;
+            }
+            if let Err(e) = bot.send_message(ChatId(user), &text).await {
+                log::warn!("Не удалось отправить оповещение мониторинга пользователю {}: {}", user, e);
             }
         }
-        Err(_) => Ok("🔴 Сервер оффлайн\n\nSSH-туннель не отвечает.".into()),
     }
 }
 
-async fn cancel(bot: &Bot, q: &CallbackQuery) -> Result<()> {
-    log::info!("Отмена операции пользователем {}", q.from.id.0);
+// /audit: журнал действий для админов, списком в чат или файлом CSV/JSONL
+async fn handle_audit_command(bot: &Bot, msg: &Message, config: &Config, args: &str) -> Result<()> {
+    let Some(user) = msg.from() else {
+        return Ok(());
+    };
+    if !is_admin(config, user.id.0) {
+        log::warn!("Пользователь {} не админ и не может читать журнал", user.id.0);
+        audit::record(user, "audit", None, audit::Outcome::Denied, args);
+        bot.send_message(msg.chat.id, "⛔ Журнал действий доступен только админам.").await?;
+        return Ok(());
+    }
+
+    let now = store::now();
+    let query = match audit::parse_query(args, now) {
+        Ok(query) => query,
+        Err(e) => {
+            bot.send_message(
+                msg.chat.id,
+                format!("⚠️ {}
+
+Пример: /audit user=alice host=server since=24h format=csv", e),
+            )
+            .await?;
+            return Ok(());
+        }
+    };
+    let Some(store) = store::get() else {
+        bot.send_message(msg.chat.id, "⚠️ База недоступна, журнал прочитать нельзя.").await?;
+        return Ok(());
+    };
+    let mut events = store.audit_events(&query.filter)?;
+    log::info!("Пользователь {} читает журнал: {:?}, записей {}", user.id.0, query, events.len());
+
+    match query.format {
+        None => {
+            bot.send_message(msg.chat.id, sanitize_for_chat(&audit::render(&events), 4000)).await?;
+        }
+        Some(format) => {
+            // В файле — по времени, как в обычном логе
+            events.reverse();
+            let file = InputFile::memory(audit::export(&events, format)).file_name(audit::file_name(format, now));
+            bot.send_document(msg.chat.id, file)
+                .caption(format!("📜 Журнал действий, записей: {}", events.len()))
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+async fn set_subscription(bot: &Bot, msg: &Message, subscribe: bool) -> Result<()> {
+    let Some(user) = msg.from() else {
+        return Ok(());
+    };
+    let user_id = user.id.0 as i64;
+    let changed = if subscribe { monitor::subscribe(user_id) } else { monitor::unsubscribe(user_id) };
+    let text = match (subscribe, changed) {
+        (true, true) => "🔔 Вы подписаны на оповещения о включении и выключении хостов.",
+        (true, false) => "🔔 Вы уже подписаны на оповещения.",
+        (false, true) => "🔕 Оповещения о хостах отключены.",
+        (false, false) => "🔕 Вы не были подписаны на оповещения.",
+    };
+    log::info!("Пользователь {} {} оповещения мониторинга", user_id, if subscribe { "включил" } else { "отключил" });
+    bot.send_message(msg.chat.id, text).await?;
+    Ok(())
+}
+
+async fn handle_host_key_decision(bot: &Bot, q: &CallbackQuery, config: &Config, request_id: &str, approve: bool) -> Result<()> {
+    safe_answer_callback_query(bot, &q.id).await?;
+
+    let action = if approve { "hostkey_approve" } else { "hostkey_reject" };
+    if !is_admin(config, q.from.id.0) {
+        log::warn!("Пользователь {} не админ и не может подтверждать ключи хостов", q.from.id.0);
+        audit::record(&q.from, action, None, audit::Outcome::Denied, "");
+        return Ok(());
+    }
+
+    let (Some(store), Ok(id)) = (store::get(), request_id.parse::<u64>()) else {
+        return Ok(());
+    };
+    let text = if approve {
+        match ssh::approve_host_key(&store, &config.host_keys, id) {
+            Ok((host, fingerprint)) => {
+                audit::record(&q.from, action, None, audit::Outcome::Ok, &format!("{} {}", host, fingerprint));
+                format!("✅ Ключ {} для {} добавлен в known_hosts.", fingerprint, host)
+            }
+            Err(e) => {
+                audit::record(&q.from, action, None, audit::Outcome::Failed, &e.to_string());
+                format!("⚠️ Не удалось подтвердить ключ: {}", e)
+            }
+        }
+    } else {
+        match ssh::reject_host_key(&store, id)? {
+            Some((host, fingerprint)) => {
+                audit::record(&q.from, action, None, audit::Outcome::Ok, &format!("{} {}", host, fingerprint));
+                format!("❌ Ключ {} для {} отклонён.", fingerprint, host)
+            }
+            None => "⚠️ Запрос на подтверждение ключа уже обработан.".to_string(),
+        }
+    };
+    log::info!("Решение по ключу хоста от {}: {}", q.from.id.0, text);
+
+    if let Some(msg) = &q.message {
+        bot.edit_message_text(msg.chat.id, msg.id, text).await?;
+    }
+    Ok(())
+}
+
+// Проверка, не запрашивал ли пользователь доступ совсем недавно
+fn check_access_request_cooldown(user_id: u64) -> bool {
+    let mut requests = ACCESS_REQUESTS.lock().unwrap();
+    let now = std::time::Instant::now();
+    if let Some(last) = requests.get(&user_id) {
+        if now.duration_since(*last) < ACCESS_REQUEST_COOLDOWN {
+            return false;
+        }
+    }
+    requests.insert(user_id, now);
+    true
+}
+
+// Неизвестному пользователю в личном чате показываем его ID и кнопку запроса доступа
+async fn offer_access_request(bot: &Bot, msg: &Message) -> Result<()> {
+    let Some(user) = msg.from() else {
+        return Ok(());
+    };
+    if !msg.chat.is_private() {
+        return Ok(());
+    }
+    let keyboard = InlineKeyboardMarkup::new(vec![vec![InlineKeyboardButton::callback(
+        "🙋 Запросить доступ",
+        access::ACCESS_REQUEST,
+    )]]);
+    bot.send_message(
+        msg.chat.id,
+        format!("⛔ У вас нет доступа к этому боту.\n\nВаш ID: {}. Можно отправить админам запрос на доступ.", user.id.0),
+    )
+    .reply_markup(keyboard)
+    .await?;
+    Ok(())
+}
+
+// Клавиатура запроса доступа у админа: выбор роли и решение
+fn access_request_keyboard(request_id: i64, selected: access::Role) -> InlineKeyboardMarkup {
+    let roles = [access::Role::Viewer, access::Role::Operator, access::Role::Admin]
+        .into_iter()
+        .map(|role| {
+            let mark = if role == selected { "●" } else { "○" };
+            InlineKeyboardButton::callback(format!("{} {}", mark, role), format!("access_role:{}:{}", request_id, role))
+        })
+        .collect::<Vec<_>>();
+    InlineKeyboardMarkup::new(vec![
+        roles,
+        vec![
+            InlineKeyboardButton::callback("✅ Одобрить", format!("access_ok:{}:{}", request_id, selected)),
+            InlineKeyboardButton::callback("❌ Отклонить", format!("access_no:{}", request_id)),
+        ],
+    ])
+}
+
+// Кнопка «Запросить доступ»: запрос сохраняется в базе и уходит всем админам
+async fn handle_access_request(bot: &Bot, q: &CallbackQuery, config: &Config) -> Result<()> {
+    safe_answer_callback_query(bot, &q.id).await?;
+    let user_id = q.from.id.0;
+    let name = audit::user_name(&q.from);
+
+    let text = if is_allowed(config, Some(user_id)) {
+        "✅ У вас уже есть доступ. Нажмите /start.".to_string()
+    } else if let Some(store) = store::get() {
+        let pending = store.pending_ops(access::ACCESS_REQUEST)?;
+        if pending.iter().any(|op| op.requested_by == user_id as i64) {
+            "⏳ Запрос уже отправлен, дождитесь решения админов.".to_string()
+        } else if !check_access_request_cooldown(user_id) {
+            "⏳ Запрос можно отправлять не чаще раза в 10 минут.".to_string()
+        } else {
+            let request_id = store.add_pending_op(&store::PendingOp {
+                id: 0,
+                kind: access::ACCESS_REQUEST.to_string(),
+                host: None,
+                requested_by: user_id as i64,
+                created_at: store::now(),
+                expires_at: None,
+                payload: name.clone(),
+            })?;
+            audit::record(&q.from, "access_request", None, audit::Outcome::Ok, "");
+            log::info!("Пользователь {} ({}) запросил доступ, запрос {}", name, user_id, request_id);
+
+            let request = format!(
+                "🙋 Запрос доступа от {} (ID {})\n\nВыберите роль и одобрите или отклоните запрос.",
+                name, user_id
+            );
+            let mut delivered = 0;
+            for admin in admin_ids(config) {
+                match bot
+                    .send_message(ChatId(admin), &request)
+                    .reply_markup(access_request_keyboard(request_id, access::Role::Viewer))
+                    .await
+                {
+                    Ok(_) => delivered += 1,
+                    Err(e) => log::warn!("Не удалось отправить запрос доступа админу {}: {}", admin, e),
+                }
+            }
+            if delivered > 0 {
+                "📨 Запрос отправлен админам. Я напишу, когда его рассмотрят.".to_string()
+            } else {
+                store.remove_pending_op(request_id)?;
+                "⚠️ Не удалось связаться ни с одним админом, попробуйте позже.".to_string()
+            }
+        }
+    } else {
+        "⚠️ Сейчас запросить доступ нельзя, попробуйте позже.".to_string()
+    };
+
+    if let Some(msg) = &q.message {
+        bot.edit_message_text(msg.chat.id, msg.id, text).await?;
+    }
+    Ok(())
+}
+
+// Решение админа по запросу доступа: выбор роли, одобрение или отказ
+async fn handle_access_decision(bot: &Bot, q: &CallbackQuery, config: &Config, action: &str, args: &str) -> Result<()> {
+    safe_answer_callback_query(bot, &q.id).await?;
+    if !is_admin(config, q.from.id.0) {
+        log::warn!("Пользователь {} не админ и не может выдавать доступ", q.from.id.0);
+        audit::record(&q.from, action, None, audit::Outcome::Denied, args);
+        return Ok(());
+    }
+    let Some(msg) = &q.message else {
+        return Ok(());
+    };
+
+    let (request_id, role) = match args.split_once(':') {
+        Some((id, role)) => (id, access::Role::from_name(role)),
+        None => (args, None),
+    };
+    let Some(store) = store::get() else {
+        return Ok(());
+    };
+    let request = match request_id.parse::<i64>() {
+        Ok(id) => store.pending_op(id)?.filter(|op| op.kind == access::ACCESS_REQUEST),
+        Err(_) => None,
+    };
+    let Some(request) = request else {
+        bot.edit_message_text(msg.chat.id, msg.id, "⚠️ Запрос доступа уже обработан.").await?;
+        return Ok(());
+    };
+    let name = &request.payload;
+
+    match (action, role) {
+        ("access_role", Some(role)) => {
+            bot.edit_message_reply_markup(msg.chat.id, msg.id)
+                .reply_markup(access_request_keyboard(request.id, role))
+                .await?;
+        }
+        ("access_ok", Some(role)) => {
+            if !store.remove_pending_op(request.id)? {
+                bot.edit_message_text(msg.chat.id, msg.id, "⚠️ Запрос доступа уже обработан.").await?;
+                return Ok(());
+            }
+            let details = format!("{} {} {}", request.requested_by, name, role);
+            if let Err(e) = access::grant(&store, request.requested_by, name, role, q.from.id.0 as i64) {
+                audit::record(&q.from, "access_approve", None, audit::Outcome::Failed, &details);
+                return Err(e);
+            }
+            audit::record(&q.from, "access_approve", None, audit::Outcome::Ok, &details);
+            log::info!("Пользователь {} получил доступ с ролью {} от {}", request.requested_by, role, q.from.id.0);
+            bot.edit_message_text(
+                msg.chat.id,
+                msg.id,
+                format!("✅ {} (ID {}) получил доступ с ролью {}.", name, request.requested_by, role),
+            )
+            .await?;
+            if let Err(e) = bot
+                .send_message(
+                    ChatId(request.requested_by),
+                    format!("✅ Доступ к боту открыт, ваша роль: {}. Нажмите /start.", role),
+                )
+                .await
+            {
+                log::warn!("Не удалось сообщить пользователю {} о выданном доступе: {}", request.requested_by, e);
+            }
+        }
+        ("access_no", _) => {
+            if !store.remove_pending_op(request.id)? {
+                bot.edit_message_text(msg.chat.id, msg.id, "⚠️ Запрос доступа уже обработан.").await?;
+                return Ok(());
+            }
+            let details = format!("{} {}", request.requested_by, name);
+            audit::record(&q.from, "access_deny", None, audit::Outcome::Ok, &details);
+            log::info!("Запрос доступа пользователя {} отклонён админом {}", request.requested_by, q.from.id.0);
+            bot.edit_message_text(
+                msg.chat.id,
+                msg.id,
+                format!("❌ Запрос доступа от {} (ID {}) отклонён.", name, request.requested_by),
+            )
+            .await?;
+            if let Err(e) = bot.send_message(ChatId(request.requested_by), "❌ Админ отклонил запрос доступа.").await {
+                log::warn!("Не удалось сообщить пользователю {} об отказе: {}", request.requested_by, e);
+            }
+        }
+        _ => log::warn!("Некорректное решение по запросу доступа: {}:{}", action, args),
+    }
+    Ok(())
+}
+
+// Как действие под кодом TOTP называется в журнале и в сообщениях.
+// Под код ставятся только включение и выключение (totp.actions), у остальных прав имени нет
+fn totp_action_names(permission: access::Permission) -> Option<(&'static str, &'static str)> {
+    match permission {
+        access::Permission::Wake => Some(("wol", "включить")),
+        access::Permission::Shutdown => Some(("shutdown", "выключить")),
+        access::Permission::View | access::Permission::Manage => None,
+    }
+}
+
+// Нужен ли код TOTP перед действием: оно выбрано в настройках и пользователь включил TOTP
+fn totp_required(config: &Config, user_id: u64, permission: access::Permission) -> bool {
+    if !config.totp.actions.contains(&permission) {
+        return false;
+    }
+    let Some(store) = store::get() else {
+        return false;
+    };
+    match totp::secret(&store, user_id as i64) {
+        Ok(secret) => secret.is_some(),
+        Err(e) => {
+            // Не знаем, включён ли TOTP, — значит, без кода не пускаем
+            log::error!("Не удалось прочитать секрет TOTP пользователя {}: {:#}", user_id, e);
+            true
+        }
+    }
+}
+
+// Вместо действия просим прислать код; само действие выполнит handle_totp_code
+async fn ask_totp_code(
+    bot: &Bot,
+    q: &CallbackQuery,
+    config: &Config,
+    host: &HostConfig,
+    permission: access::Permission,
+) -> Result<()> {
+    let Some(msg) = &q.message else {
+        return Ok(());
+    };
+    let user_id = q.from.id.0;
+    let Some((action, verb)) = totp_action_names(permission) else {
+        anyhow::bail!("действие '{}' не подтверждается кодом TOTP", permission);
+    };
+    let Some(store) = store::get() else {
+        bot.edit_message_text(msg.chat.id, msg.id, "⚠️ База недоступна, проверить код нельзя.")
+            .reply_markup(main_keyboard(config, host, user_id))
+            .await?;
+        return Ok(());
+    };
+    let now = store::now();
+    if let Some(wait) = totp::locked_for(&store, user_id, now)? {
+        audit::record(&q.from, action, Some(host), audit::Outcome::Denied, "ввод кода TOTP заблокирован");
+        bot.edit_message_text(
+            msg.chat.id,
+            msg.id,
+            format!("🔒 Слишком много неверных кодов. Попробуйте через {}.", tunnel::format_duration(wait)),
+        )
+        .reply_markup(main_keyboard(config, host, user_id))
+        .await?;
+        return Ok(());
+    }
+
+    log::info!("Пользователь {} должен ввести код TOTP, чтобы {} '{}'", user_id, verb, host.id);
+    totp::set_pending(
+        &store,
+        user_id,
+        totp::Pending::Action { permission, host: host.id.clone(), chat: msg.chat.id, message: msg.id },
+        now,
+    )?;
+    let keyboard = InlineKeyboardMarkup::new(vec![vec![InlineKeyboardButton::callback(
+        "❌ Отмена",
+        format!("cancel:{}", host.id),
+    )]]);
+    bot.edit_message_text(
+        msg.chat.id,
+        msg.id,
+        format!(
+            "🔐 Отправьте в чат 6-значный код из приложения-аутентификатора, чтобы {} {}.\n\nЖду код {}.",
+            verb,
+            host.name,
+            tunnel::format_duration(totp::CODE_TIMEOUT)
+        ),
+    )
+    .reply_markup(keyboard)
+    .await?;
+    Ok(())
+}
+
+// Код, присланный в чат: подтверждает включение TOTP, его выключение или отложенное действие
+async fn handle_totp_code(bot: &Bot, msg: &Message, config: &Config, code: &str) -> Result<()> {
+    let Some(user) = msg.from() else {
+        return Ok(());
+    };
+    let user_id = user.id.0;
+    let Some(store) = store::get() else {
+        return Ok(());
+    };
+    let now = store::now();
+    let Some((pending, fresh)) = totp::take_pending(&store, user_id, now)? else {
+        return Ok(());
+    };
+    // Код не должен оставаться в переписке
+    if let Err(e) = bot.delete_message(msg.chat.id, msg.id).await {
+        log::debug!("Не удалось удалить сообщение с кодом: {}", e);
+    }
+
+    let (action, host) = match &pending {
+        totp::Pending::Enroll { .. } => ("totp_enroll", None),
+        totp::Pending::Disable => ("totp_disable", None),
+        totp::Pending::Action { permission, host, .. } => {
+            let Some((action, _)) = totp_action_names(*permission) else {
+                log::warn!("Код TOTP пришёл для действия '{}', которое кодом не подтверждается", permission);
+                return Ok(());
+            };
+            (action, config.host(host))
+        }
+    };
+    // Сообщение с кнопками, которое ждало кода, возвращаем в меню хоста
+    let restore_menu = |text: String| async {
+        if let (totp::Pending::Action { chat, message, .. }, Some(host)) = (&pending, host) {
+            bot.edit_message_text(*chat, *message, text).reply_markup(main_keyboard(config, host, user_id)).await?;
+        } else {
+            bot.send_message(msg.chat.id, text).await?;
+        }
+        anyhow::Ok(())
+    };
+
+    if !fresh {
+        return restore_menu("⌛ Время на ввод кода истекло, начните заново.".to_string()).await;
+    }
+    if let Some(wait) = totp::locked_for(&store, user_id, now)? {
+        audit::record(user, action, host, audit::Outcome::Denied, "ввод кода TOTP заблокирован");
+        return restore_menu(format!("🔒 Слишком много неверных кодов. Попробуйте через {}.", tunnel::format_duration(wait)))
+            .await;
+    }
+
+    let valid = match &pending {
+        totp::Pending::Enroll { secret } => match totp::check(secret, code, now, None) {
+            Some(step) => {
+                totp::enroll(&store, user_id as i64, secret, step)?;
+                true
+            }
+            None => false,
+        },
+        _ => totp::verify(&store, user_id as i64, code, now)?,
+    };
+    if !valid {
+        let left = totp::record_failure(&store, user_id, now)?;
+        log::warn!("Неверный код TOTP от пользователя {} ({}), осталось попыток {}", user_id, action, left);
+        audit::record(user, action, host, audit::Outcome::Denied, &format!("неверный код TOTP, осталось попыток {}", left));
+        if left == 0 {
+            return restore_menu("🔒 Слишком много неверных кодов. Ввод кода временно заблокирован.".to_string()).await;
+        }
+        totp::set_pending(&store, user_id, pending, now)?;
+        bot.send_message(msg.chat.id, format!("❌ Неверный код, осталось попыток: {}. Отправьте код ещё раз.", left))
+            .await?;
+        return Ok(());
+    }
+    totp::reset_failures(&store, user_id)?;
+
+    match pending {
+        totp::Pending::Enroll { .. } => {
+            audit::record(user, action, None, audit::Outcome::Ok, "");
+            bot.send_message(
+                msg.chat.id,
+                "✅ Двухфакторная проверка включена. Сообщение с секретом лучше удалить.",
+            )
+            .await?;
+        }
+        totp::Pending::Disable => {
+            totp::disable(&store, user_id as i64)?;
+            audit::record(user, action, None, audit::Outcome::Ok, "");
+            bot.send_message(msg.chat.id, "✅ Двухфакторная проверка выключена.").await?;
+        }
+        totp::Pending::Action { permission, chat, message, .. } => {
+            let Some(host) = host else {
+                return Ok(());
+            };
+            // Права проверяем ещё раз: пока ждали код, конфиг мог смениться
+            if !access::allows_on(config, user_id, host, permission) {
+                audit::record(user, action, Some(host), audit::Outcome::Denied, "");
+                return restore_menu("⛔ Недостаточно прав.".to_string()).await;
+            }
+            match permission {
+                access::Permission::Wake => run_wol(bot, user, Some((chat, message)), config, host).await?,
+                access::Permission::Shutdown => start_shutdown(bot, user, Some((chat, message)), config, host).await?,
+                // Такие запросы отброшены выше: у них нет имени действия
+                access::Permission::View | access::Permission::Manage => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+// /totp: состояние, включение (секрет и подтверждение первым кодом) и выключение
+async fn handle_totp_command(bot: &Bot, msg: &Message, config: &Config, args: &str) -> Result<()> {
+    let Some(user) = msg.from() else {
+        return Ok(());
+    };
+    let user_id = user.id.0;
+    let Some(store) = store::get() else {
+        bot.send_message(msg.chat.id, "⚠️ База недоступна, настроить TOTP нельзя.").await?;
+        return Ok(());
+    };
+    let enrolled = totp::secret(&store, user_id as i64)?.is_some();
+
+    match args.trim().to_lowercase().as_str() {
+        "on" if enrolled => {
+            bot.send_message(msg.chat.id, "🔐 Двухфакторная проверка уже включена.").await?;
+        }
+        "on" => {
+            let secret = totp::new_secret()?;
+            let account = user.username.clone().unwrap_or_else(|| user_id.to_string());
+            let uri = totp::otpauth_uri(&config.totp.issuer, &account, &secret);
+            let text = format!(
+                "🔐 Добавьте секрет в приложение-аутентификатор (Google Authenticator, Aegis и т.п.):\n\n\
+                 <code>{}</code>\n\nили ссылкой:\n<code>{}</code>\n\n\
+                 Затем отправьте сюда 6-значный код из приложения. Жду код {}.",
+                totp::encode_secret(&secret),
+                escape_html(&uri),
+                tunnel::format_duration(totp::CODE_TIMEOUT)
+            );
+            totp::set_pending(&store, user_id, totp::Pending::Enroll { secret }, store::now())?;
+            bot.send_message(msg.chat.id, text).parse_mode(ParseMode::Html).await?;
+        }
+        "off" if enrolled => {
+            totp::set_pending(&store, user_id, totp::Pending::Disable, store::now())?;
+            bot.send_message(msg.chat.id, "🔐 Отправьте текущий код из приложения, чтобы выключить проверку.")
+                .await?;
+        }
+        "off" => {
+            bot.send_message(msg.chat.id, "🔐 Двухфакторная проверка и так выключена.").await?;
+        }
+        _ => {
+            let actions = config
+                .totp
+                .actions
+                .iter()
+                .map(|permission| match permission {
+                    access::Permission::Wake => "включение",
+                    _ => "выключение",
+                })
+                .collect::<Vec<_>>();
+            let scope = if actions.is_empty() {
+                "Код сейчас не требуется ни для одного действия.".to_string()
+            } else {
+                format!("Код спрашивается перед действиями: {}.", actions.join(", "))
+            };
+            let state = if enrolled { "включена" } else { "выключена" };
+            bot.send_message(
+                msg.chat.id,
+                format!(
+                    "🔐 Двухфакторная проверка {}.\n{}\n\n/totp on — включить\n/totp off — выключить",
+                    state, scope
+                ),
+            )
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+async fn ask_shutdown_confirm(bot: &Bot, q: &CallbackQuery, host: &HostConfig) -> Result<()> {
+    let user_id = q.from.id.0;
+    println!("🔴 Shutdown Confirm Handler: Начало обработки для пользователя {}, хост '{}'", user_id, host.id);
+    log::info!("Запрос подтверждения выключения хоста '{}' от пользователя {}", host.id, user_id);
+    
+    // Проверяем дебаунсинг
+    if !check_button_debounce(user_id) {
+        log::warn!("Пользователь {} нажимает кнопки слишком быстро", user_id);
+        safe_answer_callback_query(bot, &q.id).await?;
+        return Ok(());
+    }
+    
+    safe_answer_callback_query(bot, &q.id).await?;
+    
+    if let Some(msg) = &q.message {
+        let kb = InlineKeyboardMarkup::new(vec![vec![
+            InlineKeyboardButton::callback("✅ Да, выключить", format!("shutdown_yes:{}", host.id)),
+            InlineKeyboardButton::callback("❌ Отмена", format!("cancel:{}", host.id)),
+        ]]);
+        bot.edit_message_text(
+            msg.chat.id,
+            msg.id,
+            match host.approval {
+                Some(policy) => format!(
+                    "⚠️ Подтверждение\n\nВы уверены, что хотите выключить {}?\n\n🛡 Потребуется подтверждение других админов: {}.",
+                    host.name, policy.quorum
+                ),
+                None => format!("⚠️ Подтверждение\n\nВы уверены, что хотите выключить {}?", host.name),
+            },
+        )
+        .reply_markup(kb)
+        .await?;
+    }
+    Ok(())
+}
+
+async fn handle_shutdown(bot: &Bot, q: &CallbackQuery, config: &Config, host: &HostConfig) -> Result<()> {
+    let user_id = q.from.id.0;
+    log::info!("Обрабатываем запрос выключения хоста '{}' от пользователя {}", host.id, user_id);
+    
+    // Проверяем дебаунсинг
+    if !check_button_debounce(user_id) {
+        log::warn!("Пользователь {} нажимает кнопки слишком быстро", user_id);
+        safe_answer_callback_query(bot, &q.id).await?;
+        return Ok(());
+    }
+    
+    safe_answer_callback_query(bot, &q.id).await?;
+    
+    if totp_required(config, user_id, access::Permission::Shutdown) {
+        return ask_totp_code(bot, q, config, host, access::Permission::Shutdown).await;
+    }
+    start_shutdown(bot, &q.from, q.message.as_ref().map(|msg| (msg.chat.id, msg.id)), config, host).await
+}
+
+// Выключение подтверждено запросившим (и кодом TOTP): на хостах с approval сначала ждём других админов
+async fn start_shutdown(
+    bot: &Bot,
+    user: &User,
+    message: Option<(ChatId, MessageId)>,
+    config: &Config,
+    host: &HostConfig,
+) -> Result<()> {
+    match host.approval {
+        Some(policy) => request_approval(bot, user, message, config, host, policy).await,
+        None => run_shutdown(bot, user, message, config, host).await,
+    }
+}
+
+fn approval_cancel_keyboard(id: u64) -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new(vec![vec![InlineKeyboardButton::callback(
+        "❌ Отменить запрос",
+        format!("approval_cancel:{}", id),
+    )]])
+}
+
+// Рассылает запрос на выключение другим админам и показывает запросившему ход подтверждения
+async fn request_approval(
+    bot: &Bot,
+    user: &User,
+    message: Option<(ChatId, MessageId)>,
+    config: &Config,
+    host: &HostConfig,
+    policy: approval::ApprovalPolicy,
+) -> Result<()> {
+    let requester = user.id.0 as i64;
+    let approvers = admin_ids(config)
+        .into_iter()
+        .filter(|id| *id != requester && access::allows_on(config, *id as u64, host, access::Permission::Shutdown))
+        .collect::<Vec<_>>();
+    if (approvers.len() as u32) < policy.quorum {
+        log::warn!(
+            "Выключение '{}' требует {} подтверждений, а подтвердить могут {}",
+            host.id,
+            policy.quorum,
+            approvers.len()
+        );
+        let details = format!("некому подтвердить: нужно {}, админов {}", policy.quorum, approvers.len());
+        audit::record(user, "shutdown", Some(host), audit::Outcome::Failed, &details);
+        if let Some((chat_id, message_id)) = message {
+            bot.edit_message_text(
+                chat_id,
+                message_id,
+                format!(
+                    "⚠️ Выключение {} требует подтверждения других админов ({}), а подтвердить могут только {}.",
+                    host.name,
+                    policy.quorum,
+                    approvers.len()
+                ),
+            )
+            .reply_markup(main_keyboard(config, host, user.id.0))
+            .await?;
+        }
+        return Ok(());
+    }
+
+    // Запрос живёт в базе: голоса и отмена должны пережить перезапуск бота
+    let Some(store) = store::get() else {
+        audit::record(user, "shutdown", Some(host), audit::Outcome::Failed, "база недоступна");
+        if let Some((chat_id, message_id)) = message {
+            bot.edit_message_text(chat_id, message_id, "⚠️ База недоступна, запросить подтверждение нельзя.")
+                .reply_markup(main_keyboard(config, host, user.id.0))
+                .await?;
+        }
+        return Ok(());
+    };
+    let now = store::now();
+    let request = approval::Approval::new("shutdown", &host.id, user.clone(), message, approvers.clone(), policy, now);
+    let progress = request.progress(&format!("Выключение {}", host.name), now);
+    let id = approval::open(&store, &request)?;
+    log::info!("Пользователь {} запросил выключение '{}', запрос подтверждения {}", requester, host.id, id);
+    audit::record(
+        user,
+        "approval_request",
+        Some(host),
+        audit::Outcome::Ok,
+        &format!("shutdown, нужно подтверждений {}", policy.quorum),
+    );
+
+    if let Some((chat_id, message_id)) = message {
+        bot.edit_message_text(chat_id, message_id, progress).reply_markup(approval_cancel_keyboard(id)).await?;
+    }
+    let text = format!(
+        "🛡 {} просит выключить {}.\n\nНужно подтверждений: {}, время на решение: {}.",
+        audit::user_name(user),
+        host.name,
+        policy.quorum,
+        tunnel::format_duration(policy.window)
+    );
+    let keyboard = InlineKeyboardMarkup::new(vec![vec![
+        InlineKeyboardButton::callback("✅ Подтвердить", format!("approval_ok:{}", id)),
+        InlineKeyboardButton::callback("❌ Отклонить", format!("approval_no:{}", id)),
+    ]]);
+    for admin in approvers {
+        match bot.send_message(ChatId(admin), &text).reply_markup(keyboard.clone()).await {
+            Ok(sent) => {
+                if let Err(e) = approval::add_notice(&store, id, (sent.chat.id, sent.id)) {
+                    log::error!("Не удалось сохранить запрос подтверждения {}: {:#}", id, e);
+                }
+            }
+            Err(e) => log::warn!("Не удалось отправить запрос подтверждения админу {}: {}", admin, e),
+        }
+    }
+
+    tokio::spawn(watch_approval(bot.clone(), config.clone(), host.clone(), id));
+    Ok(())
+}
+
+// Обновляет у запросившего оставшееся время и отменяет запрос, когда окно истекло
+async fn watch_approval(bot: Bot, config: Config, host: HostConfig, id: u64) {
+    let Some(store) = store::get() else {
+        return;
+    };
+    loop {
+        let request = match approval::get(&store, id) {
+            Ok(Some(request)) => request,
+            Ok(None) => return,
+            Err(e) => {
+                log::error!("Не удалось прочитать запрос подтверждения {}: {:#}", id, e);
+                tokio::time::sleep(APPROVAL_REFRESH).await;
+                continue;
+            }
+        };
+        let now = store::now();
+        if now >= request.deadline {
+            let Ok(Some(request)) = approval::take(&store, id) else {
+                return;
+            };
+            let details = format!("не набрано подтверждений: {} из {}", request.approvals.len(), request.policy.quorum);
+            log::info!("Запрос подтверждения {} истёк: {}", id, details);
+            audit::record(&request.requester, "shutdown", Some(&host), audit::Outcome::Denied, &details);
+            let text = format!(
+                "⌛ Время на подтверждение выключения {} истекло ({} из {}).",
+                host.name,
+                request.approvals.len(),
+                request.policy.quorum
+            );
+            finish_approval(&bot, &config, &host, &request, &text).await;
+            return;
+        }
+        if let Some((chat_id, message_id)) = request.message {
+            let progress = request.progress(&format!("Выключение {}", host.name), now);
+            if let Err(e) = bot.edit_message_text(chat_id, message_id, progress).reply_markup(approval_cancel_keyboard(id)).await {
+                log::debug!("Не удалось обновить ход подтверждения {}: {}", id, e);
+            }
+        }
+        tokio::time::sleep(APPROVAL_REFRESH.min(request.remaining(now))).await;
+    }
+}
+
+// Запросы подтверждения, которые ждали решения при остановке бота: окно для них уже не
+// отсчитывается, поэтому при запуске они истекают, а в журнал попадает отказ
+async fn expire_stale_approvals(bot: Bot, config: Arc<Config>) {
+    let Some(store) = store::get() else {
+        return;
+    };
+    let stale = match approval::take_all(&store) {
+        Ok(stale) => stale,
+        Err(e) => {
+            log::error!("Не удалось прочитать запросы подтверждения: {:#}", e);
+            return;
+        }
+    };
+    for (id, request) in stale {
+        log::info!("Запрос подтверждения {} прерван перезапуском бота", id);
+        let host = config.host(&request.host);
+        let details = format!(
+            "запрос подтверждения прерван перезапуском бота: {} из {}",
+            request.approvals.len(),
+            request.policy.quorum
+        );
+        audit::record(&request.requester, &request.action, host, audit::Outcome::Denied, &details);
+        let name = host.map_or(request.host.as_str(), |host| host.name.as_str());
+        let text = format!("⌛ Запрос на выключение {} истёк: бот перезапускался. Запросите заново.", name);
+        match host {
+            Some(host) => finish_approval(&bot, &config, host, &request, &text).await,
+            None => close_approval_notices(&bot, &request, &text).await,
+        }
+    }
+}
+
+// Итог запроса: у админов убираем кнопки, запросившему возвращаем меню хоста
+async fn finish_approval(bot: &Bot, config: &Config, host: &HostConfig, request: &approval::Approval, text: &str) {
+    close_approval_notices(bot, request, text).await;
+    if let Some((chat_id, message_id)) = request.message {
+        if let Err(e) = bot
+            .edit_message_text(chat_id, message_id, text)
+            .reply_markup(main_keyboard(config, host, request.requester.id.0))
+            .await
+        {
+            log::warn!("Не удалось сообщить итог подтверждения пользователю {}: {}", request.requester.id.0, e);
+        }
+    }
+}
+
+async fn close_approval_notices(bot: &Bot, request: &approval::Approval, text: &str) {
+    for (chat_id, message_id) in &request.notices {
+        if let Err(e) = bot.edit_message_text(*chat_id, *message_id, text).await {
+            log::debug!("Не удалось обновить запрос подтверждения у админа: {}", e);
+        }
+    }
+}
+
+// Голос админа по запросу подтверждения
+async fn handle_approval_vote(bot: &Bot, q: &CallbackQuery, config: &Config, args: &str, approve: bool) -> Result<()> {
+    let action = if approve { "approval_approve" } else { "approval_reject" };
+    let name = audit::user_name(&q.from);
+    let vote = match (store::get(), args.parse::<u64>()) {
+        (Some(store), Ok(id)) => approval::vote(&store, id, q.from.id.0 as i64, &name, approve, store::now())?
+            .map(|vote| (id, vote)),
+        _ => None,
+    };
+    let Some((id, (request, result))) = vote else {
+        safe_answer_callback_query(bot, &q.id).await?;
+        if let Some(msg) = &q.message {
+            bot.edit_message_text(msg.chat.id, msg.id, "⚠️ Запрос уже решён или истёк.").await?;
+        }
+        return Ok(());
+    };
+    let host = config.host(&request.host);
+    let status = match result {
+        Ok(status) => status,
+        Err(reason) => {
+            audit::record(&q.from, action, host, audit::Outcome::Denied, &reason);
+            bot.answer_callback_query(&q.id).text(format!("⛔ {}", reason)).show_alert(true).await?;
+            return Ok(());
+        }
+    };
+    safe_answer_callback_query(bot, &q.id).await?;
+    let Some(host) = host else {
+        return Ok(());
+    };
+    let requester = audit::user_name(&request.requester);
+    if status != approval::Status::Expired {
+        audit::record(&q.from, action, Some(host), audit::Outcome::Ok, &format!("{} от {}", request.action, requester));
+    }
+    log::info!("Голос {} по выключению '{}' от {}: {:?}", name, host.id, requester, status);
+
+    match status {
+        approval::Status::Waiting => {
+            let left = request.policy.quorum as usize - request.approvals.len();
+            if let Some(msg) = &q.message {
+                bot.edit_message_text(
+                    msg.chat.id,
+                    msg.id,
+                    format!("✅ Вы подтвердили выключение {}. Ждём ещё подтверждений: {}.", host.name, left),
+                )
+                .await?;
+            }
+            if let Some((chat_id, message_id)) = request.message {
+                let progress = request.progress(&format!("Выключение {}", host.name), store::now());
+                bot.edit_message_text(chat_id, message_id, progress).reply_markup(approval_cancel_keyboard(id)).await?;
+            }
+        }
+        approval::Status::Approved => {
+            // Права запросившего проверяем ещё раз: пока шло голосование, доступ могли отозвать
+            if !access::allows_on(config, request.requester.id.0, host, access::Permission::Shutdown) {
+                let details = "у запросившего больше нет права на выключение";
+                audit::record(&request.requester, "shutdown", Some(host), audit::Outcome::Denied, details);
+                let text = format!("⛔ Выключение {} подтверждено, но {} больше не может его выполнить.", host.name, requester);
+                finish_approval(bot, config, host, &request, &text).await;
+                return Ok(());
+            }
+            let text = format!("✅ Выключение {} подтверждено: {}.", host.name, request.approver_names());
+            close_approval_notices(bot, &request, &text).await;
+            run_shutdown(bot, &request.requester, request.message, config, host).await?;
+        }
+        approval::Status::Rejected { by } => {
+            audit::record(&request.requester, "shutdown", Some(host), audit::Outcome::Denied, &format!("отклонил {}", by));
+            let text = format!("❌ Выключение {} отклонил {}.", host.name, by);
+            finish_approval(bot, config, host, &request, &text).await;
+        }
+        approval::Status::Expired => {
+            let details = format!("не набрано подтверждений: {} из {}", request.approvals.len(), request.policy.quorum);
+            audit::record(&request.requester, "shutdown", Some(host), audit::Outcome::Denied, &details);
+            let text = format!("⌛ Время на подтверждение выключения {} истекло.", host.name);
+            finish_approval(bot, config, host, &request, &text).await;
+        }
+    }
+    Ok(())
+}
+
+// Запросивший передумал: запрос снимается у всех админов
+async fn handle_approval_cancel(bot: &Bot, q: &CallbackQuery, config: &Config, args: &str) -> Result<()> {
+    let (Some(store), Ok(id)) = (store::get(), args.parse::<u64>()) else {
+        safe_answer_callback_query(bot, &q.id).await?;
+        return Ok(());
+    };
+    let Some(request) = approval::get(&store, id)? else {
+        safe_answer_callback_query(bot, &q.id).await?;
+        return Ok(());
+    };
+    if request.requester.id != q.from.id {
+        bot.answer_callback_query(&q.id).text("⛔ Отменить запрос может только тот, кто его отправил").show_alert(true).await?;
+        return Ok(());
+    }
+    safe_answer_callback_query(bot, &q.id).await?;
+    let (Some(request), Some(host)) = (approval::take(&store, id)?, config.host(&request.host)) else {
+        return Ok(());
+    };
+    audit::record(&q.from, "approval_cancel", Some(host), audit::Outcome::Ok, &request.action);
+    let text = format!("🚫 {} отменил запрос на выключение {}.", audit::user_name(&q.from), host.name);
+    finish_approval(bot, config, host, &request, &text).await;
+    Ok(())
+}
+
+// Выключение после всех проверок: сразу по кнопке или после ввода кода TOTP
+async fn run_shutdown(
+    bot: &Bot,
+    user: &User,
+    message: Option<(ChatId, MessageId)>,
+    config: &Config,
+    host: &HostConfig,
+) -> Result<()> {
+    if let Some((chat_id, message_id)) = message {
+        bot.edit_message_text(chat_id, message_id, format!("⏳ Отправляю команду на выключение {}...", host.name))
+            .await?;
+    }
+
+    match send_shutdown(config, host).await {
+        Ok(_) => {
+            audit::record(user, "shutdown", Some(host), audit::Outcome::Ok, "");
+            if let Some((chat_id, message_id)) = message {
+                bot.edit_message_text(
+                    chat_id,
+                    message_id,
+                    format!("🔴 Команда выключения отправлена на {}!", host.name)
+                )
+                .reply_markup(main_keyboard(config, host, user.id.0))
+                .await?;
+            }
+        }
+        Err(e) => {
+            log::error!("Ошибка выключения хоста '{}': {:#}", host.id, e);
+            audit::record(user, "shutdown", Some(host), audit::Outcome::Failed, &describe_error(&e).0);
+            if let Some((chat_id, message_id)) = message {
+                let (summary, details_id) = record_error(host, &e);
+                bot.edit_message_text(
+                    chat_id,
+                    message_id,
+                    format!("❌ Не удалось выполнить команду выключения.\n\n{}", summary)
+                )
+                .reply_markup(failure_keyboard(config, host, user.id.0, details_id))
+                .await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn send_shutdown(config: &Config, host: &HostConfig) -> Result<()> {
+    log::info!("Выполняем команду выключения");
+    // -n: если sudo требует пароль, команда завершится ошибкой вместо ожидания ввода
+    let command = "sudo -n /sbin/shutdown -h now";
+    match ssh::exec(config, &host.ssh, command, command).await {
+        Ok(_) => Ok(()),
+        // Выключаясь, сервер может оборвать сессию раньше, чем пришлёт код завершения
+        Err(e) if e.is::<ssh::NoExitStatus>() => {
+            log::info!("Хост '{}' закрыл сессию без кода завершения — выключение началось", host.id);
+            Ok(())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+async fn handle_status(bot: &Bot, q: &CallbackQuery, config: &Config, host: &HostConfig) -> Result<()> {
+    let user_id = q.from.id.0;
+    println!("🟢 Status Handler: Начало обработки для пользователя {}, хост '{}'", user_id, host.id);
+    log::info!("Проверяем статус хоста '{}' по запросу пользователя {}", host.id, user_id);
+    
+    // Проверяем дебаунсинг
+    if !check_button_debounce(user_id) {
+        log::warn!("Пользователь {} нажимает кнопки слишком быстро", user_id);
+        safe_answer_callback_query(bot, &q.id).await?;
+        return Ok(());
+    }
+    
+    safe_answer_callback_query(bot, &q.id).await?;
+    
+    if let Some(msg) = &q.message {
+        bot.edit_message_text(msg.chat.id, msg.id, format!("⏳ Проверяю статус {}...", host.name))
+            .await?;
+    }
+
+    // Каждый этап проверки ограничен своим таймаутом, так что общий не нужен
+    match check_status(config.clone(), host.clone()).await {
+        Ok(info) => {
+            if let Some(msg) = &q.message {
+                bot.edit_message_text(msg.chat.id, msg.id, info)
+                    .parse_mode(ParseMode::Html)
+                    .reply_markup(status_keyboard(config, host, q.from.id.0))
+                    .await?;
+            }
+        }
+        Err(e) => {
+            log::error!("Ошибка проверки статуса хоста '{}': {}", host.id, e);
+            if let Some(msg) = &q.message {
+                bot.edit_message_text(
+                    msg.chat.id, 
+                    msg.id, 
+                    "❌ Не удалось проверить статус сервера.\nПроверьте настройки SSH."
+                )
+                .reply_markup(main_keyboard(config, host, q.from.id.0))
+                .await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Результат проверки доступности хоста
+#[derive(Clone, Debug, PartialEq)]
+enum HostState {
+    // SSH-порт не принимает соединения или за ним не отвечает SSH
+    Offline,
+    // Порт открыт, но выполнить команду по SSH не удалось
+    PortOpen { reason: String },
+    // SSH работает, отчёт о состоянии получен
+    Online { report: report::StatusReport },
+    // Порт открыт, но ключ хоста не прошёл проверку
+    Untrusted { reason: String },
+}
+
+async fn check_status(config: Config, host: HostConfig) -> Result<String> {
+    let name = escape_html(&host.name);
+    let tunnels = escape_html(&tunnels_text(&config));
+    let probe = probe_host(config, host, true).await;
+    let header = match &probe.state {
+        HostState::Online { report } => {
+            return Ok(format!("🟢 {} онлайн\n\n<pre>{}</pre>{}", name, escape_html(&report::render(report)), tunnels))
+        }
+        HostState::PortOpen { .. } => format!("🟡 {} онлайн, но SSH не работает", name),
+        HostState::Offline => format!("🔴 {} оффлайн", name),
+        HostState::Untrusted { .. } => format!("⚠️ {} отвечает, но подключение заблокировано", name),
+    };
+
+    let steps = probe
+        .steps
+        .iter()
+        .map(|step| match &step.outcome {
+            Ok(detail) => format!("✅ {}: {}", step.title, detail),
+            Err(reason) => format!("❌ {}: {}", step.title, reason),
+        })
+        .map(|line| escape_html(&line))
+        .collect::<Vec<_>>();
+    let verdict = probe_verdict(&probe).map(|v| format!("\n\n➡️ {}", escape_html(&v))).unwrap_or_default();
+    Ok(format!("{}\n\n{}{}{}", header, steps.join("\n"), verdict, tunnels))
+}
+
+// Раздел статуса о туннелях бота; пустой, если туннели не настроены
+fn tunnels_text(config: &Config) -> String {
+    if config.tunnels.is_empty() {
+        return String::new();
+    }
+    let lines = config
+        .tunnels
+        .iter()
+        .map(|t| tunnel::describe(&t.name, tunnel::state(&t.name).as_ref()))
+        .collect::<Vec<_>>();
+    format!("\n\n🔌 Туннели:\n{}", lines.join("\n"))
+}
+
+// Итог послойной проверки хоста с пройденными этапами
+#[derive(Clone, Debug, PartialEq)]
+struct Probe {
+    state: HostState,
+    // Пройденные этапы по порядку: порт, баннер SSH, роутер, вход по SSH
+    steps: Vec<ProbeStep>,
+    // Видит ли роутер сервер в LAN: Ok(true/false), Err — сам роутер недоступен
+    lan: Option<Result<bool, String>>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct ProbeStep {
+    title: String,
+    outcome: Result<String, String>,
+}
+
+// Какое звено отказало, если это можно понять по этапам
+fn probe_verdict(probe: &Probe) -> Option<String> {
+    let failed = probe.steps.iter().position(|step| step.outcome.is_err())?;
+    let port_failed = failed == 0;
+    let banner_failed = failed == 1 && matches!(probe.state, HostState::Offline);
+
+    match (&probe.lan, port_failed, banner_failed) {
+        (Some(Err(_)), _, _) => Some("Роутер недоступен, проверить сервер из LAN не удалось.".to_string()),
+        (Some(Ok(true)), true, _) => Some("Сервер в LAN отвечает — не работает туннель до него.".to_string()),
+        (Some(Ok(true)), _, true) => {
+            Some("Порт туннеля открыт, но дальний конец не отвечает, а сервер в LAN отвечает — туннель оборван.".to_string())
+        }
+        (Some(Ok(false)), true, _) | (Some(Ok(false)), _, true) => {
+            Some("Сервер не отвечает и из LAN — похоже, он выключен.".to_string())
+        }
+        (None, _, true) => Some("Порт открыт, но SSH за ним не отвечает — вероятно, оборван туннель.".to_string()),
+        _ => None,
+    }
+}
+
+// Роутер пингует LAN-адрес сервера, а если ping запрещён — ищет его в ARP-таблице
+async fn router_reaches(config: &Config, router: &SshTarget, ip: IpAddr) -> Result<bool, String> {
+    let command = format!(
+        "ping -c 1 -W 2 {ip} >/dev/null 2>&1 && echo ping || \
+         (awk '$1 == \"{ip}\" && $3 == \"0x2\" {{ found = 1 }} END {{ print found ? \"arp\" : \"none\" }}' /proc/net/arp)",
+        ip = ip
+    );
+    match ssh::exec(config, router, &command, &format!("ping {}", ip)).await {
+        Ok(output) => Ok(matches!(output.stdout.trim(), "ping" | "arp")),
+        Err(e) => Err(describe_error(&e).0),
+    }
+}
+
+// Послойная проверка: порт туннеля, баннер SSH, вход по SSH; при сбое с with_lan —
+// ещё и видит ли роутер сервер в LAN, чтобы отличить оборванный туннель от выключенного сервера
+async fn probe_host(config: Config, host: HostConfig, with_lan: bool) -> Probe {
+    log::debug!("Проверяем статус '{}' по адресу {}:{}", host.id, host.ssh.host, host.ssh.port);
+    let port = ssh::probe_port(&config, &host.ssh, config.nc_timeout).await;
+
+    let step = |title: &str, outcome: Result<String, String>| ProbeStep { title: title.to_string(), outcome };
+    let port_title = format!("Порт {}:{}", host.ssh.host, host.ssh.port);
+    let mut steps = Vec::new();
+    let state = match port {
+        ssh::PortProbe::Closed(reason) => {
+            log::debug!("Хост '{}' недоступен: {}", host.id, reason);
+            steps.push(step(&port_title, Err(reason)));
+            HostState::Offline
+        }
+        ssh::PortProbe::Silent(reason) => {
+            steps.push(step(&port_title, Ok("открыт".to_string())));
+            steps.push(step("SSH-баннер", Err(reason)));
+            HostState::Offline
+        }
+        ssh::PortProbe::Banner(banner) => {
+            steps.push(step(&port_title, Ok("открыт".to_string())));
+            steps.push(step("SSH-баннер", Ok(banner)));
+            let command = report::command(&host.mounts);
+            let report = ssh::exec(&config, &host.ssh, &command, "отчёт о состоянии")
+                .await
+                .and_then(|output| report::collect(&output.stdout));
+            match report {
+                Ok(report) => {
+                    steps.push(step("Вход по SSH", Ok("выполнен".to_string())));
+                    HostState::Online { report }
+                }
+                Err(e) => {
+                    let reason = describe_error(&e).0;
+                    steps.push(step("Вход по SSH", Err(reason.clone())));
+                    match e.downcast_ref::<ssh::HostKeyError>() {
+                        Some(key_error) => HostState::Untrusted { reason: key_error.to_string() },
+                        None => {
+                            log::warn!("Не удалось получить отчёт о состоянии: {:#}", e);
+                            HostState::PortOpen { reason }
+                        }
+                    }
+                }
+            }
+        }
+    };
+
+    let mut lan = None;
+    if with_lan && !matches!(state, HostState::Online { .. }) {
+        if let (Some(router), Some(ip)) = (&host.router, host.lan_ip) {
+            let reaches = router_reaches(&config, router, ip).await;
+            let outcome = match &reaches {
+                Ok(true) => Ok("отвечает".to_string()),
+                Ok(false) => Err("не отвечает на ping и нет в ARP-таблице".to_string()),
+                Err(reason) => Err(format!("роутер недоступен: {}", reason)),
+            };
+            // Этап роутера стоит перед входом по SSH, как и звено в цепочке
+            let at = steps.iter().position(|s| s.title == "Вход по SSH").unwrap_or(steps.len());
+            steps.insert(at, step(&format!("Роутер видит {}", ip), outcome));
+            lan = Some(reaches);
+        }
+    }
+
+    Probe { state, steps, lan }
+}
+
+async fn cancel(bot: &Bot, q: &CallbackQuery, config: &Config, host: &HostConfig) -> Result<()> {
+    log::info!("Отмена операции пользователем {}", q.from.id.0);
+    if let Some(store) = store::get() {
+        if let Err(e) = totp::cancel_pending(&store, q.from.id.0) {
+            log::error!("Не удалось отменить ожидание кода TOTP: {:#}", e);
+        }
+    }
     
     safe_answer_callback_query(bot, &q.id).await?;
     
     if let Some(msg) = &q.message {
         bot.edit_message_text(msg.chat.id, msg.id, "❌ Операция отменена")
-            .reply_markup(main_keyboard())
+            .reply_markup(main_keyboard(config, host, q.from.id.0))
             .await?;
     }
     Ok(())
 }
 // Note: Handler functions are now directly integrated into the dispatcher
-// These were the original wrapper functions that are no longer needed 
\ No newline at end of file
+// These were the original wrapper functions that are no longer needed
@@ -0,0 +1,282 @@
+// Фоновый опрос хостов: оповещает подписчиков, когда хост появляется в сети или пропадает
+
+use std::{
+    collections::HashSet,
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::mpsc;
+
+use crate::{store, tunnel, Config, HostConfig, HostState, Probe};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MonitorConfig {
+    // Период опроса по умолчанию; у хоста его можно переопределить. Ноль (по умолчанию) —
+    // опрос выключен, пока его не включат здесь или в monitor_interval хоста
+    pub interval: Duration,
+    // Сколько опросов подряд должны показать новое состояние, прежде чем о нём сообщить
+    pub confirmations: u32,
+}
+
+impl Default for MonitorConfig {
+    fn default() -> Self {
+        MonitorConfig { interval: Duration::ZERO, confirmations: 2 }
+    }
+}
+
+// Состояние хоста с точки зрения оповещений
+#[derive(Clone, Debug, PartialEq)]
+pub enum Reachability {
+    Online,
+    Offline,
+    // Хост в сети (порт открыт или его видит роутер), но войти по SSH нельзя
+    NoSsh { reason: String },
+}
+
+impl Reachability {
+    pub fn from_probe(probe: &Probe) -> Self {
+        match &probe.state {
+            HostState::Online { .. } => Reachability::Online,
+            HostState::PortOpen { reason } | HostState::Untrusted { reason } => {
+                Reachability::NoSsh { reason: reason.clone() }
+            }
+            HostState::Offline if matches!(probe.lan, Some(Ok(true))) => Reachability::NoSsh {
+                reason: "сервер виден в LAN, но SSH-порт не отвечает".to_string(),
+            },
+            HostState::Offline => Reachability::Offline,
+        }
+    }
+
+    // Имя состояния в истории хоста (store)
+    pub fn name(&self) -> &'static str {
+        match self {
+            Reachability::Online => "online",
+            Reachability::Offline => "offline",
+            Reachability::NoSsh { .. } => "no_ssh",
+        }
+    }
+
+    pub fn from_record(record: &store::HostStateRecord) -> Option<Self> {
+        match record.state.as_str() {
+            "online" => Some(Reachability::Online),
+            "offline" => Some(Reachability::Offline),
+            "no_ssh" => Some(Reachability::NoSsh { reason: record.details.clone() }),
+            _ => None,
+        }
+    }
+
+    fn details(&self) -> &str {
+        match self {
+            Reachability::NoSsh { reason } => reason,
+            _ => "",
+        }
+    }
+
+    // Причина отказа SSH не меняет состояние: иначе разные тексты ошибок считались бы переходами
+    fn same_kind(&self, other: &Reachability) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+    }
+}
+
+// Подавляет дребезг: новое состояние принимается после нескольких одинаковых опросов подряд
+#[derive(Debug)]
+pub struct Debouncer {
+    confirmations: u32,
+    confirmed: Option<(Reachability, Instant)>,
+    candidate: Option<(Reachability, u32)>,
+}
+
+impl Debouncer {
+    pub fn new(confirmations: u32) -> Self {
+        Debouncer { confirmations: confirmations.max(1), confirmed: None, candidate: None }
+    }
+
+    // Состояние, известное до перезапуска бота: с ним сверяется первый же опрос
+    pub fn restore(&mut self, state: Reachability, age: Duration) {
+        let since = Instant::now().checked_sub(age).unwrap_or_else(Instant::now);
+        self.confirmed = Some((state, since));
+    }
+
+    // Возвращает подтверждённый переход: прежнее состояние и сколько оно длилось.
+    // Первый опрос только запоминает исходное состояние — о нём не сообщаем.
+    pub fn observe(&mut self, seen: Reachability) -> Option<(Reachability, Duration)> {
+        let Some((confirmed, since)) = &self.confirmed else {
+            self.confirmed = Some((seen, Instant::now()));
+            return None;
+        };
+        if confirmed.same_kind(&seen) {
+            self.candidate = None;
+            return None;
+        }
+
+        let count = match &self.candidate {
+            Some((candidate, count)) if candidate.same_kind(&seen) => count + 1,
+            _ => 1,
+        };
+        if count < self.confirmations {
+            self.candidate = Some((seen, count));
+            return None;
+        }
+
+        let previous = (confirmed.clone(), since.elapsed());
+        self.confirmed = Some((seen, Instant::now()));
+        self.candidate = None;
+        Some(previous)
+    }
+}
+
+// Подтверждённая смена состояния хоста
+#[derive(Clone, Debug)]
+pub struct MonitorEvent {
+    pub host_id: String,
+    pub host: String,
+    pub state: Reachability,
+    pub previous: Reachability,
+    pub lasted: Duration,
+}
+
+impl fmt::Display for MonitorEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let lasted = tunnel::format_duration(self.lasted);
+        match (&self.state, &self.previous) {
+            (Reachability::Online, Reachability::Offline) => {
+                write!(f, "🟢 {} снова онлайн (был недоступен {})", self.host, lasted)
+            }
+            (Reachability::Online, _) => write!(f, "🟢 {} снова доступен по SSH (после {})", self.host, lasted),
+            (Reachability::Offline, _) => write!(f, "🔴 {} перестал отвечать (был в сети {})", self.host, lasted),
+            (Reachability::NoSsh { reason }, _) => {
+                write!(f, "🟡 {} в сети, но SSH недоступен: {}", self.host, reason)
+            }
+        }
+    }
+}
+
+// Настройка пользователя в базе: "1" — подписан, "0" — отписался
+const NOTIFY_PREF: &str = "notify";
+
+lazy_static::lazy_static! {
+    static ref SUBSCRIBERS: Mutex<HashSet<i64>> = Mutex::new(HashSet::new());
+    static ref EVENTS: (
+        mpsc::UnboundedSender<MonitorEvent>,
+        Mutex<Option<mpsc::UnboundedReceiver<MonitorEvent>>>,
+    ) = {
+        let (tx, rx) = mpsc::unbounded_channel();
+        (tx, Mutex::new(Some(rx)))
+    };
+}
+
+// Получатель событий мониторинга; забрать его можно один раз
+pub fn take_events() -> Option<mpsc::UnboundedReceiver<MonitorEvent>> {
+    EVENTS.1.lock().unwrap().take()
+}
+
+// Подписка на оповещения; возвращает false, если состояние не изменилось.
+// Выбор пользователя сохраняется в базе и перекрывает notify из конфига.
+pub fn subscribe(user_id: i64) -> bool {
+    save_subscription(user_id, true);
+    SUBSCRIBERS.lock().unwrap().insert(user_id)
+}
+
+pub fn unsubscribe(user_id: i64) -> bool {
+    save_subscription(user_id, false);
+    SUBSCRIBERS.lock().unwrap().remove(&user_id)
+}
+
+fn save_subscription(user_id: i64, on: bool) {
+    if let Some(store) = store::get() {
+        if let Err(e) = store.set_pref(user_id, NOTIFY_PREF, if on { "1" } else { "0" }) {
+            log::warn!("Не удалось сохранить подписку пользователя {}: {:#}", user_id, e);
+        }
+    }
+}
+
+pub fn subscribers() -> Vec<i64> {
+    let mut ids = SUBSCRIBERS.lock().unwrap().iter().copied().collect::<Vec<_>>();
+    ids.sort_unstable();
+    ids
+}
+
+// Запускает опрос всех хостов, у которых он не отключён
+pub fn start(config: Arc<Config>) {
+    let mut subscribers = SUBSCRIBERS.lock().unwrap();
+    subscribers.extend(config.subscribers.iter().copied());
+    if let Some(store) = store::get() {
+        match store.prefs(NOTIFY_PREF) {
+            Ok(prefs) => {
+                for (user_id, value) in prefs {
+                    if value == "1" {
+                        subscribers.insert(user_id);
+                    } else {
+                        subscribers.remove(&user_id);
+                    }
+                }
+            }
+            Err(e) => log::warn!("Не удалось прочитать подписки из базы: {:#}", e),
+        }
+    }
+    drop(subscribers);
+
+    let mut watched = 0;
+    for host in config.hosts.clone() {
+        let interval = host.monitor_interval.unwrap_or(config.monitor.interval);
+        if interval.is_zero() {
+            log::info!("Мониторинг хоста '{}' отключён", host.id);
+            continue;
+        }
+        tokio::spawn(watch(config.clone(), host, interval));
+        watched += 1;
+    }
+    if watched == 0 {
+        log::info!("Фоновый опрос выключен: задайте monitor.interval или monitor_interval у хоста");
+    }
+}
+
+async fn watch(config: Arc<Config>, host: HostConfig, interval: Duration) {
+    log::info!("Мониторинг хоста '{}' раз в {:?}", host.id, interval);
+    let mut debouncer = Debouncer::new(config.monitor.confirmations);
+    let store = store::get();
+    let last = store.as_ref().and_then(|store| match store.last_host_state(&host.id) {
+        Ok(last) => last,
+        Err(e) => {
+            log::warn!("Не удалось прочитать историю хоста '{}': {:#}", host.id, e);
+            None
+        }
+    });
+    let mut recorded = false;
+    if let Some((state, record)) = last.as_ref().and_then(|r| Reachability::from_record(r).map(|s| (s, r))) {
+        log::info!("Хост '{}' до перезапуска: {:?}", host.id, state);
+        let age = Duration::from_secs(store::now().saturating_sub(record.at).max(0) as u64);
+        debouncer.restore(state, age);
+        recorded = true;
+    }
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        ticker.tick().await;
+        let probe = crate::probe_host((*config).clone(), host.clone(), true).await;
+        let seen = Reachability::from_probe(&probe);
+        log::debug!("Мониторинг '{}': {:?}", host.id, seen);
+
+        let transition = debouncer.observe(seen.clone());
+        // Исходное состояние тоже пишем в историю, чтобы после перезапуска было с чем сравнить
+        if transition.is_some() || !recorded {
+            if let Some(store) = &store {
+                match store.record_host_state(&host.id, store::now(), seen.name(), seen.details()) {
+                    Ok(()) => recorded = true,
+                    Err(e) => log::warn!("Не удалось записать состояние хоста '{}': {:#}", host.id, e),
+                }
+            }
+        }
+
+        if let Some((previous, lasted)) = transition {
+            log::info!("Хост '{}': {:?} -> {:?}", host.id, previous, seen);
+            let event = MonitorEvent { host_id: host.id.clone(), host: host.name.clone(), state: seen, previous, lasted };
+            if EVENTS.0.send(event).is_err() {
+                log::warn!("Некому доставить событие мониторинга");
+            }
+        }
+    }
+}
@@ -0,0 +1,245 @@
+// Подробный отчёт о состоянии хоста: собирается одной командой и разбирается по разделам
+
+use std::time::Duration;
+
+use anyhow::Result;
+
+use crate::tunnel;
+
+// Точки монтирования в отчёте, если у хоста не задан ключ mounts
+pub const DEFAULT_MOUNTS: &[&str] = &["/"];
+// Разделы вывода отделяются строками вида "@@mem"
+const SECTION_MARK: &str = "@@";
+// Названия чипов lm-sensors, температура которых относится к процессору
+const CPU_CHIPS: &[&str] = &["coretemp", "k10temp", "zenpower", "cpu_thermal", "cpu-thermal", "soc_thermal"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Usage {
+    pub used: u64,
+    pub total: u64,
+}
+
+impl Usage {
+    pub fn percent(&self) -> u64 {
+        (self.used * 100).checked_div(self.total).unwrap_or(0)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Disk {
+    pub mount: String,
+    pub usage: Usage,
+}
+
+// Каждый раздел необязателен: на хосте может не быть sensors, swap и т.п.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StatusReport {
+    pub uptime: Option<Duration>,
+    pub load: Option<[f64; 3]>,
+    pub memory: Option<Usage>,
+    pub swap: Option<Usage>,
+    pub disks: Vec<Disk>,
+    pub temperature: Option<f64>,
+    pub users: Vec<String>,
+}
+
+// Экранирует аргумент для sh одинарными кавычками
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+// Одна команда на все разделы, чтобы отчёт стоил один проход по SSH.
+// Разделы необязательны: сбой любой из команд не должен менять код завершения,
+// поэтому команда всегда завершается успешно, а об успехе судим по разобранным разделам.
+pub fn command(mounts: &[String]) -> String {
+    let mounts = mounts.iter().map(|m| shell_quote(m)).collect::<Vec<_>>().join(" ");
+    [
+        "echo @@uptime; cat /proc/uptime 2>/dev/null",
+        "echo @@load; cat /proc/loadavg 2>/dev/null",
+        "echo @@mem; cat /proc/meminfo 2>/dev/null",
+        &format!("echo @@disk; df -P -k -- {} 2>/dev/null", mounts),
+        "echo @@sensors; sensors -j 2>/dev/null",
+        "echo @@users; who 2>/dev/null",
+        "true",
+    ]
+    .join("; ")
+}
+
+// Делит вывод команды на разделы по меткам
+fn sections(output: &str) -> Vec<(&str, Vec<&str>)> {
+    let mut sections: Vec<(&str, Vec<&str>)> = Vec::new();
+    for line in output.lines() {
+        match line.strip_prefix(SECTION_MARK) {
+            Some(name) => sections.push((name.trim(), Vec::new())),
+            None => {
+                if let Some((_, lines)) = sections.last_mut() {
+                    lines.push(line);
+                }
+            }
+        }
+    }
+    sections
+}
+
+pub fn parse(output: &str) -> StatusReport {
+    let mut report = StatusReport::default();
+    for (name, lines) in sections(output) {
+        match name {
+            "uptime" => report.uptime = parse_uptime(&lines),
+            "load" => report.load = parse_load(&lines),
+            "mem" => (report.memory, report.swap) = parse_meminfo(&lines),
+            "disk" => report.disks = parse_df(&lines),
+            "sensors" => report.temperature = parse_sensors(&lines.join("\n")),
+            "users" => report.users = parse_who(&lines),
+            other => log::debug!("Неизвестный раздел отчёта '{}'", other),
+        }
+    }
+    report
+}
+
+// Отчёт из вывода command(). Ошибка — если в выводе нет ни одного раздела с данными:
+// тогда команда, скорее всего, не выполнилась вовсе.
+pub fn collect(output: &str) -> Result<StatusReport> {
+    let report = parse(output);
+    if report == StatusReport::default() {
+        anyhow::bail!("в выводе нет ни одного раздела отчёта");
+    }
+    Ok(report)
+}
+
+// /proc/uptime: "12345.67 54321.00"
+fn parse_uptime(lines: &[&str]) -> Option<Duration> {
+    let secs = lines.first()?.split_whitespace().next()?.parse::<f64>().ok()?;
+    Some(Duration::from_secs(secs as u64))
+}
+
+// /proc/loadavg: "0.15 0.10 0.05 1/234 5678"
+fn parse_load(lines: &[&str]) -> Option<[f64; 3]> {
+    let mut fields = lines.first()?.split_whitespace().map(|f| f.parse::<f64>().ok());
+    Some([fields.next()??, fields.next()??, fields.next()??])
+}
+
+// /proc/meminfo в килобайтах; занятой считаем память сверх MemAvailable, как free
+fn parse_meminfo(lines: &[&str]) -> (Option<Usage>, Option<Usage>) {
+    let value = |key: &str| {
+        lines.iter().find_map(|line| {
+            let rest = line.strip_prefix(key)?.strip_prefix(':')?;
+            rest.split_whitespace().next()?.parse::<u64>().ok().map(|kb| kb * 1024)
+        })
+    };
+    let memory = match (value("MemTotal"), value("MemAvailable")) {
+        (Some(total), Some(available)) => Some(Usage { used: total.saturating_sub(available), total }),
+        _ => None,
+    };
+    let swap = match (value("SwapTotal"), value("SwapFree")) {
+        (Some(total), Some(free)) => Some(Usage { used: total.saturating_sub(free), total }),
+        _ => None,
+    };
+    (memory, swap)
+}
+
+// df -P -k: Filesystem 1024-blocks Used Available Capacity Mounted-on. Процент, как у df,
+// считается от used + available, без зарезервированных блоков.
+fn parse_df(lines: &[&str]) -> Vec<Disk> {
+    lines
+        .iter()
+        .skip(1)
+        .filter_map(|line| {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            if fields.len() < 6 {
+                return None;
+            }
+            let used = fields[2].parse::<u64>().ok()? * 1024;
+            let available = fields[3].parse::<u64>().ok()? * 1024;
+            Some(Disk { mount: fields[5..].join(" "), usage: Usage { used, total: used + available } })
+        })
+        .collect()
+}
+
+// sensors -j: {"chip": {"feature": {"temp1_input": 42.0, ...}, "Adapter": "..."}, ...}.
+// Берём максимум по чипам процессора, а если их нет — по всем датчикам температуры.
+fn parse_sensors(json: &str) -> Option<f64> {
+    let chips = serde_json::from_str::<serde_json::Value>(json).ok()?;
+    let mut cpu = Vec::new();
+    let mut all = Vec::new();
+    for (chip, features) in chips.as_object()? {
+        let is_cpu = CPU_CHIPS.iter().any(|prefix| chip.starts_with(prefix));
+        for feature in features.as_object().into_iter().flat_map(|f| f.values()) {
+            for (name, value) in feature.as_object().into_iter().flatten() {
+                if let (true, Some(t)) = (name.starts_with("temp") && name.ends_with("_input"), value.as_f64()) {
+                    all.push(t);
+                    if is_cpu {
+                        cpu.push(t);
+                    }
+                }
+            }
+        }
+    }
+    let temps = if cpu.is_empty() { all } else { cpu };
+    temps.into_iter().reduce(f64::max)
+}
+
+// who: "alice pts/0 2024-01-01 10:00 (10.0.0.1)"; одного пользователя показываем один раз
+fn parse_who(lines: &[&str]) -> Vec<String> {
+    let mut users: Vec<String> = Vec::new();
+    for user in lines.iter().filter_map(|line| line.split_whitespace().next()) {
+        if !users.iter().any(|u| u == user) {
+            users.push(user.to_string());
+        }
+    }
+    users
+}
+
+// Байты в человекочитаемом виде: 512 МБ, 15.5 ГБ
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["Б", "КБ", "МБ", "ГБ", "ТБ"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if value < 10.0 && unit > 0 {
+        format!("{:.1} {}", value, UNITS[unit])
+    } else {
+        format!("{:.0} {}", value, UNITS[unit])
+    }
+}
+
+fn format_usage(usage: &Usage) -> String {
+    if usage.total == 0 {
+        return "нет".to_string();
+    }
+    format!("{:>3}%  {} из {}", usage.percent(), format_bytes(usage.used), format_bytes(usage.total))
+}
+
+// Таблица «подпись — значение» с выровненными подписями (для моноширинного блока)
+pub fn render(report: &StatusReport) -> String {
+    let mut rows: Vec<(String, String)> = Vec::new();
+    if let Some(uptime) = report.uptime {
+        rows.push(("Аптайм".to_string(), tunnel::format_duration(uptime)));
+    }
+    if let Some([one, five, fifteen]) = report.load {
+        rows.push(("Нагрузка".to_string(), format!("{:.2} {:.2} {:.2}", one, five, fifteen)));
+    }
+    if let Some(memory) = &report.memory {
+        rows.push(("Память".to_string(), format_usage(memory)));
+    }
+    if let Some(swap) = &report.swap {
+        rows.push(("Swap".to_string(), format_usage(swap)));
+    }
+    for disk in &report.disks {
+        rows.push((format!("Диск {}", disk.mount), format_usage(&disk.usage)));
+    }
+    if let Some(temperature) = report.temperature {
+        rows.push(("CPU".to_string(), format!("{:.0} °C", temperature)));
+    }
+    let users = if report.users.is_empty() { "никого".to_string() } else { report.users.join(", ") };
+    rows.push(("В системе".to_string(), users));
+
+    let width = rows.iter().map(|(label, _)| label.chars().count()).max().unwrap_or(0);
+    rows.iter()
+        .map(|(label, value)| format!("{:<width$}  {}", label, value, width = width))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
@@ -0,0 +1,907 @@
+// SSH: установка соединения и выполнение команд на роутерах и серверах.
+// Клиент асинхронный (russh): обработчики бота ждут сеть, не занимая потоки.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use base64::Engine;
+use serde_json::{json, Value};
+use russh::{
+    client,
+    keys::{self, agent::client::AgentClient, HashAlg, PrivateKeyWithHashAlg, PublicKey},
+    Channel, ChannelMsg, Disconnect,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite},
+    net::TcpStream,
+    sync::mpsc,
+};
+
+use crate::{
+    store::{self, PendingOp, Store},
+    Config, SshTarget,
+};
+
+pub const DEFAULT_KNOWN_HOSTS: &str = "/app/known_hosts";
+
+// Как часто слать keepalive в открытые сессии; после KEEPALIVE_MAX пропущенных ответов
+// russh закрывает сессию сам
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+const KEEPALIVE_MAX: usize = 3;
+// Сколько держать неиспользуемую сессию
+const IDLE_LIMIT: Duration = Duration::from_secs(300);
+// Сколько свободных сессий держать на одну цель
+const IDLE_PER_TARGET: usize = 2;
+
+// Таймауты этапов удалённой операции
+#[derive(Clone, Debug, PartialEq)]
+pub struct Timeouts {
+    // Разрешение имени и TCP-подключение
+    pub connect: Duration,
+    // Обмен версиями и ключами SSH
+    pub handshake: Duration,
+    // Аутентификация по ключу
+    pub auth: Duration,
+    // Открытие канала, выполнение команды и чтение вывода
+    pub exec: Duration,
+}
+
+impl Timeouts {
+    pub fn uniform(timeout: Duration) -> Self {
+        Timeouts {
+            connect: timeout,
+            handshake: timeout,
+            auth: timeout,
+            exec: timeout,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Phase {
+    Connect,
+    Handshake,
+    Auth,
+    Exec,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Phase::Connect => "подключение",
+            Phase::Handshake => "рукопожатие",
+            Phase::Auth => "аутентификация",
+            Phase::Exec => "выполнение команды",
+        })
+    }
+}
+
+// Этап удалённой операции не уложился в свой таймаут
+#[derive(Debug, Clone)]
+pub struct PhaseTimeout {
+    pub phase: Phase,
+    // host:port, с которым шёл обмен
+    pub target: String,
+    pub after: Duration,
+}
+
+impl fmt::Display for PhaseTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {} не ответил за {} с", self.phase, self.target, self.after.as_secs())
+    }
+}
+
+impl std::error::Error for PhaseTimeout {}
+
+// Ограничивает этап удалённой операции его таймаутом; ошибка дополняется адресом и этапом
+async fn in_phase<T, E: fmt::Display>(
+    future: impl Future<Output = Result<T, E>>,
+    phase: Phase,
+    target: &str,
+    timeout: Duration,
+) -> Result<T> {
+    match tokio::time::timeout(timeout, future).await {
+        Ok(Ok(value)) => Ok(value),
+        Ok(Err(e)) => Err(anyhow::anyhow!("{}, {}: {}", target, phase, e)),
+        Err(_) => Err(PhaseTimeout { phase, target: target.to_string(), after: timeout }.into()),
+    }
+}
+
+// Способ аутентификации на SSH-сервере
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AuthMethod {
+    // Ключи из ssh-agent (сокет из SSH_AUTH_SOCK)
+    Agent,
+    // Файл приватного ключа, при необходимости с паролем
+    Key,
+}
+
+impl AuthMethod {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "agent" => Some(AuthMethod::Agent),
+            "key" => Some(AuthMethod::Key),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for AuthMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AuthMethod::Agent => "agent",
+            AuthMethod::Key => "key",
+        })
+    }
+}
+
+// Как проверять ключи хостов
+#[derive(Clone, Debug, PartialEq)]
+pub struct HostKeyPolicy {
+    // Файл в формате OpenSSH known_hosts
+    pub known_hosts: String,
+    // Неизвестный ключ запоминается и ждёт подтверждения админа вместо отказа
+    pub trust_on_first_use: bool,
+}
+
+impl Default for HostKeyPolicy {
+    fn default() -> Self {
+        HostKeyPolicy {
+            known_hosts: DEFAULT_KNOWN_HOSTS.to_string(),
+            trust_on_first_use: false,
+        }
+    }
+}
+
+// Ключ хоста не прошёл проверку — соединение прерывается до аутентификации
+#[derive(Debug, Clone)]
+pub enum HostKeyError {
+    Unknown { host: String, fingerprint: String, known_hosts: String },
+    PendingApproval { host: String, fingerprint: String },
+    Mismatch { host: String, fingerprint: String },
+}
+
+impl fmt::Display for HostKeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HostKeyError::Unknown { host, fingerprint, known_hosts } => write!(
+                f,
+                "ключ хоста {} ({}) не найден в {}",
+                host, fingerprint, known_hosts
+            ),
+            HostKeyError::PendingApproval { host, fingerprint } => write!(
+                f,
+                "новый ключ хоста {} ({}) ожидает подтверждения администратора",
+                host, fingerprint
+            ),
+            HostKeyError::Mismatch { host, fingerprint } => write!(
+                f,
+                "ключ хоста {} изменился ({}), возможна подмена сервера",
+                host, fingerprint
+            ),
+        }
+    }
+}
+
+impl std::error::Error for HostKeyError {}
+
+// События для админов: новый ключ на подтверждение или несовпадение ключа
+#[derive(Debug, Clone)]
+pub enum HostKeyEvent {
+    NewKey { id: u64, host: String, fingerprint: String },
+    Mismatch { host: String, fingerprint: String },
+}
+
+// Вид записи в pending_ops для ключей, ждущих решения админа
+pub const HOST_KEY_KIND: &str = "host_key";
+
+// Ключ, увиденный впервые в режиме trust-on-first-use. Хранится в pending_ops, так что
+// кнопки подтверждения переживают перезапуск, а номера запросов не повторяются.
+struct PendingKey {
+    host: String,
+    port: u16,
+    key: PublicKey,
+    fingerprint: String,
+}
+
+impl PendingKey {
+    fn encode(&self) -> Result<String> {
+        Ok(json!({
+            "host": self.host,
+            "port": self.port,
+            "key": self.key.to_openssh()?,
+            "fingerprint": self.fingerprint,
+        })
+        .to_string())
+    }
+
+    fn decode(op: &PendingOp) -> Option<PendingKey> {
+        let payload = serde_json::from_str::<Value>(&op.payload).ok()?;
+        Some(PendingKey {
+            host: payload["host"].as_str()?.to_string(),
+            port: u16::try_from(payload["port"].as_u64()?).ok()?,
+            key: PublicKey::from_openssh(payload["key"].as_str()?).ok()?,
+            fingerprint: payload["fingerprint"].as_str()?.to_string(),
+        })
+    }
+}
+
+lazy_static::lazy_static! {
+    // Проверка «уже ждёт решения» и добавление запроса должны идти одним шагом
+    static ref PENDING_LOCK: Mutex<()> = Mutex::new(());
+    // Несовпадения, о которых админы уже знают: (хост, отпечаток)
+    static ref REPORTED_MISMATCHES: Mutex<HashSet<(String, String)>> = Mutex::new(HashSet::new());
+    static ref HOST_KEY_EVENTS: (
+        mpsc::UnboundedSender<HostKeyEvent>,
+        Mutex<Option<mpsc::UnboundedReceiver<HostKeyEvent>>>,
+    ) = {
+        let (tx, rx) = mpsc::unbounded_channel();
+        (tx, Mutex::new(Some(rx)))
+    };
+}
+
+// Свободная сессия в пуле
+struct PooledSession {
+    conn: Connection,
+    idle_since: Instant,
+}
+
+lazy_static::lazy_static! {
+    // Тёплые сессии по целям; занятая сессия из пула изымается
+    static ref POOL: Mutex<HashMap<SshTarget, Vec<PooledSession>>> = Mutex::new(HashMap::new());
+}
+
+// Получатель событий о ключах хостов; забрать его можно один раз
+pub fn take_host_key_events() -> Option<mpsc::UnboundedReceiver<HostKeyEvent>> {
+    HOST_KEY_EVENTS.1.lock().unwrap().take()
+}
+
+fn emit(event: HostKeyEvent) {
+    if HOST_KEY_EVENTS.0.send(event).is_err() {
+        log::warn!("Некому доставить событие о ключе хоста");
+    }
+}
+
+// Имя хоста в known_hosts: для нестандартного порта OpenSSH пишет [host]:port
+pub fn known_hosts_name(host: &str, port: u16) -> String {
+    if port == 22 {
+        host.to_string()
+    } else {
+        format!("[{}]:{}", host, port)
+    }
+}
+
+// Отпечаток в виде, который печатает ssh-keygen -l: SHA256:<base64 без паддинга>
+pub fn fingerprint(digest: &[u8]) -> String {
+    format!("SHA256:{}", base64::engine::general_purpose::STANDARD_NO_PAD.encode(digest))
+}
+
+fn verify_host_key(key: &PublicKey, host: &str, port: u16, policy: &HostKeyPolicy) -> Result<()> {
+    let fingerprint = fingerprint(key.fingerprint(HashAlg::Sha256).as_bytes());
+    let name = known_hosts_name(host, port);
+
+    // Отсутствующий файл — то же, что пустой: ключ неизвестен
+    match keys::check_known_hosts_path(host, port, key, &policy.known_hosts) {
+        Ok(true) => {
+            log::debug!("Ключ хоста {} совпадает с known_hosts", name);
+            Ok(())
+        }
+        Err(keys::Error::KeyChanged { line }) => {
+            log::error!(
+                "Ключ хоста {} не совпадает с known_hosts (строка {}): {}",
+                name,
+                line,
+                fingerprint
+            );
+            let first_report = REPORTED_MISMATCHES
+                .lock()
+                .unwrap()
+                .insert((name.clone(), fingerprint.clone()));
+            if first_report {
+                emit(HostKeyEvent::Mismatch { host: name.clone(), fingerprint: fingerprint.clone() });
+            }
+            Err(HostKeyError::Mismatch { host: name, fingerprint }.into())
+        }
+        Ok(false) if policy.trust_on_first_use => {
+            let store = store::get().ok_or_else(|| anyhow::anyhow!("база состояния не открыта"))?;
+            if let Some(id) = remember_host_key(&store, host, port, key)? {
+                log::warn!("Новый ключ хоста {} ({}) ждёт подтверждения", name, fingerprint);
+                emit(HostKeyEvent::NewKey { id, host: name.clone(), fingerprint: fingerprint.clone() });
+            }
+            Err(HostKeyError::PendingApproval { host: name, fingerprint }.into())
+        }
+        Ok(false) => Err(HostKeyError::Unknown {
+            host: name,
+            fingerprint,
+            known_hosts: policy.known_hosts.clone(),
+        }
+        .into()),
+        Err(e) => anyhow::bail!("не удалось проверить ключ хоста {} по {}: {}", name, policy.known_hosts, e),
+    }
+}
+
+// Сохраняет ключ, показанный сервером host:port, до решения админа.
+// Возвращает номер запроса, если такой ключ ещё не ждал подтверждения.
+pub fn remember_host_key(store: &Store, host: &str, port: u16, key: &PublicKey) -> Result<Option<u64>> {
+    let pending = PendingKey {
+        host: host.to_string(),
+        port,
+        key: key.clone(),
+        fingerprint: fingerprint(key.fingerprint(HashAlg::Sha256).as_bytes()),
+    };
+    let _lock = PENDING_LOCK.lock().unwrap();
+    let known_already = store
+        .pending_ops(HOST_KEY_KIND)?
+        .iter()
+        .filter_map(PendingKey::decode)
+        .any(|p| p.host == pending.host && p.port == pending.port && p.fingerprint == pending.fingerprint);
+    if known_already {
+        return Ok(None);
+    }
+    let id = store.add_pending_op(&PendingOp {
+        id: 0,
+        kind: HOST_KEY_KIND.to_string(),
+        host: None,
+        requested_by: 0,
+        created_at: store::now(),
+        expires_at: None,
+        payload: pending.encode()?,
+    })?;
+    Ok(Some(id as u64))
+}
+
+// Админ подтвердил ключ: дописываем его в known_hosts. Возвращает имя хоста и отпечаток.
+pub fn approve_host_key(store: &Store, policy: &HostKeyPolicy, id: u64) -> Result<(String, String)> {
+    let pending = take_pending(store, id)?
+        .ok_or_else(|| anyhow::anyhow!("запрос на подтверждение ключа уже обработан"))?;
+    let name = known_hosts_name(&pending.host, pending.port);
+
+    keys::known_hosts::learn_known_hosts_path(&pending.host, pending.port, &pending.key, &policy.known_hosts)
+        .map_err(|e| anyhow::anyhow!("не удалось записать {}: {}", policy.known_hosts, e))?;
+
+    log::info!("Ключ хоста {} ({}) добавлен в {}", name, pending.fingerprint, policy.known_hosts);
+    Ok((name, pending.fingerprint))
+}
+
+// Админ отклонил ключ: забываем его, следующее подключение снова спросит
+pub fn reject_host_key(store: &Store, id: u64) -> Result<Option<(String, String)>> {
+    let Some(pending) = take_pending(store, id)? else {
+        return Ok(None);
+    };
+    log::warn!("Ключ хоста {}:{} ({}) отклонён", pending.host, pending.port, pending.fingerprint);
+    Ok(Some((known_hosts_name(&pending.host, pending.port), pending.fingerprint)))
+}
+
+fn take_pending(store: &Store, id: u64) -> Result<Option<PendingKey>> {
+    let _lock = PENDING_LOCK.lock().unwrap();
+    let Some(op) = store.pending_op(id as i64)?.filter(|op| op.kind == HOST_KEY_KIND) else {
+        return Ok(None);
+    };
+    if !store.remove_pending_op(op.id)? {
+        return Ok(None);
+    }
+    let pending = PendingKey::decode(&op);
+    if pending.is_none() {
+        log::warn!("Повреждённый запрос на подтверждение ключа {} удалён", id);
+    }
+    Ok(pending)
+}
+
+// IPv6-адрес можно указать в квадратных скобках, как в URL: [fd00::1]
+pub fn strip_brackets(host: &str) -> &str {
+    host.strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host)
+}
+
+// Разрешает имя с ограничением по времени. Системный резолвер не умеет таймауты:
+// tokio вызывает его в своём пуле потоков, а мы перестаём ждать, если он не успел.
+pub async fn resolve(host: &str, port: u16, timeout: Duration) -> Result<Vec<SocketAddr>> {
+    let host = strip_brackets(host);
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+
+    match tokio::time::timeout(timeout, tokio::net::lookup_host((host, port))).await {
+        Ok(Ok(addrs)) => {
+            let addrs = addrs.collect::<Vec<_>>();
+            if addrs.is_empty() {
+                anyhow::bail!("имя {} не разрешается ни в один адрес", host);
+            }
+            log::debug!("{} разрешается в {:?}", host, addrs);
+            Ok(addrs)
+        }
+        Ok(Err(e)) => anyhow::bail!("не удалось разрешить имя {}: {}", host, e),
+        Err(_) => Err(PhaseTimeout {
+            phase: Phase::Connect,
+            target: format!("{}:{}", host, port),
+            after: timeout,
+        }
+        .into()),
+    }
+}
+
+// Подключается по очереди ко всем адресам хоста, пока не уложимся в общий таймаут
+pub async fn connect_tcp(host: &str, port: u16, timeout: Duration) -> Result<TcpStream> {
+    let deadline = tokio::time::Instant::now() + timeout;
+    let addrs = resolve(host, port, timeout).await?;
+
+    let mut errors = Vec::new();
+    let mut timed_out = true;
+    for addr in &addrs {
+        match tokio::time::timeout_at(deadline, TcpStream::connect(addr)).await {
+            Ok(Ok(stream)) => {
+                log::debug!("Подключились к {} ({})", host, addr);
+                return Ok(stream);
+            }
+            Ok(Err(e)) => {
+                timed_out &= e.kind() == std::io::ErrorKind::TimedOut;
+                errors.push(format!("{}: {}", addr, e));
+            }
+            Err(_) => break,
+        }
+    }
+    if timed_out {
+        return Err(PhaseTimeout {
+            phase: Phase::Connect,
+            target: format!("{}:{}", host, port),
+            after: timeout,
+        }
+        .into());
+    }
+    anyhow::bail!("не удалось подключиться к {} порт {} ({})", host, port, errors.join("; "))
+}
+
+// Что ответил SSH-порт цели
+#[derive(Clone, Debug, PartialEq)]
+pub enum PortProbe {
+    // Подключиться не удалось
+    Closed(String),
+    // Порт принимает соединения, но SSH-сервер за ним не отвечает (например, оборван туннель)
+    Silent(String),
+    // Получен баннер SSH-сервера
+    Banner(String),
+}
+
+// Открыт ли SSH-порт цели и отвечает ли за ним SSH-сервер. За промежуточными хостами
+// проверяем пробным каналом direct-tcpip с последнего из них — напрямую из бота LAN не виден.
+pub async fn probe_port(config: &Config, target: &SshTarget, timeout: Duration) -> PortProbe {
+    let host = strip_brackets(&target.host);
+    match target.jump.split_last() {
+        None => match connect_tcp(host, target.port, timeout).await {
+            Ok(mut stream) => read_banner(&mut stream, timeout).await,
+            Err(e) => PortProbe::Closed(format!("{:#}", e)),
+        },
+        Some((hop, before)) => {
+            let hop = SshTarget { jump: before.to_vec(), ..hop.clone() };
+            let conn = match establish_ssh_connection(&hop, &config.ssh_timeouts, &config.host_keys).await {
+                Ok(conn) => conn,
+                Err(e) => return PortProbe::Closed(format!("промежуточный хост {}: {:#}", hop.host, e)),
+            };
+            let channel = conn.handle.channel_open_direct_tcpip(host, target.port as u32, "127.0.0.1", 0);
+            let probe = match tokio::time::timeout(timeout, channel).await {
+                Ok(Ok(channel)) => read_banner(&mut channel.into_stream(), timeout).await,
+                Ok(Err(e)) => PortProbe::Closed(e.to_string()),
+                Err(_) => PortProbe::Closed(format!("нет ответа за {} с", timeout.as_secs())),
+            };
+            conn.close().await;
+            probe
+        }
+    }
+}
+
+// SSH-сервер первым присылает строку вида SSH-2.0-OpenSSH_9.2
+async fn read_banner(stream: &mut (impl AsyncRead + Unpin), timeout: Duration) -> PortProbe {
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut banner = Vec::new();
+    let mut buf = [0u8; 64];
+    while banner.len() < 256 && !banner.contains(&b'\n') {
+        match tokio::time::timeout_at(deadline, stream.read(&mut buf)).await {
+            Ok(Ok(0)) => break,
+            Ok(Ok(n)) => banner.extend_from_slice(&buf[..n]),
+            Ok(Err(e)) => return PortProbe::Silent(e.to_string()),
+            Err(_) => return PortProbe::Silent(format!("нет ответа за {} с", timeout.as_secs())),
+        }
+    }
+
+    let text = String::from_utf8_lossy(&banner);
+    let line = text.lines().next().unwrap_or_default().trim();
+    if line.starts_with("SSH-") {
+        PortProbe::Banner(line.to_string())
+    } else if line.is_empty() {
+        PortProbe::Silent("соединение закрыто без ответа".to_string())
+    } else {
+        PortProbe::Silent(format!("ответ не похож на SSH: {}", line.chars().take(40).collect::<String>()))
+    }
+}
+
+// Обработчик сессии russh: проверяет ключ сервера во время обмена ключами, до аутентификации,
+// и передаёт туннелю каналы, которые сервер открывает по удалённому пробросу
+pub struct Client {
+    host: String,
+    port: u16,
+    policy: HostKeyPolicy,
+    forwarded: mpsc::UnboundedSender<Channel<client::Msg>>,
+}
+
+impl client::Handler for Client {
+    type Error = anyhow::Error;
+
+    async fn check_server_key(&mut self, key: &PublicKey) -> Result<bool> {
+        verify_host_key(key, &self.host, self.port, &self.policy)?;
+        Ok(true)
+    }
+
+    async fn server_channel_open_forwarded_tcpip(
+        &mut self,
+        channel: Channel<client::Msg>,
+        _connected_address: &str,
+        _connected_port: u32,
+        _originator_address: &str,
+        _originator_port: u32,
+        _session: &mut client::Session,
+    ) -> Result<()> {
+        if self.forwarded.send(channel).is_err() {
+            log::debug!("Канал проброса от {}:{} некому принять", self.host, self.port);
+        }
+        Ok(())
+    }
+}
+
+// Установленная и аутентифицированная сессия
+pub struct Connection {
+    pub handle: client::Handle<Client>,
+    // Каналы удалённых пробросов (ssh -R), открытые сервером
+    pub forwarded: mpsc::UnboundedReceiver<Channel<client::Msg>>,
+    // Сессия промежуточного хоста: по её каналу идёт весь трафик этой
+    hop: Option<Box<Connection>>,
+}
+
+impl Connection {
+    // Сессия оборвалась: сервер закрыл её или перестал отвечать на keepalive
+    pub fn is_closed(&self) -> bool {
+        self.handle.is_closed() || self.hop.as_ref().is_some_and(|hop| hop.is_closed())
+    }
+
+    pub async fn close(&self) {
+        let _ = self.handle.disconnect(Disconnect::ByApplication, "", "").await;
+    }
+}
+
+// Централизованная функция установления SSH соединения.
+// Если у цели есть промежуточные хосты, TCP до неё заменяется каналом direct-tcpip
+// через последний из них — он сам подключается через предыдущие.
+pub async fn establish_ssh_connection(
+    target: &SshTarget,
+    timeouts: &Timeouts,
+    host_keys: &HostKeyPolicy,
+) -> Result<Connection> {
+    let host = strip_brackets(&target.host);
+    let port = target.port;
+    let label = format!("{}:{}", host, port);
+    log::info!("Устанавливаем SSH соединение с {}", label);
+
+    let (tx, forwarded) = mpsc::unbounded_channel();
+    let client = Client { host: host.to_string(), port, policy: host_keys.clone(), forwarded: tx };
+    // Keepalive не даёт NAT и туннелям закрыть простаивающую сессию и замечает мёртвую
+    let config = Arc::new(client::Config {
+        keepalive_interval: Some(KEEPALIVE_INTERVAL),
+        keepalive_max: KEEPALIVE_MAX,
+        ..Default::default()
+    });
+
+    let (handle, hop) = match target.jump.split_last() {
+        None => {
+            let stream = connect_tcp(host, port, timeouts.connect).await?;
+            (handshake(config, stream, client, &label, timeouts.handshake).await?, None)
+        }
+        Some((hop, before)) => {
+            log::debug!("{} доступен через {}:{}", label, hop.host, hop.port);
+            let hop = SshTarget { jump: before.to_vec(), ..hop.clone() };
+            let hop = Box::pin(establish_ssh_connection(&hop, timeouts, host_keys)).await?;
+            let channel = in_phase(
+                hop.handle.channel_open_direct_tcpip(host, port as u32, "127.0.0.1", 0),
+                Phase::Connect,
+                &label,
+                timeouts.connect,
+            )
+            .await?;
+            let handle = handshake(config, channel.into_stream(), client, &label, timeouts.handshake).await?;
+            (handle, Some(Box::new(hop)))
+        }
+    };
+    let mut conn = Connection { handle, forwarded, hop };
+
+    match tokio::time::timeout(timeouts.auth, authenticate(&mut conn.handle, target, &label)).await {
+        Ok(result) => result?,
+        Err(_) => {
+            return Err(PhaseTimeout { phase: Phase::Auth, target: label, after: timeouts.auth }.into());
+        }
+    }
+
+    log::info!("SSH соединение установлено успешно");
+    Ok(conn)
+}
+
+// Обмен версиями и ключами; ключ сервера проверяет Client::check_server_key,
+// и его ошибку (HostKeyError) отдаём как есть, чтобы её можно было распознать
+async fn handshake<S>(
+    config: Arc<client::Config>,
+    stream: S,
+    client: Client,
+    label: &str,
+    timeout: Duration,
+) -> Result<client::Handle<Client>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    match tokio::time::timeout(timeout, client::connect_stream(config, stream, client)).await {
+        Ok(Ok(handle)) => Ok(handle),
+        Ok(Err(e)) if e.is::<HostKeyError>() => Err(e),
+        Ok(Err(e)) => Err(anyhow::anyhow!("{}, {}: {}", label, Phase::Handshake, e)),
+        Err(_) => Err(PhaseTimeout { phase: Phase::Handshake, target: label.to_string(), after: timeout }.into()),
+    }
+}
+
+// Пробует способы входа по порядку; при неудаче перечисляет, что и почему не подошло
+async fn authenticate(handle: &mut client::Handle<Client>, target: &SshTarget, label: &str) -> Result<()> {
+    let mut tried = Vec::new();
+    for method in &target.auth {
+        let result = match method {
+            AuthMethod::Agent => auth_agent(handle, &target.user).await,
+            AuthMethod::Key => auth_key(handle, target).await,
+        };
+        match result {
+            Ok(true) => {
+                log::debug!("Вход на {} выполнен способом {}", label, method);
+                return Ok(());
+            }
+            Ok(false) => tried.push(format!("{}: сервер не принял", method)),
+            Err(e) => tried.push(format!("{}: {}", method, e)),
+        }
+    }
+
+    log::error!("SSH аутентификация не удалась для {}@{}: {}", target.user, label, tried.join("; "));
+    anyhow::bail!(
+        "{}@{}: аутентификация не удалась, испробовано: {}",
+        target.user,
+        label,
+        tried.join("; ")
+    )
+}
+
+// Хеш подписи для RSA-ключей: лучший из объявленных сервером, по умолчанию rsa-sha2-256 —
+// устаревший ssh-rsa (SHA-1) современные серверы отвергают
+async fn rsa_hash(handle: &client::Handle<Client>, key: &PublicKey) -> Option<HashAlg> {
+    if !key.algorithm().is_rsa() {
+        return None;
+    }
+    match handle.best_supported_rsa_hash().await {
+        Ok(Some(hash)) => hash,
+        _ => Some(HashAlg::Sha256),
+    }
+}
+
+async fn auth_agent(handle: &mut client::Handle<Client>, user: &str) -> Result<bool> {
+    if std::env::var_os("SSH_AUTH_SOCK").is_none() {
+        anyhow::bail!("SSH_AUTH_SOCK не задан");
+    }
+    let mut agent = AgentClient::connect_env().await?;
+    let identities = agent.request_identities().await?;
+    if identities.is_empty() {
+        anyhow::bail!("в агенте нет ключей");
+    }
+
+    for identity in &identities {
+        let hash = rsa_hash(handle, identity).await;
+        match handle.authenticate_publickey_with(user, identity.clone(), hash, &mut agent).await {
+            Ok(result) if result.success() => return Ok(true),
+            Ok(_) => log::debug!("Ключ агента '{}' не подошёл", identity.comment()),
+            Err(e) => log::debug!("Ключ агента '{}' не подошёл: {}", identity.comment(), e),
+        }
+    }
+    anyhow::bail!("ни один из ключей агента ({}) не подошёл", identities.len())
+}
+
+async fn auth_key(handle: &mut client::Handle<Client>, target: &SshTarget) -> Result<bool> {
+    let key = target
+        .key
+        .as_deref()
+        .ok_or_else(|| anyhow::anyhow!("не задан файл ключа"))?;
+    // Пароль читаем при каждом подключении, чтобы ротация секрета не требовала перезапуска
+    let passphrase = match &target.passphrase_file {
+        Some(path) => {
+            let secret = std::fs::read_to_string(path)
+                .map_err(|e| anyhow::anyhow!("не удалось прочитать файл пароля {}: {}", path, e))?;
+            Some(secret.trim_end_matches(['\r', '\n']).to_string())
+        }
+        None => None,
+    };
+    let secret = keys::load_secret_key(key, passphrase.as_deref()).map_err(|e| anyhow::anyhow!("{}: {}", key, e))?;
+    let hash = rsa_hash(handle, secret.public_key()).await;
+    let result = handle
+        .authenticate_publickey(&target.user, PrivateKeyWithHashAlg::new(Arc::new(secret), hash))
+        .await?;
+    Ok(result.success())
+}
+
+// Результат удалённой команды
+#[derive(Debug, Clone)]
+pub struct CommandOutput {
+    pub stdout: String,
+    pub stderr: String,
+    pub exit_status: i32,
+}
+
+// Команда выполнилась, но завершилась с ненулевым кодом
+#[derive(Debug, Clone)]
+pub struct RemoteCommandError {
+    // Команда в том виде, в каком её можно показывать (без паролей)
+    pub command: String,
+    pub output: CommandOutput,
+}
+
+impl fmt::Display for RemoteCommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = self
+            .output
+            .stderr
+            .lines()
+            .chain(self.output.stdout.lines())
+            .map(str::trim)
+            .find(|line| !line.is_empty())
+            .unwrap_or("нет вывода");
+        write!(f, "{} (код {})", reason, self.output.exit_status)
+    }
+}
+
+impl std::error::Error for RemoteCommandError {}
+
+// Канал закрылся, а код завершения сервер так и не прислал: успех команды не подтверждён
+#[derive(Debug, Clone)]
+pub struct NoExitStatus {
+    pub command: String,
+}
+
+impl fmt::Display for NoExitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "'{}': сервер закрыл канал, не сообщив код завершения", self.command)
+    }
+}
+
+impl std::error::Error for NoExitStatus {}
+
+async fn connect(config: &Config, target: &SshTarget) -> Result<Connection> {
+    establish_ssh_connection(target, &config.ssh_timeouts, &config.host_keys).await
+}
+
+// Берёт тёплую сессию из пула или открывает новую. Второе значение — взята ли из пула.
+async fn checkout(config: &Config, target: &SshTarget) -> Result<(Connection, bool)> {
+    let idle = POOL.lock().unwrap().get_mut(target).and_then(Vec::pop);
+    if let Some(pooled) = idle {
+        if pooled.idle_since.elapsed() < IDLE_LIMIT && !pooled.conn.is_closed() {
+            log::debug!("Используем открытую сессию с {}:{}", target.host, target.port);
+            return Ok((pooled.conn, true));
+        }
+    }
+    Ok((connect(config, target).await?, false))
+}
+
+fn checkin(target: &SshTarget, conn: Connection) {
+    let mut pool = POOL.lock().unwrap();
+    let idle = pool.entry(target.clone()).or_default();
+    if idle.len() < IDLE_PER_TARGET {
+        idle.push(PooledSession { conn, idle_since: Instant::now() });
+    }
+}
+
+// Убирает из пула оборвавшиеся и давно не использованные сессии. Keepalive в открытые
+// сессии шлёт сам russh, он же закрывает сессию, если сервер перестал на них отвечать.
+pub fn maintain_pool() {
+    let mut pool = POOL.lock().unwrap();
+    for (target, sessions) in pool.iter_mut() {
+        sessions.retain(|pooled| {
+            if pooled.conn.is_closed() {
+                log::info!("Сессия с {}:{} оборвалась", target.host, target.port);
+                false
+            } else if pooled.idle_since.elapsed() >= IDLE_LIMIT {
+                log::debug!("Закрываем простаивающую сессию с {}:{}", target.host, target.port);
+                false
+            } else {
+                true
+            }
+        });
+    }
+    pool.retain(|_, sessions| !sessions.is_empty());
+}
+
+// Фоновая задача: чистит пул сессий
+pub async fn keep_alive_sessions() {
+    let mut ticker = tokio::time::interval(KEEPALIVE_INTERVAL);
+    loop {
+        ticker.tick().await;
+        maintain_pool();
+    }
+}
+
+// Выполняет команду и читает stdout, stderr и код завершения.
+// `display` — та же команда для логов и сообщений об ошибках (с замаскированными секретами).
+// Ненулевой код завершения превращается в RemoteCommandError, отсутствующий — в NoExitStatus.
+// Сессия берётся из пула и возвращается туда после успешного обмена.
+pub async fn exec(config: &Config, target: &SshTarget, command: &str, display: &str) -> Result<CommandOutput> {
+    let timeout = config.ssh_timeouts.exec;
+    let label = format!("{}:{}", strip_brackets(&target.host), target.port);
+    let (mut conn, reused) = checkout(config, target).await?;
+
+    log::info!("Выполняем на {}: {}", label, display);
+    // Сессия из пула могла умереть, пока лежала; до exec переподключиться безопасно
+    let mut channel = match in_phase(conn.handle.channel_open_session(), Phase::Exec, &label, timeout).await {
+        Ok(channel) => channel,
+        Err(e) if reused => {
+            log::info!("Сессия с {} устарела ({}), переподключаемся", label, e);
+            conn = connect(config, target).await?;
+            in_phase(conn.handle.channel_open_session(), Phase::Exec, &label, timeout).await?
+        }
+        Err(e) => return Err(e),
+    };
+    // Тайм-аут выполнения ограничивает команду целиком, от запуска до последнего байта вывода
+    let deadline = tokio::time::Instant::now() + timeout;
+    in_phase(channel.exec(true, command), Phase::Exec, &label, timeout).await?;
+
+    let output = read_output(&mut channel, &label, display, deadline, timeout).await?;
+    log::debug!("Команда '{}' завершилась с кодом {}", display, output.exit_status);
+    checkin(target, conn);
+
+    if output.exit_status != 0 {
+        let err = RemoteCommandError { command: display.to_string(), output };
+        log::warn!("Команда '{}' завершилась с ошибкой: {}", display, err);
+        return Err(err.into());
+    }
+    Ok(output)
+}
+
+// Собирает вывод по мере поступления: stdout и stderr приходят сообщениями одного канала,
+// поэтому команда, много пишущая в stderr, не ждёт, пока мы дочитаем stdout.
+// Команда, которая продолжает понемногу писать, всё равно прерывается в срок `deadline`.
+async fn read_output(
+    channel: &mut Channel<client::Msg>,
+    label: &str,
+    display: &str,
+    deadline: tokio::time::Instant,
+    timeout: Duration,
+) -> Result<CommandOutput> {
+    let (mut stdout, mut stderr) = (Vec::new(), Vec::new());
+    let mut exit_status = None;
+    loop {
+        let msg = tokio::time::timeout_at(deadline, channel.wait())
+            .await
+            .map_err(|_| PhaseTimeout { phase: Phase::Exec, target: label.to_string(), after: timeout })?;
+        match msg {
+            Some(ChannelMsg::Data { data }) => stdout.extend_from_slice(&data),
+            Some(ChannelMsg::ExtendedData { data, ext: 1 }) => stderr.extend_from_slice(&data),
+            Some(ChannelMsg::ExitStatus { exit_status: status }) => exit_status = Some(status as i32),
+            Some(ChannelMsg::ExitSignal { signal_name, .. }) => {
+                stderr.extend_from_slice(format!("завершена сигналом {:?}\n", signal_name).as_bytes());
+                exit_status = Some(-1);
+            }
+            Some(_) => {}
+            None => break,
+        }
+    }
+    let Some(exit_status) = exit_status else {
+        log::warn!("Команда '{}' на {} завершилась без кода", display, label);
+        return Err(NoExitStatus { command: display.to_string() }.into());
+    };
+    Ok(CommandOutput {
+        stdout: String::from_utf8_lossy(&stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&stderr).into_owned(),
+        exit_status,
+    })
+}
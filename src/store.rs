@@ -0,0 +1,483 @@
+// Встроенная база SQLite: состояние бота, которое должно пережить перезапуск контейнера
+
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context, Result};
+use rusqlite::{params, params_from_iter, types::Value, Connection, OptionalExtension};
+
+use crate::audit::{AuditEvent, AuditFilter};
+
+pub const DEFAULT_PATH: &str = "/app/data/bot.db";
+
+// Миграции применяются по порядку; номер последней хранится в PRAGMA user_version.
+// Уже выпущенные миграции не меняются — только дописываются новые.
+const MIGRATIONS: &[&str] = &[
+    // 1: исходная схема
+    "CREATE TABLE audit_events (
+        id INTEGER PRIMARY KEY,
+        at INTEGER NOT NULL,
+        user_id INTEGER NOT NULL,
+        user_name TEXT NOT NULL,
+        action TEXT NOT NULL,
+        host TEXT,
+        result TEXT NOT NULL,
+        details TEXT NOT NULL DEFAULT ''
+    );
+    CREATE INDEX audit_events_at ON audit_events (at);
+
+    CREATE TABLE host_states (
+        id INTEGER PRIMARY KEY,
+        host TEXT NOT NULL,
+        at INTEGER NOT NULL,
+        state TEXT NOT NULL,
+        details TEXT NOT NULL DEFAULT ''
+    );
+    CREATE INDEX host_states_host_at ON host_states (host, at);
+
+    CREATE TABLE schedules (
+        id INTEGER PRIMARY KEY,
+        host TEXT NOT NULL,
+        action TEXT NOT NULL,
+        spec TEXT NOT NULL,
+        created_by INTEGER NOT NULL,
+        created_at INTEGER NOT NULL,
+        enabled INTEGER NOT NULL DEFAULT 1
+    );
+
+    CREATE TABLE user_prefs (
+        user_id INTEGER NOT NULL,
+        key TEXT NOT NULL,
+        value TEXT NOT NULL,
+        PRIMARY KEY (user_id, key)
+    );
+
+    CREATE TABLE pending_ops (
+        id INTEGER PRIMARY KEY,
+        kind TEXT NOT NULL,
+        host TEXT,
+        requested_by INTEGER NOT NULL,
+        created_at INTEGER NOT NULL,
+        expires_at INTEGER,
+        payload TEXT NOT NULL DEFAULT ''
+    );",
+    // 2: пользователи, которым доступ выдан из чата, без правки конфига
+    "CREATE TABLE granted_users (
+        user_id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL,
+        role TEXT NOT NULL,
+        granted_by INTEGER NOT NULL,
+        granted_at INTEGER NOT NULL
+    );",
+    // 3: номера ожидающих операций больше не переиспользуются — кнопка из сообщения
+    // о решённом запросе не должна попасть в новый запрос с тем же номером
+    "CREATE TABLE pending_ops_new (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        kind TEXT NOT NULL,
+        host TEXT,
+        requested_by INTEGER NOT NULL,
+        created_at INTEGER NOT NULL,
+        expires_at INTEGER,
+        payload TEXT NOT NULL DEFAULT ''
+    );
+    INSERT INTO pending_ops_new (id, kind, host, requested_by, created_at, expires_at, payload)
+        SELECT id, kind, host, requested_by, created_at, expires_at, payload FROM pending_ops;
+    DROP TABLE pending_ops;
+    ALTER TABLE pending_ops_new RENAME TO pending_ops;",
+];
+
+// Запись истории состояний хоста
+#[derive(Clone, Debug, PartialEq)]
+pub struct HostStateRecord {
+    pub at: i64,
+    pub state: String,
+    pub details: String,
+}
+
+// Операция, ожидающая решения: запрос доступа, подтверждение и т.п.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PendingOp {
+    pub id: i64,
+    pub kind: String,
+    pub host: Option<String>,
+    pub requested_by: i64,
+    pub created_at: i64,
+    pub expires_at: Option<i64>,
+    pub payload: String,
+}
+
+// Отложенное или повторяющееся действие над хостом; spec — когда его выполнять.
+// Планировщика, который их выполняет, пока нет: хранилище готовит для него место
+#[allow(dead_code)]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Schedule {
+    pub id: i64,
+    pub host: String,
+    pub action: String,
+    pub spec: String,
+    pub created_by: i64,
+    pub created_at: i64,
+    pub enabled: bool,
+}
+
+// Пользователь, получивший доступ через запрос в чате
+#[derive(Clone, Debug, PartialEq)]
+pub struct GrantedUser {
+    pub user_id: i64,
+    pub name: String,
+    pub role: String,
+    pub granted_by: i64,
+    pub granted_at: i64,
+}
+
+pub struct Store {
+    conn: Mutex<Connection>,
+}
+
+lazy_static::lazy_static! {
+    static ref STORE: Mutex<Option<Arc<Store>>> = Mutex::new(None);
+}
+
+// Открывает базу бота при запуске; дальше она доступна через get()
+pub fn init(path: &str) -> Result<()> {
+    let store = Store::open(path)?;
+    *STORE.lock().unwrap() = Some(Arc::new(store));
+    Ok(())
+}
+
+pub fn get() -> Option<Arc<Store>> {
+    STORE.lock().unwrap().clone()
+}
+
+pub fn now() -> i64 {
+    chrono::Utc::now().timestamp()
+}
+
+impl Store {
+    pub fn open(path: &str) -> Result<Store> {
+        if let Some(dir) = Path::new(path).parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir).with_context(|| format!("не удалось создать каталог {}", dir.display()))?;
+        }
+        let mut conn = Connection::open(path).with_context(|| format!("не удалось открыть базу {}", path))?;
+        Store::prepare(&mut conn).with_context(|| format!("база {}", path))?;
+        Ok(Store { conn: Mutex::new(conn) })
+    }
+
+    fn prepare(conn: &mut Connection) -> Result<()> {
+        // journal_mode возвращает строку с итоговым режимом, поэтому с проверкой
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+        conn.busy_timeout(std::time::Duration::from_secs(5))?;
+        migrate(conn)
+    }
+
+    // ------------------------------------------------------------------
+    // Настройки пользователей
+
+    pub fn set_pref(&self, user_id: i64, key: &str, value: &str) -> Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO user_prefs (user_id, key, value) VALUES (?1, ?2, ?3)
+             ON CONFLICT (user_id, key) DO UPDATE SET value = excluded.value",
+            params![user_id, key, value],
+        )?;
+        Ok(())
+    }
+
+    pub fn pref(&self, user_id: i64, key: &str) -> Result<Option<String>> {
+        let conn = self.conn.lock().unwrap();
+        let value = conn
+            .query_row(
+                "SELECT value FROM user_prefs WHERE user_id = ?1 AND key = ?2",
+                params![user_id, key],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(value)
+    }
+
+    pub fn remove_pref(&self, user_id: i64, key: &str) -> Result<()> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM user_prefs WHERE user_id = ?1 AND key = ?2", params![user_id, key])?;
+        Ok(())
+    }
+
+    // Значение настройки у всех пользователей, где она задана
+    pub fn prefs(&self, key: &str) -> Result<Vec<(i64, String)>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT user_id, value FROM user_prefs WHERE key = ?1 ORDER BY user_id")?;
+        let rows = stmt.query_map(params![key], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+
+    // ------------------------------------------------------------------
+    // Журнал действий
+
+    pub fn record_audit(&self, event: &AuditEvent) -> Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO audit_events (at, user_id, user_name, action, host, result, details)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                event.at,
+                event.user_id,
+                event.user_name,
+                event.action,
+                event.host,
+                event.result,
+                event.details
+            ],
+        )?;
+        Ok(())
+    }
+
+    // Последние записи, подходящие под фильтр, новые сначала
+    pub fn audit_events(&self, filter: &AuditFilter) -> Result<Vec<AuditEvent>> {
+        let mut conditions = Vec::new();
+        let mut values: Vec<Value> = Vec::new();
+        if let Some(user) = &filter.user {
+            match user.parse::<i64>() {
+                Ok(id) => {
+                    conditions.push("user_id = ?");
+                    values.push(Value::Integer(id));
+                }
+                Err(_) => {
+                    conditions.push("lower(user_name) IN (lower(?), lower(?))");
+                    values.push(Value::Text(user.clone()));
+                    values.push(Value::Text(format!("@{}", user)));
+                }
+            }
+        }
+        if let Some(host) = &filter.host {
+            conditions.push("host = ?");
+            values.push(Value::Text(host.clone()));
+        }
+        if let Some(since) = filter.since {
+            conditions.push("at >= ?");
+            values.push(Value::Integer(since));
+        }
+        if let Some(until) = filter.until {
+            conditions.push("at < ?");
+            values.push(Value::Integer(until));
+        }
+        values.push(Value::Integer(filter.limit as i64));
+
+        let condition = if conditions.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", conditions.join(" AND "))
+        };
+        let sql = format!(
+            "SELECT at, user_id, user_name, action, host, result, details FROM audit_events {}
+             ORDER BY at DESC, id DESC LIMIT ?",
+            condition
+        );
+
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&sql)?;
+        let rows = stmt.query_map(params_from_iter(values), |row| {
+            Ok(AuditEvent {
+                at: row.get(0)?,
+                user_id: row.get(1)?,
+                user_name: row.get(2)?,
+                action: row.get(3)?,
+                host: row.get(4)?,
+                result: row.get(5)?,
+                details: row.get(6)?,
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+
+    // ------------------------------------------------------------------
+    // Ожидающие операции
+
+    // Сохраняет операцию и возвращает её номер (поле id игнорируется)
+    pub fn add_pending_op(&self, op: &PendingOp) -> Result<i64> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO pending_ops (kind, host, requested_by, created_at, expires_at, payload)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![op.kind, op.host, op.requested_by, op.created_at, op.expires_at, op.payload],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    pub fn pending_ops(&self, kind: &str) -> Result<Vec<PendingOp>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, kind, host, requested_by, created_at, expires_at, payload FROM pending_ops
+             WHERE kind = ?1 ORDER BY id",
+        )?;
+        let rows = stmt.query_map(params![kind], pending_op_from_row)?;
+        Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+
+    pub fn pending_op(&self, id: i64) -> Result<Option<PendingOp>> {
+        let conn = self.conn.lock().unwrap();
+        let op = conn
+            .query_row(
+                "SELECT id, kind, host, requested_by, created_at, expires_at, payload FROM pending_ops WHERE id = ?1",
+                params![id],
+                pending_op_from_row,
+            )
+            .optional()?;
+        Ok(op)
+    }
+
+    // Меняет данные операции; false — её уже нет
+    pub fn update_pending_op(&self, id: i64, payload: &str) -> Result<bool> {
+        let updated = self
+            .conn
+            .lock()
+            .unwrap()
+            .execute("UPDATE pending_ops SET payload = ?2 WHERE id = ?1", params![id, payload])?;
+        Ok(updated > 0)
+    }
+
+    // Удаляет операцию; false — её уже нет (обработана кем-то другим)
+    pub fn remove_pending_op(&self, id: i64) -> Result<bool> {
+        let removed = self.conn.lock().unwrap().execute("DELETE FROM pending_ops WHERE id = ?1", params![id])?;
+        Ok(removed > 0)
+    }
+
+    // ------------------------------------------------------------------
+    // Доступ, выданный из чата
+
+    pub fn grant_user(&self, user: &GrantedUser) -> Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO granted_users (user_id, name, role, granted_by, granted_at) VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT (user_id) DO UPDATE SET
+                name = excluded.name, role = excluded.role,
+                granted_by = excluded.granted_by, granted_at = excluded.granted_at",
+            params![user.user_id, user.name, user.role, user.granted_by, user.granted_at],
+        )?;
+        Ok(())
+    }
+
+    pub fn granted_users(&self) -> Result<Vec<GrantedUser>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt =
+            conn.prepare("SELECT user_id, name, role, granted_by, granted_at FROM granted_users ORDER BY user_id")?;
+        let rows = stmt.query_map([], |row| {
+            Ok(GrantedUser {
+                user_id: row.get(0)?,
+                name: row.get(1)?,
+                role: row.get(2)?,
+                granted_by: row.get(3)?,
+                granted_at: row.get(4)?,
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+
+    // ------------------------------------------------------------------
+    // История состояний хостов
+
+    pub fn record_host_state(&self, host: &str, at: i64, state: &str, details: &str) -> Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO host_states (host, at, state, details) VALUES (?1, ?2, ?3, ?4)",
+            params![host, at, state, details],
+        )?;
+        Ok(())
+    }
+
+    pub fn last_host_state(&self, host: &str) -> Result<Option<HostStateRecord>> {
+        let conn = self.conn.lock().unwrap();
+        let record = conn
+            .query_row(
+                "SELECT at, state, details FROM host_states WHERE host = ?1 ORDER BY at DESC, id DESC LIMIT 1",
+                params![host],
+                |row| Ok(HostStateRecord { at: row.get(0)?, state: row.get(1)?, details: row.get(2)? }),
+            )
+            .optional()?;
+        Ok(record)
+    }
+}
+
+// Расписания
+#[allow(dead_code)]
+impl Store {
+    pub fn add_schedule(&self, schedule: &Schedule) -> Result<i64> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO schedules (host, action, spec, created_by, created_at, enabled)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                schedule.host,
+                schedule.action,
+                schedule.spec,
+                schedule.created_by,
+                schedule.created_at,
+                schedule.enabled
+            ],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    pub fn schedules(&self) -> Result<Vec<Schedule>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare("SELECT id, host, action, spec, created_by, created_at, enabled FROM schedules ORDER BY id")?;
+        let rows = stmt.query_map([], |row| {
+            Ok(Schedule {
+                id: row.get(0)?,
+                host: row.get(1)?,
+                action: row.get(2)?,
+                spec: row.get(3)?,
+                created_by: row.get(4)?,
+                created_at: row.get(5)?,
+                enabled: row.get(6)?,
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+
+    // Включает или приостанавливает расписание; false — его уже нет
+    pub fn set_schedule_enabled(&self, id: i64, enabled: bool) -> Result<bool> {
+        let updated = self
+            .conn
+            .lock()
+            .unwrap()
+            .execute("UPDATE schedules SET enabled = ?2 WHERE id = ?1", params![id, enabled])?;
+        Ok(updated > 0)
+    }
+
+    pub fn remove_schedule(&self, id: i64) -> Result<bool> {
+        let removed = self.conn.lock().unwrap().execute("DELETE FROM schedules WHERE id = ?1", params![id])?;
+        Ok(removed > 0)
+    }
+}
+
+fn pending_op_from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<PendingOp> {
+    Ok(PendingOp {
+        id: row.get(0)?,
+        kind: row.get(1)?,
+        host: row.get(2)?,
+        requested_by: row.get(3)?,
+        created_at: row.get(4)?,
+        expires_at: row.get(5)?,
+        payload: row.get(6)?,
+    })
+}
+
+fn migrate(conn: &mut Connection) -> Result<()> {
+    let current = conn.pragma_query_value(None, "user_version", |row| row.get::<_, i64>(0))? as usize;
+    if current > MIGRATIONS.len() {
+        anyhow::bail!(
+            "схема базы версии {} новее, чем знает бот ({}); обновите бота",
+            current,
+            MIGRATIONS.len()
+        );
+    }
+    for (index, sql) in MIGRATIONS.iter().enumerate().skip(current) {
+        let version = index + 1;
+        log::info!("Применяем миграцию базы {}", version);
+        let tx = conn.transaction()?;
+        tx.execute_batch(sql).with_context(|| format!("миграция {}", version))?;
+        tx.pragma_update(None, "user_version", version as i64)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
@@ -1,30 +1,86 @@
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod tests {
+    use std::collections::HashMap;
     use std::time::Duration;
     use teloxide::types::{
-        Update, UpdateKind, CallbackQuery, User, Chat, ChatKind, Message, MessageKind,
+        CallbackQuery, User, Chat, ChatKind, Message, MessageKind,
         MessageId, UserId, ChatPrivate,
     };
-    use tokio_test;
     use std::sync::Arc;
-    use crate::{Config, is_allowed, main_keyboard, is_valid_mac, check_button_debounce};
+    use crate::{
+        access::{self, AclEntry, Permission, Role},
+        approval,
+        totp,
+        audit::{self, AuditEvent, AuditFilter, ExportFormat},
+        Config, HostConfig, SshTarget, is_allowed, main_keyboard, start_keyboard, hosts_keyboard, is_valid_mac, boot_failure_alert,
+        is_valid_host_id, check_button_debounce, parse_callback_data, boot_progress_text, HostState,
+        describe_error, failure_keyboard, sanitize_for_chat, record_error, error_details, ErrorDetails, probe_verdict, Probe, ProbeStep,
+        access_request_keyboard, admin_ids, check_access_request_cooldown, totp_action_names,
+        ssh::{self, CommandOutput, HostKeyPolicy, RemoteCommandError},
+        monitor::{self, Debouncer, MonitorEvent, Reachability},
+        report::{self, Disk, StatusReport, Usage},
+        store::{self, Store},
+        tunnel,
+        wol::{self, CommandTemplate, RouterCommand, SecureOn, UdpSender, WakeRetry, WolTransport},
+    };
 
     // Тестовая конфигурация
     fn test_config() -> Config {
         Config {
             bot_token: "test_token".to_string(),
             allowed_users: vec![123456789],
-            server_mac: "00:11:22:33:44:55".to_string(),
-            router_ssh_host: "test_router".to_string(),
-            router_ssh_port: 22,
-            router_ssh_user: "test_user".to_string(),
-            router_ssh_key: "/test/key".to_string(),
-            server_ssh_host: "test_server".to_string(),
-            server_ssh_port: 22,
-            server_ssh_user: "test_user".to_string(),
-            server_ssh_key: "/test/key".to_string(),
-            ssh_timeout: Duration::from_secs(5),
+            admins: vec![123456789],
+            roles: HashMap::from([(123456789, Role::Admin)]),
+            hosts: vec![test_host("server", "00:11:22:33:44:55")],
+            ssh_timeouts: ssh::Timeouts::uniform(Duration::from_secs(5)),
             nc_timeout: Duration::from_secs(3),
+            host_keys: HostKeyPolicy::default(),
+            monitor: monitor::MonitorConfig::default(),
+            subscribers: vec![],
+            database: ":memory:".to_string(),
+            totp: totp::TotpConfig::default(),
+            tunnels: vec![],
+            warnings: vec![],
+        }
+    }
+
+    // Тестовый хост инвентаря
+    fn test_host(id: &str, mac: &str) -> HostConfig {
+        HostConfig {
+            id: id.to_string(),
+            name: format!("Test {}", id),
+            mac: mac.to_string(),
+            ssh: SshTarget {
+                host: "test_server".to_string(),
+                port: 22,
+                user: "test_user".to_string(),
+                key: Some("/test/key".to_string()),
+                passphrase_file: None,
+                auth: vec![ssh::AuthMethod::Key],
+                jump: vec![],
+            },
+            router: Some(SshTarget {
+                host: "test_router".to_string(),
+                port: 22,
+                user: "test_user".to_string(),
+                key: Some("/test/key".to_string()),
+                passphrase_file: None,
+                auth: vec![ssh::AuthMethod::Key],
+                jump: vec![],
+            }),
+            wol: WolTransport::Router(RouterCommand {
+                template: CommandTemplate::Etherwake,
+                interface: "br-lan".to_string(),
+            }),
+            secureon: None,
+            boot_timeout: Duration::from_secs(180),
+            retry: WakeRetry::default(),
+            lan_ip: None,
+            mounts: vec!["/".to_string()],
+            monitor_interval: None,
+            acl: None,
+            approval: None,
         }
     }
 
@@ -56,6 +112,7 @@ mod tests {
                 has_restricted_voice_and_video_messages: None,
             }),
             photo: None,
+            pinned_message: None,
             has_aggressive_anti_spam_enabled: false,
             has_hidden_members: false,
             message_auto_delete_time: None,
@@ -120,7 +177,8 @@ mod tests {
 
     #[tokio::test]
     async fn test_main_keyboard_creation() {
-        let kb = main_keyboard();
+        let config = test_config();
+        let kb = main_keyboard(&config, &config.hosts[0], 123456789);
         
         // Проверяем что клавиатура создалась
         assert!(!kb.inline_keyboard.is_empty());
@@ -136,19 +194,19 @@ mod tests {
         
         // Проверяем callback data кнопок через поле kind
         if let teloxide::types::InlineKeyboardButtonKind::CallbackData(data) = &kb.inline_keyboard[0][0].kind {
-            assert_eq!(data, "wol");
+            assert_eq!(data, "wol:server");
         } else {
             panic!("Ожидался CallbackData для кнопки WOL");
         }
         
         if let teloxide::types::InlineKeyboardButtonKind::CallbackData(data) = &kb.inline_keyboard[0][1].kind {
-            assert_eq!(data, "shutdown_confirm");
+            assert_eq!(data, "shutdown_confirm:server");
         } else {
             panic!("Ожидался CallbackData для кнопки shutdown_confirm");
         }
         
         if let teloxide::types::InlineKeyboardButtonKind::CallbackData(data) = &kb.inline_keyboard[1][0].kind {
-            assert_eq!(data, "status");
+            assert_eq!(data, "status:server");
         } else {
             panic!("Ожидался CallbackData для кнопки status");
         }
@@ -156,15 +214,97 @@ mod tests {
         println!("✅ Главная клавиатура создается корректно");
     }
 
+    #[test]
+    fn test_multi_host_keyboards() {
+        let mut config = test_config();
+        config.hosts.push(test_host("nas", "AA:BB:CC:DD:EE:FF"));
+
+        // Со списком хостов стартовое меню предлагает выбрать машину
+        let kb = start_keyboard(&config, 123456789);
+        assert_eq!(kb.inline_keyboard.len(), 2);
+        if let teloxide::types::InlineKeyboardButtonKind::CallbackData(data) = &kb.inline_keyboard[1][0].kind {
+            assert_eq!(data, "host:nas");
+        } else {
+            panic!("Ожидался CallbackData для выбора хоста");
+        }
+
+        // В меню хоста появляется кнопка возврата к списку
+        let kb = main_keyboard(&config, &config.hosts[1], 123456789);
+        assert_eq!(kb.inline_keyboard.len(), 3);
+        if let teloxide::types::InlineKeyboardButtonKind::CallbackData(data) = &kb.inline_keyboard[0][0].kind {
+            assert_eq!(data, "wol:nas");
+        } else {
+            panic!("Ожидался CallbackData для кнопки WOL");
+        }
+
+        println!("✅ Клавиатуры для нескольких хостов создаются корректно");
+    }
+
+    #[test]
+    fn test_parse_callback_data() {
+        let mut config = test_config();
+
+        // Единственный хост подставляется для кнопок старого формата
+        let (action, host) = parse_callback_data(&config, "wol");
+        assert_eq!(action, "wol");
+        assert_eq!(host.map(|h| h.id.as_str()), Some("server"));
+
+        config.hosts.push(test_host("nas", "AA:BB:CC:DD:EE:FF"));
+
+        let (action, host) = parse_callback_data(&config, "status:nas");
+        assert_eq!(action, "status");
+        assert_eq!(host.map(|h| h.id.as_str()), Some("nas"));
+
+        let (_, host) = parse_callback_data(&config, "wol");
+        assert!(host.is_none(), "Без хоста при нескольких машинах действие неоднозначно");
+
+        let (_, host) = parse_callback_data(&config, "wol:unknown");
+        assert!(host.is_none());
+
+        // После ':' у запросов и ошибок стоит номер, а не хост — даже если есть хост с таким id
+        config.hosts.push(test_host("5", "AA:BB:CC:DD:EE:05"));
+        let (_, host) = parse_callback_data(&config, "status:5");
+        assert_eq!(host.map(|h| h.id.as_str()), Some("5"));
+        let ids = [
+            "details:5", "approval_ok:5", "approval_no:5", "approval_cancel:5", "hostkey_ok:5", "hostkey_no:5",
+            "access_ok:5", "access_no:5", "access_role:5:viewer",
+        ];
+        for data in ids {
+            let (action, host) = parse_callback_data(&config, data);
+            assert_eq!(action, data.split(':').next().unwrap());
+            assert!(host.is_none(), "{}: номер принят за хост", data);
+        }
+        config.hosts.truncate(1);
+        let (action, host) = parse_callback_data(&config, "details");
+        assert_eq!(action, "details");
+        assert!(host.is_none());
+
+        println!("✅ Разбор callback data работает корректно");
+    }
+
+    #[test]
+    fn test_host_id_validation() {
+        assert!(is_valid_host_id("server"));
+        assert!(is_valid_host_id("build-box_2"));
+        assert!(!is_valid_host_id(""));
+        assert!(!is_valid_host_id("NAS"));
+        assert!(!is_valid_host_id("nas:1"));
+        assert!(!is_valid_host_id(&"a".repeat(33)));
+
+        println!("✅ Валидация идентификаторов хостов работает корректно");
+    }
+
     #[test]
     fn test_callback_data_recognition() {
         // Тестируем распознавание всех callback данных
         let test_cases = vec![
-            ("wol", "Wake on LAN"),
-            ("shutdown_confirm", "Shutdown confirmation"),
-            ("shutdown_yes", "Shutdown execution"),
-            ("status", "Status check"),
-            ("cancel", "Cancel operation"),
+            ("wol:server", "Wake on LAN"),
+            ("shutdown_confirm:server", "Shutdown confirmation"),
+            ("shutdown_yes:server", "Shutdown execution"),
+            ("status:server", "Status check"),
+            ("cancel:server", "Cancel operation"),
+            ("host:server", "Host selection"),
+            ("hosts", "Host list"),
         ];
 
         for (callback_data, description) in test_cases {
@@ -209,10 +349,10 @@ mod tests {
         println!("  User ID: {:?}", user_id);
         println!("  Is allowed: {}", is_allowed(&config, user_id));
         println!("  Text: {:?}", msg.text());
-        println!("  Starts with /start: {}", msg.text().map_or(false, |t| t.starts_with("/start")));
+        println!("  Starts with /start: {}", msg.text().is_some_and(|t| t.starts_with("/start")));
         
         // Симулируем обработку CallbackQuery
-        let callback_query = test_callback_query("wol");
+        let callback_query = test_callback_query("wol:server");
         let callback_user_id = Some(callback_query.from.id.0);
         
         println!("\nCallbackQuery обработка:");
@@ -222,12 +362,12 @@ mod tests {
         
         // Проверяем матчинг callback данных
         if let Some(data) = callback_query.data.as_deref() {
-            let handler_found = match data {
-                "wol" => { println!("  ✅ WOL handler найден"); true },
-                "shutdown_confirm" => { println!("  ✅ Shutdown confirm handler найден"); true },
-                "shutdown_yes" => { println!("  ✅ Shutdown yes handler найден"); true },
-                "status" => { println!("  ✅ Status handler найден"); true },
-                "cancel" => { println!("  ✅ Cancel handler найден"); true },
+            let handler_found = match parse_callback_data(&config, data) {
+                ("wol", Some(_)) => { println!("  ✅ WOL handler найден"); true },
+                ("shutdown_confirm", Some(_)) => { println!("  ✅ Shutdown confirm handler найден"); true },
+                ("shutdown_yes", Some(_)) => { println!("  ✅ Shutdown yes handler найден"); true },
+                ("status", Some(_)) => { println!("  ✅ Status handler найден"); true },
+                ("cancel", Some(_)) => { println!("  ✅ Cancel handler найден"); true },
                 _ => { println!("  ❌ Handler НЕ найден для: {}", data); false },
             };
             assert!(handler_found, "Handler должен быть найден для всех callback данных");
@@ -242,7 +382,8 @@ mod tests {
         
         assert!(!config.bot_token.is_empty());
         assert!(!config.allowed_users.is_empty());
-        assert!(!config.server_mac.is_empty());
+        assert!(!config.hosts.is_empty());
+        assert!(config.hosts.iter().all(|h| is_valid_mac(&h.mac)));
         
         println!("✅ Тестовая конфигурация создается корректно");
     }
@@ -310,41 +451,1800 @@ mod tests {
         println!("✅ Функция safe_answer_callback_query определена корректно");
     }
 
+    // Окружение для загрузчика конфигурации без изменения настоящих переменных процесса
+    fn env_map(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        move |key| vars.get(key).cloned()
+    }
+
+    const LEGACY_ENV: [(&str, &str); 11] = [
+        ("BOT_TOKEN", "test_token"),
+        ("ALLOWED_USERS", "123456789, 987654321"),
+        ("SERVER_MAC", "00:11:22:33:44:55"),
+        ("ROUTER_SSH_HOST", "localhost"),
+        ("ROUTER_SSH_PORT", "2223"),
+        ("ROUTER_SSH_USER", "root"),
+        ("ROUTER_SSH_KEY_PATH", "/app/keys/router"),
+        ("SERVER_SSH_HOST", "localhost"),
+        ("SERVER_SSH_PORT", "2222"),
+        ("SERVER_SSH_USER", "admin"),
+        ("SERVER_SSH_KEY_PATH", "/app/keys/id_rsa"),
+    ];
+
     #[test]
     fn test_config_with_invalid_mac() {
-        // Сохраняем оригинальное значение переменной окружения
-        let original_mac = std::env::var("SERVER_MAC").ok();
-        
-        // Устанавливаем невалидный MAC
-        std::env::set_var("SERVER_MAC", "invalid_mac");
-        std::env::set_var("BOT_TOKEN", "test_token");
-        std::env::set_var("ALLOWED_USERS", "123456789");
-        
+        // Все переменные корректны, кроме MAC
+        let mut vars = LEGACY_ENV.to_vec();
+        vars.retain(|(k, _)| *k != "SERVER_MAC");
+        vars.push(("SERVER_MAC", "invalid_mac"));
+
         // Пытаемся создать конфигурацию
-        let result = Config::from_env();
-        
-        // Восстанавливаем оригинальное значение
-        if let Some(mac) = original_mac {
-            std::env::set_var("SERVER_MAC", mac);
-        } else {
-            std::env::remove_var("SERVER_MAC");
-        }
-        
+        let result = Config::from_sources(toml::Table::new(), &env_map(&vars));
+
         // Проверяем что конфигурация не создалась с невалидным MAC
         assert!(result.is_err(), "Конфигурация не должна создаваться с невалидным MAC");
+        let err = result.err().unwrap().to_string();
+        assert!(err.contains("hosts.server.mac (из SERVER_MAC)"), "Ошибка должна указывать путь: {}", err);
         
         println!("✅ Валидация MAC в конфигурации работает корректно");
     }
 
+    #[test]
+    fn test_config_from_legacy_env() {
+        let config = Config::from_sources(toml::Table::new(), &env_map(&LEGACY_ENV)).unwrap();
+
+        assert_eq!(config.allowed_users, vec![123456789, 987654321]);
+        assert_eq!(config.hosts.len(), 1);
+        let host = &config.hosts[0];
+        assert_eq!(host.id, "server");
+        assert_eq!(host.router.as_ref().unwrap().port, 2223);
+        assert_eq!(host.ssh.port, 2222);
+        assert_eq!(host.ssh.user, "admin");
+        assert_eq!(config.ssh_timeouts, ssh::Timeouts::uniform(Duration::from_secs(15)));
+
+        println!("✅ Конфигурация из переменных окружения старого формата загружается");
+    }
+
+    const TEST_TOML: &str = r#"
+        bot_token = "file_token"
+
+        [users.alice]
+        id = 111
+
+        [timeouts]
+        ssh = 20
+        status = 7
+
+        [routers.main]
+        host = "router.lan"
+        user = "root"
+        key = "/keys/router"
+
+        [hosts.build]
+        name = "Build box"
+        mac = "aa:bb:cc:dd:ee:ff"
+        ssh = { host = "build.lan", port = 2200, user = "ci", key = "/keys/build" }
+
+        [hosts.nas]
+        mac = "11-22-33-44-55-66"
+        router = "main"
+        boot_timeout = 300
+        ssh = { host = "nas.lan", user = "admin", key = "/keys/nas" }
+    "#;
+
+    #[test]
+    fn test_config_from_toml() {
+        let table = TEST_TOML.parse::<toml::Table>().unwrap();
+        let config = Config::from_sources(table, &env_map(&[])).unwrap();
+
+        assert_eq!(config.bot_token, "file_token");
+        assert_eq!(config.allowed_users, vec![111]);
+        assert_eq!(config.ssh_timeouts, ssh::Timeouts::uniform(Duration::from_secs(20)));
+        assert_eq!(config.nc_timeout, Duration::from_secs(7));
+
+        // Порядок хостов совпадает с порядком в файле
+        let ids = config.hosts.iter().map(|h| h.id.as_str()).collect::<Vec<_>>();
+        assert_eq!(ids, vec!["build", "nas"]);
+        assert_eq!(config.hosts[0].name, "Build box");
+        assert_eq!(config.hosts[0].ssh.port, 2200);
+        assert_eq!(config.hosts[1].name, "nas");
+        assert_eq!(config.hosts[1].ssh.port, 22);
+        assert_eq!(config.hosts[0].boot_timeout, Duration::from_secs(180));
+        assert_eq!(config.hosts[1].boot_timeout, Duration::from_secs(300));
+        assert_eq!(config.hosts[1].router.as_ref().unwrap().host, "router.lan");
+        assert_eq!(
+            config.hosts[1].wol,
+            WolTransport::Router(RouterCommand {
+                template: CommandTemplate::Etherwake,
+                interface: "br-lan".to_string(),
+            })
+        );
+
+        println!("✅ Конфигурация из TOML загружается корректно");
+    }
+
+    #[test]
+    fn test_config_env_overrides_toml() {
+        let table = TEST_TOML.parse::<toml::Table>().unwrap();
+        let env = env_map(&[
+            ("BOT_TOKEN", "env_token"),
+            ("ALLOWED_USERS", "222"),
+            ("SSH_TIMEOUT", "30"),
+            ("HOSTS", "nas"),
+            ("HOST_NAS_SSH_PORT", "2022"),
+        ]);
+        let config = Config::from_sources(table, &env).unwrap();
+
+        assert_eq!(config.bot_token, "env_token");
+        assert_eq!(config.allowed_users, vec![111, 222]);
+        assert_eq!(config.ssh_timeouts, ssh::Timeouts::uniform(Duration::from_secs(30)));
+        let nas = config.host("nas").unwrap();
+        assert_eq!(nas.ssh.port, 2022);
+        assert_eq!(nas.ssh.host, "nas.lan");
+
+        println!("✅ Переменные окружения переопределяют отдельные ключи");
+    }
+
+    #[test]
+    fn test_config_reports_all_errors() {
+        let table = r#"
+            bot_tokn = "typo"
+
+            [users.bob]
+            id = "not a number"
+
+            [routers.main]
+            host = "router.lan"
+            port = 70000
+            user = "root"
+            key = "/keys/router"
+
+            [hosts.nas]
+            mac = "zz:zz:zz:zz:zz:zz"
+            ssh = { host = "nas.lan", user = "admin" }
+            color = "red"
+        "#
+        .parse::<toml::Table>()
+        .unwrap();
+
+        let err = Config::from_sources(table, &env_map(&[])).err().unwrap().to_string();
+        for expected in [
+            "bot_tokn: неизвестный ключ",
+            "bot_token: обязательный ключ отсутствует",
+            "users.bob.id: ожидалось целое число",
+            "routers.main.port: значение 70000 вне диапазона",
+            "hosts.nas.mac: некорректный MAC-адрес",
+            "hosts.nas.ssh.key: обязательный ключ отсутствует",
+            "hosts.nas.color: неизвестный ключ",
+        ] {
+            assert!(err.contains(expected), "Ожидалась ошибка '{}' в:\n{}", expected, err);
+        }
+
+        println!("✅ Загрузчик сообщает обо всех ошибках сразу");
+    }
+
+    #[test]
+    fn test_config_udp_transport() {
+        let table = r#"
+            bot_token = "t"
+            users.alice.id = 1
+
+            [hosts.desk]
+            mac = "aa:bb:cc:dd:ee:ff"
+            wol = { transport = "udp", broadcast = "192.168.1.255", port = 7 }
+            ssh = { host = "desk.lan", user = "me", key = "/keys/desk" }
+
+            [hosts.bad]
+            mac = "aa:bb:cc:dd:ee:01"
+            wol = { transport = "udp", broadcast = "192.168.1", port = 80 }
+            ssh = { host = "bad.lan", user = "me", key = "/keys/bad" }
+        "#
+        .parse::<toml::Table>()
+        .unwrap();
+
+        let err = Config::from_sources(table.clone(), &env_map(&[])).err().unwrap().to_string();
+        assert!(err.contains("hosts.bad.wol.broadcast: некорректный IPv4-адрес"), "{}", err);
+        assert!(err.contains("hosts.bad.wol.port: порт Wake-on-LAN должен быть 7 или 9"), "{}", err);
+        assert!(!err.contains("hosts.desk"), "UDP-хосту не нужен роутер: {}", err);
+
+        let mut table = table;
+        table["hosts"].as_table_mut().unwrap().remove("bad");
+        let config = Config::from_sources(table, &env_map(&[])).unwrap();
+        assert_eq!(
+            config.hosts[0].wol,
+            WolTransport::Udp(UdpSender { broadcast: "192.168.1.255".parse().unwrap(), port: 7 })
+        );
+        assert!(config.hosts[0].router.is_none());
+
+        println!("✅ UDP-транспорт Wake-on-LAN настраивается корректно");
+    }
+
+    #[test]
+    fn test_magic_packet() {
+        let mac = wol::parse_mac("00-11-22-AA-bb-FF").unwrap();
+        assert_eq!(mac, [0x00, 0x11, 0x22, 0xAA, 0xBB, 0xFF]);
+        assert!(wol::parse_mac("00:11:22:33:44").is_err());
+        assert!(wol::parse_mac("00:11:22:33:44:5G").is_err());
+
+        let packet = wol::magic_packet(&mac, None);
+        assert_eq!(packet.len(), 102);
+        assert_eq!(&packet[..6], &[0xFF; 6]);
+        for chunk in packet[6..].chunks(6) {
+            assert_eq!(chunk, &mac);
+        }
+
+        println!("✅ Magic packet формируется корректно");
+    }
+
+    #[test]
+    fn test_router_command_templates() {
+        let mac = "AA-BB-CC-DD-EE-FF";
+        let cases = [
+            (CommandTemplate::Etherwake, "etherwake -i eth1 aa:bb:cc:dd:ee:ff"),
+            (CommandTemplate::EtherWake, "ether-wake -i eth1 aa:bb:cc:dd:ee:ff"),
+            (CommandTemplate::Wakeonlan, "wakeonlan aa:bb:cc:dd:ee:ff"),
+            (CommandTemplate::Mikrotik, "/tool wol interface=eth1 mac=aa:bb:cc:dd:ee:ff"),
+            (
+                CommandTemplate::Ubus,
+                r#"ubus call file exec '{"command":"/usr/bin/etherwake","params":["-i","eth1","aa:bb:cc:dd:ee:ff"]}'"#,
+            ),
+            (
+                CommandTemplate::Custom("/opt/wake --if={interface} {mac}".to_string()),
+                "/opt/wake --if=eth1 aa:bb:cc:dd:ee:ff",
+            ),
+        ];
+        for (template, expected) in cases {
+            assert_eq!(template.render(mac, "eth1", None).unwrap(), expected);
+        }
+
+        // Значения, которые могли бы сломать команду, отклоняются
+        assert!(CommandTemplate::Etherwake.render(mac, "br-lan; reboot", None).is_err());
+        assert!(CommandTemplate::Etherwake.render("aa:bb:cc:dd:ee:ff;id", "br-lan", None).is_err());
+
+        // Проверка пользовательских шаблонов
+        assert!(CommandTemplate::from_name("custom", Some("wake {interface}".into())).is_err());
+        assert!(CommandTemplate::from_name("custom", Some("wake {mac} {host}".into())).is_err());
+        assert!(CommandTemplate::from_name("custom", None).is_err());
+        assert!(CommandTemplate::from_name("tplink", None).is_err());
+
+        println!("✅ Шаблоны команд Wake-on-LAN формируются безопасно");
+    }
+
+    #[test]
+    fn test_config_wol_command() {
+        let table = r#"
+            bot_token = "t"
+            users.alice.id = 1
+            routers.mt = { host = "mt.lan", user = "admin", key = "/keys/mt" }
+
+            [hosts.desk]
+            mac = "aa:bb:cc:dd:ee:ff"
+            wol = { command = "mikrotik", interface = "bridge1" }
+            ssh = { host = "desk.lan", user = "me", key = "/keys/desk" }
+
+            [hosts.bad]
+            mac = "aa:bb:cc:dd:ee:01"
+            wol = { command = "custom", template = "wake {host}", interface = "br lan", broadcast = "1.2.3.4" }
+            ssh = { host = "bad.lan", user = "me", key = "/keys/bad" }
+
+            [hosts.nobridge]
+            mac = "aa:bb:cc:dd:ee:02"
+            wol = { command = "mikrotik" }
+            ssh = { host = "nobridge.lan", user = "me", key = "/keys/nobridge" }
+        "#
+        .parse::<toml::Table>()
+        .unwrap();
+
+        let err = Config::from_sources(table.clone(), &env_map(&[])).err().unwrap().to_string();
+        assert!(err.contains("hosts.bad.wol.template: неизвестная подстановка"), "{}", err);
+        assert!(err.contains("hosts.bad.wol.interface: некорректное имя интерфейса"), "{}", err);
+        assert!(err.contains("hosts.bad.wol.broadcast: используется только с transport = \"udp\""), "{}", err);
+        // У RouterOS нет общего имени моста, br-lan из OpenWrt туда не подставляется
+        assert!(err.contains("hosts.nobridge.wol.interface: для command = \"mikrotik\" интерфейс нужно указать явно"), "{}", err);
+
+        let mut table = table;
+        table["hosts"].as_table_mut().unwrap().remove("bad");
+        table["hosts"].as_table_mut().unwrap().remove("nobridge");
+        let config = Config::from_sources(table, &env_map(&[("HOST_DESK_WOL_INTERFACE", "ether2")])).unwrap();
+        assert_eq!(
+            config.hosts[0].wol,
+            WolTransport::Router(RouterCommand {
+                template: CommandTemplate::Mikrotik,
+                interface: "ether2".to_string(),
+            })
+        );
+
+        println!("✅ Команда Wake-on-LAN настраивается для каждого хоста");
+    }
+
+    #[test]
+    fn test_secureon_password() {
+        let short = SecureOn::parse("192.168.1.1").unwrap();
+        assert_eq!(short.bytes(), &[192, 168, 1, 1]);
+        assert_eq!(short.to_string(), "192.168.1.1");
+
+        let long = SecureOn::parse("00-11-22-AA-BB-CC").unwrap();
+        assert_eq!(long.bytes(), &[0x00, 0x11, 0x22, 0xAA, 0xBB, 0xCC]);
+        assert_eq!(long.to_string(), "00:11:22:aa:bb:cc");
+
+        for invalid in ["", "secret", "1.2.3", "00:11:22:33:44", "1.2.3.4; reboot"] {
+            assert!(SecureOn::parse(invalid).is_err(), "Пароль '{}' должен быть отклонён", invalid);
+        }
+
+        // Пароль дописывается в конец magic packet
+        let mac = [0xAA; 6];
+        assert_eq!(wol::magic_packet(&mac, Some(&short)).len(), 106);
+        let packet = wol::magic_packet(&mac, Some(&long));
+        assert_eq!(packet.len(), 108);
+        assert_eq!(&packet[102..], long.bytes());
+
+        // И передаётся командам роутера через -p
+        let mac = "aa:bb:cc:dd:ee:ff";
+        assert_eq!(
+            CommandTemplate::Etherwake.render(mac, "br-lan", Some(&long)).unwrap(),
+            "etherwake -i br-lan -p 00:11:22:aa:bb:cc aa:bb:cc:dd:ee:ff"
+        );
+        assert_eq!(
+            CommandTemplate::Custom("wake {mac} {password}".into()).render(mac, "br-lan", Some(&short)).unwrap(),
+            "wake aa:bb:cc:dd:ee:ff 192.168.1.1"
+        );
+        assert!(CommandTemplate::Mikrotik.render(mac, "ether1", Some(&short)).is_err());
+        assert!(CommandTemplate::Custom("wake {mac} {password}".into()).render(mac, "br-lan", None).is_err());
+
+        println!("✅ Пароль SecureOn проверяется и передаётся корректно");
+    }
+
+    #[test]
+    fn test_config_secureon() {
+        let table = r#"
+            bot_token = "t"
+            users.alice.id = 1
+            routers.main = { host = "r.lan", user = "root", key = "/keys/r" }
+
+            [hosts.good]
+            mac = "aa:bb:cc:dd:ee:01"
+            secureon = "01:02:03:04:05:06"
+            ssh = { host = "good.lan", user = "me", key = "/keys/good" }
+
+            [hosts.udp]
+            mac = "aa:bb:cc:dd:ee:02"
+            secureon = "10.0.0.1"
+            wol = { transport = "udp" }
+            ssh = { host = "udp.lan", user = "me", key = "/keys/udp" }
+
+            [hosts.mikrotik]
+            mac = "aa:bb:cc:dd:ee:03"
+            secureon = "10.0.0.1"
+            wol = { command = "mikrotik", interface = "bridge" }
+            ssh = { host = "mt.lan", user = "me", key = "/keys/mt" }
+
+            [hosts.garbage]
+            mac = "aa:bb:cc:dd:ee:04"
+            secureon = "hunter2"
+            ssh = { host = "g.lan", user = "me", key = "/keys/g" }
+        "#
+        .parse::<toml::Table>()
+        .unwrap();
+
+        let err = Config::from_sources(table.clone(), &env_map(&[])).err().unwrap().to_string();
+        assert!(err.contains("hosts.mikrotik.secureon: выбранная команда роутера не поддерживает"), "{}", err);
+        assert!(err.contains("hosts.garbage.secureon: пароль SecureOn должен быть"), "{}", err);
+        assert!(!err.contains("hosts.good") && !err.contains("hosts.udp"), "{}", err);
+
+        let mut table = table;
+        let hosts = table["hosts"].as_table_mut().unwrap();
+        hosts.remove("mikrotik");
+        hosts.remove("garbage");
+        let config = Config::from_sources(table, &env_map(&[])).unwrap();
+        assert_eq!(config.host("good").unwrap().secureon.as_ref().unwrap().bytes().len(), 6);
+        assert_eq!(config.host("udp").unwrap().secureon.as_ref().unwrap().bytes().len(), 4);
+
+        println!("✅ Пароль SecureOn проверяется при загрузке конфигурации");
+    }
+
+    #[test]
+    fn test_boot_progress_text() {
+        let mut host = test_host("server", "00:11:22:33:44:55");
+        let deadline = Duration::from_secs(300);
+
+        let text = boot_progress_text(&host, 1, Duration::from_secs(20), deadline, Some(&HostState::Offline), false);
+        assert!(text.contains("(попытка 1 из 3)"), "{}", text);
+        assert!(text.contains("20с из 300с"), "{}", text);
+        assert!(!text.contains("SSH-порт открыт"));
+
+        let text = boot_progress_text(&host, 2, Duration::from_secs(65), deadline, Some(&HostState::PortOpen { reason: "таймаут".to_string() }), true);
+        assert!(text.contains("(попытка 2 из 3)"), "{}", text);
+        assert!(text.contains("SSH-порт открыт"), "{}", text);
+        assert!(text.contains("Не удалось повторно отправить"), "{}", text);
+
+        // Без повторов номер попытки не показываем
+        host.retry.attempts = 1;
+        let text = boot_progress_text(&host, 1, Duration::from_secs(5), deadline, None, false);
+        assert!(!text.contains("попытка"), "{}", text);
+
+        println!("✅ Прогресс загрузки отображается корректно");
+    }
+
+    #[test]
+    fn test_wake_retry_schedule() {
+        let retry = WakeRetry {
+            attempts: 3,
+            grace_period: Duration::from_secs(45),
+            interval: Duration::from_secs(30),
+        };
+        assert_eq!(
+            retry.schedule(),
+            vec![Duration::ZERO, Duration::from_secs(45), Duration::from_secs(75)]
+        );
+        assert_eq!(retry.deadline(Duration::from_secs(120)), Duration::from_secs(195));
+
+        let single = WakeRetry { attempts: 1, ..retry };
+        assert_eq!(single.schedule(), vec![Duration::ZERO]);
+        assert_eq!(single.deadline(Duration::from_secs(120)), Duration::from_secs(120));
+
+        // Админов оповещаем и после единственной попытки
+        let host = test_host("desk", "aa:bb:cc:dd:ee:ff");
+        let alert = boot_failure_alert(&host, 1, 42);
+        assert!(alert.contains("Test desk не включился после Wake-on-LAN."), "{}", alert);
+        assert!(alert.contains("Запросил пользователь 42"));
+        assert!(boot_failure_alert(&host, 3, 42).contains("после 3 попыток Wake-on-LAN"));
+
+        println!("✅ Расписание повторных magic packet рассчитывается корректно");
+    }
+
+    #[test]
+    fn test_config_retry_and_admins() {
+        let table = r#"
+            bot_token = "t"
+            users.alice = { id = 1, admin = true }
+            users.bob = { id = 2 }
+
+            [hosts.desk]
+            mac = "aa:bb:cc:dd:ee:ff"
+            wol = { transport = "udp", attempts = 5, grace_period = 30 }
+            ssh = { host = "desk.lan", user = "me", key = "/keys/desk" }
+        "#
+        .parse::<toml::Table>()
+        .unwrap();
+
+        let config = Config::from_sources(table.clone(), &env_map(&[("ADMIN_USERS", "3")])).unwrap();
+        assert_eq!(config.allowed_users, vec![1, 2, 3]);
+        assert_eq!(config.admins, vec![1, 3]);
+        // Отметки админов без других ролей не отбирают права у остальных
+        assert_eq!(access::user_role(&config, 2), Some(Role::Admin));
+        assert!(access::allows(&config, 2, Permission::Shutdown));
+        assert!(config.warnings.is_empty(), "{:?}", config.warnings);
+        let retry = &config.hosts[0].retry;
+        assert_eq!(retry.attempts, 5);
+        assert_eq!(retry.grace_period, Duration::from_secs(30));
+        assert_eq!(retry.interval, WakeRetry::default().interval);
+
+        let env = env_map(&[("HOST_DESK_WOL_ATTEMPTS", "0")]);
+        let err = Config::from_sources(table, &env).err().unwrap().to_string();
+        assert!(err.contains("hosts.desk.wol.attempts (из HOST_DESK_WOL_ATTEMPTS): значение 0 вне диапазона"), "{}", err);
+
+        println!("✅ Повторы Wake-on-LAN и админы настраиваются корректно");
+    }
+
+    #[test]
+    fn test_remote_command_errors() {
+        let err = anyhow::Error::new(RemoteCommandError {
+            command: "sudo -n /sbin/shutdown -h now".to_string(),
+            output: CommandOutput {
+                stdout: String::new(),
+                stderr: "\nsudo: a password is required\nsecond line\n".to_string(),
+                exit_status: 1,
+            },
+        });
+        let (summary, details) = describe_error(&err);
+        assert_eq!(summary, "sudo: a password is required (код 1)");
+        assert!(details.contains("Команда: sudo -n /sbin/shutdown -h now"));
+        assert!(details.contains("Код завершения: 1"));
+        assert!(details.contains("second line"));
+
+        // Канал без кода завершения — не успех, а отдельная ошибка
+        let err = anyhow::Error::new(ssh::NoExitStatus { command: "uptime".to_string() });
+        let (summary, _) = describe_error(&err);
+        assert_eq!(summary, "'uptime': сервер закрыл канал, не сообщив код завершения");
+
+        let err = anyhow::anyhow!("connection refused").context("SSH connection failed");
+        let (summary, details) = describe_error(&err);
+        assert_eq!(summary, "connection refused");
+        assert_eq!(details, "SSH connection failed: connection refused");
+
+        assert_eq!(sanitize_for_chat("a\x1b[31mb\r\nc", 100), "a[31mb\nc");
+        assert_eq!(sanitize_for_chat("абвгд", 3), "абв…");
+
+        let config = test_config();
+        let kb = failure_keyboard(&config, &config.hosts[0], 123456789, 42);
+        assert_eq!(kb.inline_keyboard[0][0].text, "🔍 Подробнее");
+        assert!(matches!(
+            &kb.inline_keyboard[0][0].kind,
+            teloxide::types::InlineKeyboardButtonKind::CallbackData(data) if data == "details:42"
+        ));
+
+        println!("✅ Ошибки удалённых команд описываются корректно");
+    }
+
+    #[test]
+    fn test_host_key_settings() {
+        let config = Config::from_sources(
+            TEST_TOML.parse::<toml::Table>().unwrap(),
+            &env_map(&[]),
+        )
+        .unwrap();
+        assert_eq!(config.host_keys, HostKeyPolicy::default());
+        assert!(!config.host_keys.trust_on_first_use);
+
+        let env = env_map(&[
+            ("KNOWN_HOSTS_PATH", "/data/known_hosts"),
+            ("SSH_TRUST_ON_FIRST_USE", "true"),
+        ]);
+        let config = Config::from_sources(TEST_TOML.parse::<toml::Table>().unwrap(), &env).unwrap();
+        assert_eq!(config.host_keys.known_hosts, "/data/known_hosts");
+        assert!(config.host_keys.trust_on_first_use);
+
+        let env = env_map(&[("SSH_TRUST_ON_FIRST_USE", "yes")]);
+        let err = Config::from_sources(TEST_TOML.parse::<toml::Table>().unwrap(), &env)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("known_hosts.trust_on_first_use (из SSH_TRUST_ON_FIRST_USE)"), "{}", err);
+
+        assert_eq!(ssh::known_hosts_name("router.lan", 22), "router.lan");
+        assert_eq!(ssh::known_hosts_name("localhost", 2222), "[localhost]:2222");
+        assert_eq!(ssh::fingerprint(&[0xFF; 32]), "SHA256://////////////////////////////////////////8");
+
+        let err = ssh::HostKeyError::Mismatch {
+            host: "[localhost]:2222".to_string(),
+            fingerprint: "SHA256:abc".to_string(),
+        };
+        assert_eq!(
+            err.to_string(),
+            "ключ хоста [localhost]:2222 изменился (SHA256:abc), возможна подмена сервера"
+        );
+
+        // Ключи на подтверждении хранятся в базе: переживают перезапуск, номера не повторяются
+        let dir = std::env::temp_dir().join(format!("wol-bot-hostkeys-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let db = dir.join("bot.db");
+        let policy = HostKeyPolicy {
+            known_hosts: dir.join("known_hosts").to_str().unwrap().to_string(),
+            trust_on_first_use: true,
+        };
+        let key = russh::keys::PublicKey::from_openssh(
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIIVjsd6ol13inJ4gkGf/vf9VqNhnd6N8clXBki5bNFbO",
+        )
+        .unwrap();
+
+        let store = Store::open(db.to_str().unwrap()).unwrap();
+        let first = ssh::remember_host_key(&store, "localhost", 2222, &key).unwrap().unwrap();
+        assert_eq!(ssh::remember_host_key(&store, "localhost", 2222, &key).unwrap(), None);
+        drop(store);
+
+        let store = Store::open(db.to_str().unwrap()).unwrap();
+        let (host, fingerprint) = ssh::reject_host_key(&store, first).unwrap().unwrap();
+        assert_eq!(host, "[localhost]:2222");
+        assert!(fingerprint.starts_with("SHA256:"), "{}", fingerprint);
+        assert_eq!(ssh::reject_host_key(&store, first).unwrap(), None);
+
+        let second = ssh::remember_host_key(&store, "localhost", 2222, &key).unwrap().unwrap();
+        assert!(second > first);
+        // Старая кнопка не может решить судьбу нового запроса
+        assert!(ssh::approve_host_key(&store, &policy, first).is_err());
+        assert!(!dir.join("known_hosts").exists());
+
+        assert_eq!(ssh::approve_host_key(&store, &policy, second).unwrap().0, "[localhost]:2222");
+        assert!(russh::keys::check_known_hosts_path("localhost", 2222, &key, &policy.known_hosts).unwrap());
+        assert!(ssh::approve_host_key(&store, &policy, second).is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+
+        println!("✅ Проверка ключей хостов настраивается корректно");
+    }
+
+    #[tokio::test]
+    async fn test_host_resolution() {
+        let timeout = Duration::from_secs(5);
+
+        assert_eq!(ssh::strip_brackets("[fd00::1]"), "fd00::1");
+        assert_eq!(ssh::strip_brackets("router.lan"), "router.lan");
+
+        let addrs = ssh::resolve("[::1]", 2222, timeout).await.unwrap();
+        assert_eq!(addrs, vec!["[::1]:2222".parse().unwrap()]);
+        let addrs = ssh::resolve("192.168.1.1", 22, timeout).await.unwrap();
+        assert_eq!(addrs, vec!["192.168.1.1:22".parse().unwrap()]);
+
+        // Имена разрешаются по-настоящему, а не через addr.parse()
+        let addrs = ssh::resolve("localhost", 22, timeout).await.unwrap();
+        assert!(addrs.iter().all(|a| a.ip().is_loopback() && a.port() == 22), "{:?}", addrs);
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        assert!(ssh::connect_tcp("localhost", port, timeout).await.is_ok());
+        drop(listener);
+
+        let err = ssh::connect_tcp("no-such-host.invalid", 22, timeout).await.err().unwrap().to_string();
+        assert!(err.contains("no-such-host.invalid"), "{}", err);
+        let err = ssh::connect_tcp("127.0.0.1", port, timeout).await.err().unwrap().to_string();
+        assert!(err.contains(&format!("127.0.0.1 порт {}", port)), "{}", err);
+
+        println!("✅ Имена хостов разрешаются корректно");
+    }
+
+    #[tokio::test]
+    async fn test_ssh_exec_reports_unreachable_host() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+
+        let config = test_config();
+        let target = SshTarget {
+            host: "127.0.0.1".to_string(),
+            port,
+            user: "admin".to_string(),
+            key: Some("/nonexistent".to_string()),
+            passphrase_file: None,
+            auth: vec![ssh::AuthMethod::Key],
+            jump: vec![],
+        };
+        let err = ssh::exec(&config, &target, "uptime", "uptime").await.err().unwrap();
+        assert!(err.to_string().contains(&format!("127.0.0.1 порт {}", port)), "{}", err);
+
+        // Неудачное подключение не оставляет сессий в пуле, обслуживание пустого пула безопасно
+        ssh::maintain_pool();
+
+        println!("✅ Асинхронный SSH сообщает о недоступном хосте");
+    }
+
+    #[tokio::test]
+    async fn test_ssh_phase_timeouts() {
+        let table = TEST_TOML.parse::<toml::Table>().unwrap();
+        let env = env_map(&[("SSH_TIMEOUT", "20"), ("SSH_EXEC_TIMEOUT", "60")]);
+        let config = Config::from_sources(table, &env).unwrap();
+        assert_eq!(
+            config.ssh_timeouts,
+            ssh::Timeouts {
+                connect: Duration::from_secs(20),
+                handshake: Duration::from_secs(20),
+                auth: Duration::from_secs(20),
+                exec: Duration::from_secs(60),
+            }
+        );
+
+        // Сервер принимает TCP, но молчит — должно сработать ограничение рукопожатия
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let silent = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            std::thread::sleep(Duration::from_secs(3));
+            drop(stream);
+        });
+
+        let mut config = test_config();
+        config.ssh_timeouts.handshake = Duration::from_secs(1);
+        let target = SshTarget {
+            host: "127.0.0.1".to_string(),
+            port,
+            user: "admin".to_string(),
+            key: Some("/nonexistent".to_string()),
+            passphrase_file: None,
+            auth: vec![ssh::AuthMethod::Key],
+            jump: vec![],
+        };
+        let started = std::time::Instant::now();
+        let err = ssh::exec(&config, &target, "uptime", "uptime").await.err().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(3));
+        let timeout = err.downcast_ref::<ssh::PhaseTimeout>().expect("ожидался таймаут этапа");
+        assert_eq!(timeout.phase, ssh::Phase::Handshake);
+        assert_eq!(
+            err.to_string(),
+            format!("рукопожатие: 127.0.0.1:{} не ответил за 1 с", port)
+        );
+        silent.join().unwrap();
+
+        println!("✅ Таймауты этапов SSH срабатывают и называют этап");
+    }
+
+    #[test]
+    fn test_jump_host_chains() {
+        let toml = r#"
+            bot_token = "t"
+            users.alice = { id = 1 }
+
+            [jump_hosts.bastion]
+            host = "bastion.example.com"
+            user = "jump"
+            key = "/keys/bastion"
+
+            [jump_hosts.gw]
+            host = "10.0.0.1"
+            port = 2200
+            user = "gw"
+            key = "/keys/gw"
+
+            [routers.main]
+            host = "192.168.1.1"
+            user = "root"
+            key = "/keys/router"
+            jump = ["bastion"]
+
+            [hosts.server]
+            mac = "aa:bb:cc:dd:ee:ff"
+            ssh = { host = "192.168.1.10", user = "me", key = "/keys/server", jump = ["bastion", "gw"] }
+        "#;
+
+        let config = Config::from_sources(toml.parse::<toml::Table>().unwrap(), &env_map(&[])).unwrap();
+        let host = &config.hosts[0];
+        let hops = host.ssh.jump.iter().map(|h| (h.host.as_str(), h.port, h.user.as_str())).collect::<Vec<_>>();
+        assert_eq!(hops, vec![("bastion.example.com", 22, "jump"), ("10.0.0.1", 2200, "gw")]);
+        assert_eq!(host.router.as_ref().unwrap().jump.len(), 1);
+
+        // Из окружения цепочка задаётся строкой через запятую
+        let env = env_map(&[("HOST_SERVER_SSH_JUMP", "gw")]);
+        let config = Config::from_sources(toml.parse::<toml::Table>().unwrap(), &env).unwrap();
+        assert_eq!(config.hosts[0].ssh.jump[0].host, "10.0.0.1");
+
+        let broken = toml
+            .replace(r#"jump = ["bastion"]"#, r#"jump = ["nowhere"]"#)
+            .replace(r#"key = "/keys/gw""#, "key = \"/keys/gw\"\njump = [\"bastion\"]");
+        let err = Config::from_sources(broken.parse::<toml::Table>().unwrap(), &env_map(&[]))
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("routers.main.jump: промежуточный хост 'nowhere' не описан в [jump_hosts]"), "{}", err);
+        assert!(err.contains("jump_hosts.gw.jump: у промежуточного хоста не может быть своей цепочки"), "{}", err);
+
+        println!("✅ Цепочки промежуточных хостов разбираются корректно");
+    }
+
+    #[test]
+    fn test_ssh_auth_methods() {
+        let toml = r#"
+            bot_token = "t"
+            users.alice = { id = 1 }
+
+            [routers.main]
+            host = "router.lan"
+            user = "root"
+            auth = ["agent"]
+
+            [hosts.server]
+            mac = "aa:bb:cc:dd:ee:ff"
+            ssh = { host = "server.lan", user = "me", key = "/keys/id_ed25519", passphrase_file = "/run/secrets/key_pass", auth = ["agent", "key"] }
+        "#;
+
+        let config = Config::from_sources(toml.parse::<toml::Table>().unwrap(), &env_map(&[])).unwrap();
+        let host = &config.hosts[0];
+        assert_eq!(host.ssh.auth, vec![ssh::AuthMethod::Agent, ssh::AuthMethod::Key]);
+        assert_eq!(host.ssh.passphrase_file.as_deref(), Some("/run/secrets/key_pass"));
+        let router = host.router.as_ref().unwrap();
+        assert_eq!(router.auth, vec![ssh::AuthMethod::Agent]);
+        assert_eq!(router.key, None);
+
+        // Без auth вход только по ключу, как раньше
+        let env = env_map(&[("HOST_SERVER_SSH_AUTH", "key")]);
+        let config = Config::from_sources(toml.parse::<toml::Table>().unwrap(), &env).unwrap();
+        assert_eq!(config.hosts[0].ssh.auth, vec![ssh::AuthMethod::Key]);
+
+        let broken = toml
+            .replace(r#"auth = ["agent"]"#, r#"auth = ["agent", "password"]"#)
+            .replace(r#"key = "/keys/id_ed25519", "#, "");
+        let err = Config::from_sources(broken.parse::<toml::Table>().unwrap(), &env_map(&[]))
+            .err()
+            .unwrap()
+            .to_string();
+        for expected in [
+            "routers.main.auth: неизвестный способ 'password', допустимы agent и key",
+            "hosts.server.ssh.key: обязательный ключ отсутствует",
+        ] {
+            assert!(err.contains(expected), "Ожидалась ошибка '{}' в:\n{}", expected, err);
+        }
+
+        let broken = toml.replace(r#"auth = ["agent"]"#, r#"passphrase_file = "/run/secrets/router_pass""#);
+        let broken = broken.replace(r#"user = "root""#, "user = \"root\"\nauth = \"agent\"");
+        let err = Config::from_sources(broken.parse::<toml::Table>().unwrap(), &env_map(&[]))
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("routers.main.passphrase_file: имеет смысл только вместе с key"), "{}", err);
+
+        println!("✅ Способы аутентификации SSH настраиваются корректно");
+    }
+
+    #[test]
+    fn test_tunnels() {
+        let toml = r#"
+            bot_token = "t"
+            users.alice = { id = 1 }
+
+            [tunnels.server]
+            ssh = { host = "vps.example.com", user = "tunnel", key = "/keys/tunnel" }
+            listen = "127.0.0.1:2222"
+            connect = "192.168.1.10:22"
+
+            [tunnels.back]
+            ssh = { host = "vps.example.com", user = "tunnel", key = "/keys/tunnel" }
+            direction = "remote"
+            listen = "[::1]:8022"
+            connect = "localhost:22"
+
+            [hosts.server]
+            mac = "aa:bb:cc:dd:ee:ff"
+            wol = { transport = "udp" }
+            ssh = { host = "localhost", port = 2222, user = "me", key = "/keys/server" }
+        "#;
+
+        let config = Config::from_sources(toml.parse::<toml::Table>().unwrap(), &env_map(&[])).unwrap();
+        let tunnels = config
+            .tunnels
+            .iter()
+            .map(|t| (t.name.as_str(), t.direction, t.listen.clone(), t.connect.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            tunnels,
+            vec![
+                ("server", tunnel::Direction::Local, ("127.0.0.1".to_string(), 2222), ("192.168.1.10".to_string(), 22)),
+                ("back", tunnel::Direction::Remote, ("::1".to_string(), 8022), ("localhost".to_string(), 22)),
+            ]
+        );
+
+        let broken = toml
+            .replace(r#"direction = "remote""#, r#"direction = "sideways""#)
+            .replace(r#"listen = "127.0.0.1:2222""#, r#"listen = "127.0.0.1""#);
+        let err = Config::from_sources(broken.parse::<toml::Table>().unwrap(), &env_map(&[]))
+            .err()
+            .unwrap()
+            .to_string();
+        for expected in [
+            "tunnels.back.direction: неизвестное направление 'sideways'",
+            "tunnels.server.listen: адрес '127.0.0.1' должен быть в виде host:port",
+        ] {
+            assert!(err.contains(expected), "Ожидалась ошибка '{}' в:\n{}", expected, err);
+        }
+
+        assert_eq!(tunnel::format_duration(Duration::from_secs(42)), "42 с");
+        assert_eq!(tunnel::format_duration(Duration::from_secs(7530)), "2 ч 5 мин");
+        assert_eq!(tunnel::describe("server", None), "🟡 server — подключается");
+        let down = tunnel::TunnelState::Down {
+            reason: "подключение: vps.example.com:22 не ответил за 10 с".to_string(),
+            since: std::time::Instant::now(),
+        };
+        assert_eq!(
+            tunnel::describe("server", Some(&down)),
+            "🔴 server — нет соединения 0 с: подключение: vps.example.com:22 не ответил за 10 с"
+        );
+        let event = tunnel::TunnelEvent::Recovered { name: "server".to_string(), downtime: Duration::from_secs(180) };
+        assert_eq!(event.to_string(), "🟢 Туннель server восстановлен после 3 мин");
+
+        println!("✅ Туннели настраиваются и описываются корректно");
+    }
+
+    #[tokio::test]
+    async fn test_layered_status_probe() {
+        use std::io::Write;
+        use std::net::TcpListener;
+
+        // Баннер SSH, молчащий порт и закрытый порт различаются
+        let config = test_config();
+        let target = |port| SshTarget { host: "127.0.0.1".to_string(), port, ..test_config().hosts[0].ssh.clone() };
+        let timeout = Duration::from_millis(300);
+
+        let ssh_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let ssh_port = ssh_listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            let (mut socket, _) = ssh_listener.accept().unwrap();
+            socket.write_all(b"SSH-2.0-Test\r\n").unwrap();
+            std::thread::sleep(Duration::from_millis(500));
+        });
+        assert_eq!(ssh::probe_port(&config, &target(ssh_port), timeout).await, ssh::PortProbe::Banner("SSH-2.0-Test".to_string()));
+
+        let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+        let silent_port = silent.local_addr().unwrap().port();
+        assert!(matches!(ssh::probe_port(&config, &target(silent_port), timeout).await, ssh::PortProbe::Silent(_)));
+
+        let closed_port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        assert!(matches!(ssh::probe_port(&config, &target(closed_port), timeout).await, ssh::PortProbe::Closed(_)));
+
+        // LAN-адрес сервера проверяется только через роутер
+        let toml = r#"
+            bot_token = "t"
+            users.alice = { id = 1 }
+            routers.main = { host = "r.lan", user = "root", key = "/keys/r" }
+
+            [hosts.server]
+            mac = "aa:bb:cc:dd:ee:ff"
+            router = "main"
+            lan_ip = "192.168.1.10"
+            ssh = { host = "localhost", port = 2222, user = "me", key = "/keys/server" }
+        "#;
+        let parsed = Config::from_sources(toml.parse::<toml::Table>().unwrap(), &env_map(&[])).unwrap();
+        assert_eq!(parsed.hosts[0].lan_ip, Some("192.168.1.10".parse().unwrap()));
+        let overridden =
+            Config::from_sources(toml.parse::<toml::Table>().unwrap(), &env_map(&[("HOST_SERVER_LAN_IP", "fd00::10")]))
+                .unwrap();
+        assert_eq!(overridden.hosts[0].lan_ip, Some("fd00::10".parse().unwrap()));
+
+        let broken = toml.replace(r#"router = "main""#, "").replace("192.168.1.10", "192.168.1");
+        let err = Config::from_sources(broken.parse::<toml::Table>().unwrap(), &env_map(&[]))
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("hosts.server.lan_ip: некорректный IP-адрес '192.168.1'"), "{}", err);
+
+        // Вердикт называет отказавшее звено
+        let step = |title: &str, ok: bool| ProbeStep {
+            title: title.to_string(),
+            outcome: if ok { Ok("ok".to_string()) } else { Err("нет".to_string()) },
+        };
+        let probe = |steps, lan| Probe { state: HostState::Offline, steps, lan };
+
+        let tunnel_dead = probe(vec![step("Порт", false), step("Роутер", true)], Some(Ok(true)));
+        assert_eq!(probe_verdict(&tunnel_dead).unwrap(), "Сервер в LAN отвечает — не работает туннель до него.");
+        let powered_off = probe(vec![step("Порт", false), step("Роутер", false)], Some(Ok(false)));
+        assert_eq!(probe_verdict(&powered_off).unwrap(), "Сервер не отвечает и из LAN — похоже, он выключен.");
+        let router_down = probe(vec![step("Порт", false), step("Роутер", false)], Some(Err("таймаут".to_string())));
+        assert_eq!(probe_verdict(&router_down).unwrap(), "Роутер недоступен, проверить сервер из LAN не удалось.");
+        let no_banner = probe(vec![step("Порт", true), step("SSH-баннер", false)], None);
+        assert_eq!(probe_verdict(&no_banner).unwrap(), "Порт открыт, но SSH за ним не отвечает — вероятно, оборван туннель.");
+        assert_eq!(probe_verdict(&probe(vec![step("Порт", false)], None)), None);
+
+        println!("✅ Статус проверяется послойно и называет отказавшее звено");
+    }
+
+    #[test]
+    fn test_status_report() {
+        let command = report::command(&["/".to_string(), "/mnt/it's".to_string()]);
+        assert!(command.contains(r"df -P -k -- '/' '/mnt/it'\''s'"), "{}", command);
+
+        let output = r#"@@uptime
+273600.52 1000000.00
+@@load
+0.15 0.10 0.05 1/234 5678
+@@mem
+MemTotal:       16384000 kB
+MemFree:         1000000 kB
+MemAvailable:   12288000 kB
+SwapTotal:       2097152 kB
+SwapFree:        2097152 kB
+@@disk
+Filesystem     1024-blocks      Used Available Capacity Mounted on
+/dev/sda1        100000000  45000000  55000000      45% /
+/dev/sdb1       1000000000 250000000 750000000      25% /srv/big data
+@@sensors
+{
+   "acpitz-acpi-0": {"Adapter": "ACPI interface", "temp1": {"temp1_input": 71.0}},
+   "coretemp-isa-0000": {
+      "Adapter": "ISA adapter",
+      "Package id 0": {"temp1_input": 52.0, "temp1_max": 100.0},
+      "Core 0": {"temp2_input": 49.0}
+   }
+}
+@@users
+alice    pts/0        2024-01-01 10:00 (10.0.0.1)
+bob      pts/1        2024-01-01 11:00 (10.0.0.2)
+alice    pts/2        2024-01-01 12:00 (10.0.0.1)
+"#;
+        let parsed = report::parse(output);
+        assert_eq!(
+            parsed,
+            StatusReport {
+                uptime: Some(Duration::from_secs(273600)),
+                load: Some([0.15, 0.10, 0.05]),
+                memory: Some(Usage { used: 4096000 * 1024, total: 16384000 * 1024 }),
+                swap: Some(Usage { used: 0, total: 2097152 * 1024 }),
+                disks: vec![
+                    Disk { mount: "/".to_string(), usage: Usage { used: 45000000 * 1024, total: 100000000 * 1024 } },
+                    Disk {
+                        mount: "/srv/big data".to_string(),
+                        usage: Usage { used: 250000000 * 1024, total: 1000000000 * 1024 },
+                    },
+                ],
+                // Температура процессора важнее остальных датчиков, даже если они горячее
+                temperature: Some(52.0),
+                users: vec!["alice".to_string(), "bob".to_string()],
+            }
+        );
+
+        assert_eq!(
+            report::render(&parsed),
+            [
+                "Аптайм              3 д 4 ч",
+                "Нагрузка            0.15 0.10 0.05",
+                "Память               25%  3.9 ГБ из 16 ГБ",
+                "Swap                  0%  0 Б из 2.0 ГБ",
+                "Диск /               45%  43 ГБ из 95 ГБ",
+                "Диск /srv/big data   25%  238 ГБ из 954 ГБ",
+                "CPU                 52 °C",
+                "В системе           alice, bob",
+            ]
+            .join("\n")
+        );
+
+        // Без sensors, swap и пользователей отчёт всё равно собирается
+        let minimal = report::parse("@@uptime\n42.0 1.0\n@@sensors\n@@users\n");
+        assert_eq!(report::render(&minimal), "Аптайм     42 с\nВ системе  никого");
+
+        // Сбой одного раздела не портит отчёт: команда всегда завершается успешно,
+        // а пустой или пропавший раздел пользователей просто не попадает в отчёт
+        assert!(command.ends_with("echo @@users; who 2>/dev/null; true"), "{}", command);
+        let no_users = report::collect("@@uptime\n42.0 1.0\n@@load\n0.15 0.10 0.05 1/234 5678\n").unwrap();
+        assert_eq!(no_users.load, Some([0.15, 0.10, 0.05]));
+        assert!(no_users.users.is_empty());
+        assert_eq!(report::collect("@@uptime\n42.0 1.0\n@@users\n").unwrap().uptime, Some(Duration::from_secs(42)));
+        assert!(report::collect("").is_err());
+        assert!(report::collect("@@uptime\n@@load\n@@users\n").is_err());
+
+        // Точки монтирования задаются в конфиге и окружении
+        let toml = r#"
+            bot_token = "t"
+            users.alice = { id = 1 }
+
+            [hosts.server]
+            mac = "aa:bb:cc:dd:ee:ff"
+            wol = { transport = "udp" }
+            mounts = ["/", "/data"]
+            ssh = { host = "localhost", user = "me", key = "/keys/server" }
+        "#;
+        let config = Config::from_sources(toml.parse::<toml::Table>().unwrap(), &env_map(&[])).unwrap();
+        assert_eq!(config.hosts[0].mounts, vec!["/", "/data"]);
+        let config = Config::from_sources(
+            toml.parse::<toml::Table>().unwrap(),
+            &env_map(&[("HOST_SERVER_MOUNTS", "/, /srv")]),
+        )
+        .unwrap();
+        assert_eq!(config.hosts[0].mounts, vec!["/", "/srv"]);
+        let err = Config::from_sources(toml.replace("/data", "data").parse::<toml::Table>().unwrap(), &env_map(&[]))
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("hosts.server.mounts: путь 'data' должен быть абсолютным"), "{}", err);
+
+        println!("✅ Отчёт о состоянии разбирается и выводится выровненной таблицей");
+    }
+
+    #[test]
+    fn test_monitor_transitions() {
+        // Первый опрос задаёт исходное состояние, одиночный сбой не считается переходом
+        let no_ssh = || Reachability::NoSsh { reason: "аутентификация не удалась".to_string() };
+        let mut debouncer = Debouncer::new(2);
+        assert_eq!(debouncer.observe(Reachability::Online), None);
+        assert_eq!(debouncer.observe(Reachability::Offline), None);
+        assert_eq!(debouncer.observe(Reachability::Online), None);
+        assert_eq!(debouncer.observe(Reachability::Offline), None);
+        let (previous, _) = debouncer.observe(Reachability::Offline).unwrap();
+        assert_eq!(previous, Reachability::Online);
+        assert_eq!(debouncer.observe(Reachability::Offline), None);
+
+        // Смена причины отказа SSH — не новый переход
+        assert_eq!(debouncer.observe(no_ssh()), None);
+        assert!(debouncer.observe(no_ssh()).is_some());
+        let other_reason = Reachability::NoSsh { reason: "код 255".to_string() };
+        assert_eq!(debouncer.observe(other_reason.clone()), None);
+        assert_eq!(debouncer.observe(other_reason), None);
+
+        let mut immediate = Debouncer::new(1);
+        immediate.observe(Reachability::Offline);
+        assert!(immediate.observe(Reachability::Online).is_some());
+
+        // Хост, который видит роутер, но не видно через туннель, — «в сети без SSH»
+        let probe = Probe { state: HostState::Offline, steps: vec![], lan: Some(Ok(true)) };
+        assert!(matches!(Reachability::from_probe(&probe), Reachability::NoSsh { .. }));
+        let probe = Probe { state: HostState::Offline, steps: vec![], lan: Some(Ok(false)) };
+        assert_eq!(Reachability::from_probe(&probe), Reachability::Offline);
+
+        let event = |state, previous| MonitorEvent {
+            host_id: "build".to_string(),
+            host: "Build box".to_string(),
+            state,
+            previous,
+            lasted: Duration::from_secs(300),
+        };
+        assert_eq!(
+            event(Reachability::Online, Reachability::Offline).to_string(),
+            "🟢 Build box снова онлайн (был недоступен 5 мин)"
+        );
+        assert_eq!(
+            event(Reachability::Offline, Reachability::Online).to_string(),
+            "🔴 Build box перестал отвечать (был в сети 5 мин)"
+        );
+        assert_eq!(
+            event(no_ssh(), Reachability::Online).to_string(),
+            "🟡 Build box в сети, но SSH недоступен: аутентификация не удалась"
+        );
+
+        // Период опроса, подтверждения и подписчики из конфига и окружения
+        let toml = r#"
+            bot_token = "t"
+            users.alice = { id = 1, notify = true }
+            users.bob = { id = 2 }
+            monitor = { interval = 30, confirmations = 3 }
+
+            [hosts.server]
+            mac = "aa:bb:cc:dd:ee:ff"
+            wol = { transport = "udp" }
+            monitor_interval = 0
+            ssh = { host = "localhost", user = "me", key = "/keys/server" }
+        "#;
+        let config = Config::from_sources(toml.parse::<toml::Table>().unwrap(), &env_map(&[("NOTIFY_USERS", "3")])).unwrap();
+        assert_eq!(config.monitor, monitor::MonitorConfig { interval: Duration::from_secs(30), confirmations: 3 });
+        assert_eq!(config.subscribers, vec![1, 3]);
+        assert_eq!(config.hosts[0].monitor_interval, Some(Duration::ZERO));
+        assert!(config.allowed_users.contains(&3));
+
+        // Без настроек фоновый опрос выключен
+        let quiet = r#"
+            bot_token = "t"
+            users.alice = { id = 1 }
+
+            [hosts.server]
+            mac = "aa:bb:cc:dd:ee:ff"
+            wol = { transport = "udp" }
+            ssh = { host = "localhost", user = "me", key = "/keys/server" }
+        "#;
+        let config = Config::from_sources(quiet.parse::<toml::Table>().unwrap(), &env_map(&[])).unwrap();
+        assert!(config.monitor.interval.is_zero());
+        assert_eq!(config.hosts[0].monitor_interval, None);
+
+        let err = Config::from_sources(
+            toml.replace("confirmations = 3", "confirmations = 0").parse::<toml::Table>().unwrap(),
+            &env_map(&[]),
+        )
+        .err()
+        .unwrap()
+        .to_string();
+        assert!(err.contains("monitor.confirmations: значение 0 вне диапазона 1..=100"), "{}", err);
+
+        assert!(monitor::subscribe(777));
+        assert!(!monitor::subscribe(777));
+        assert!(monitor::subscribers().contains(&777));
+        assert!(monitor::unsubscribe(777));
+        assert!(!monitor::unsubscribe(777));
+
+        println!("✅ Мониторинг подтверждает переходы и не реагирует на дребезг");
+    }
+
+    #[test]
+    fn test_persistent_store() {
+        let dir = std::env::temp_dir().join(format!("wol-bot-store-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join("nested").join("bot.db");
+        let path = path.to_str().unwrap();
+
+        // База и каталог создаются, миграции применяются один раз
+        let store = Store::open(path).unwrap();
+        store.set_pref(1, "notify", "1").unwrap();
+        store.set_pref(1, "notify", "0").unwrap();
+        store.set_pref(2, "notify", "1").unwrap();
+        store.record_host_state("server", 100, "online", "").unwrap();
+        store.record_host_state("server", 200, "no_ssh", "код 255").unwrap();
+        store.record_host_state("nas", 300, "offline", "").unwrap();
+        let schedule = store::Schedule {
+            id: 0,
+            host: "server".to_string(),
+            action: "wake".to_string(),
+            spec: "0 8 * * 1-5".to_string(),
+            created_by: 1,
+            created_at: 100,
+            enabled: true,
+        };
+        let first = store.add_schedule(&schedule).unwrap();
+        let second = store.add_schedule(&store::Schedule { action: "shutdown".to_string(), ..schedule.clone() }).unwrap();
+        drop(store);
+
+        let store = Store::open(path).unwrap();
+        assert_eq!(store.prefs("notify").unwrap(), vec![(1, "0".to_string()), (2, "1".to_string())]);
+        assert!(store.prefs("missing").unwrap().is_empty());
+        let last = store.last_host_state("server").unwrap().unwrap();
+        assert_eq!(last, store::HostStateRecord { at: 200, state: "no_ssh".to_string(), details: "код 255".to_string() });
+        assert_eq!(store.last_host_state("unknown").unwrap(), None);
+        assert!(store.set_schedule_enabled(first, false).unwrap());
+        assert!(store.remove_schedule(second).unwrap());
+        assert!(!store.remove_schedule(second).unwrap());
+        assert!(!store.set_schedule_enabled(second, true).unwrap());
+        assert_eq!(store.schedules().unwrap(), vec![store::Schedule { id: first, enabled: false, ..schedule }]);
+        drop(store);
+
+        let conn = rusqlite::Connection::open(path).unwrap();
+        let version: i64 = conn.pragma_query_value(None, "user_version", |row| row.get(0)).unwrap();
+        assert_eq!(version, 3);
+        let tables = conn
+            .prepare("SELECT name FROM sqlite_master WHERE type = 'table' ORDER BY name")
+            .unwrap()
+            .query_map([], |row| row.get::<_, String>(0))
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(tables, vec!["audit_events", "granted_users", "host_states", "pending_ops", "schedules", "sqlite_sequence", "user_prefs"]);
+
+        // База от более новой версии бота не трогается
+        conn.pragma_update(None, "user_version", 99).unwrap();
+        drop(conn);
+        let err = format!("{:#}", Store::open(path).err().unwrap());
+        assert!(err.contains("схема базы версии 99 новее"), "{}", err);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // Состояние хоста из истории восстанавливается после перезапуска
+        assert_eq!(Reachability::from_record(&last), Some(Reachability::NoSsh { reason: "код 255".to_string() }));
+        let mut debouncer = Debouncer::new(2);
+        debouncer.restore(Reachability::Online, Duration::from_secs(600));
+        assert_eq!(debouncer.observe(Reachability::Offline), None);
+        let (previous, lasted) = debouncer.observe(Reachability::Offline).unwrap();
+        assert_eq!(previous, Reachability::Online);
+        assert!(lasted >= Duration::from_secs(600));
+
+        let toml = r#"
+            bot_token = "t"
+            users.alice = { id = 1 }
+            storage = { path = "/data/bot.db" }
+
+            [hosts.server]
+            mac = "aa:bb:cc:dd:ee:ff"
+            wol = { transport = "udp" }
+            ssh = { host = "localhost", user = "me", key = "/keys/server" }
+        "#;
+        let config = Config::from_sources(toml.parse::<toml::Table>().unwrap(), &env_map(&[])).unwrap();
+        assert_eq!(config.database, "/data/bot.db");
+        let config = Config::from_sources(
+            toml.replace(r#"storage = { path = "/data/bot.db" }"#, "").parse::<toml::Table>().unwrap(),
+            &env_map(&[]),
+        )
+        .unwrap();
+        assert_eq!(config.database, store::DEFAULT_PATH);
+        let config =
+            Config::from_sources(toml.parse::<toml::Table>().unwrap(), &env_map(&[("DATABASE_PATH", "/tmp/x.db")])).unwrap();
+        assert_eq!(config.database, "/tmp/x.db");
+
+        println!("✅ Состояние бота сохраняется в SQLite с миграциями");
+    }
+
+    #[test]
+    fn test_audit_log() {
+        // 2024-05-01 12:00:00 UTC
+        let now = 1714564800;
+        let query = audit::parse_query("user=@alice host=Server since=24h until=2024-05-01T11:30 limit=5", now).unwrap();
+        assert_eq!(
+            query.filter,
+            AuditFilter {
+                user: Some("alice".to_string()),
+                host: Some("server".to_string()),
+                since: Some(now - 86400),
+                until: Some(now - 1800),
+                limit: 5,
+            }
+        );
+        assert_eq!(query.format, None);
+        assert_eq!(audit::parse_query("", now).unwrap().filter.limit, 20);
+        assert_eq!(audit::parse_query("limit=1000", now).unwrap().filter.limit, 50);
+        let export = audit::parse_query("format=jsonl since=2024-04-30", now).unwrap();
+        assert_eq!(export.format, Some(ExportFormat::Jsonl));
+        assert_eq!(export.filter.since, Some(now - 86400 - 12 * 3600));
+        assert_eq!(export.filter.limit, 10_000);
+        for (args, expected) in [
+            ("when=today", "неизвестный фильтр 'when'"),
+            ("since=yesterday", "'yesterday': ожидалось 30m, 24h, 7d"),
+            ("since=999999999999999d", "'999999999999999d': ожидалось 30m, 24h, 7d"),
+            ("format=xml", "неизвестный формат 'xml'"),
+            ("alice", "'alice': ожидалось ключ=значение"),
+        ] {
+            let err = audit::parse_query(args, now).err().unwrap().to_string();
+            assert!(err.contains(expected), "{} -> {}", args, err);
+        }
+
+        // Запись и выборка по фильтрам
+        let dir = std::env::temp_dir().join(format!("wol-bot-audit-{}", std::process::id()));
+        let store = Store::open(dir.join("bot.db").to_str().unwrap()).unwrap();
+        let event = |at, user_id: i64, user_name: &str, action: &str, host: Option<&str>, result: &str, details: &str| AuditEvent {
+            at,
+            user_id,
+            user_name: user_name.to_string(),
+            action: action.to_string(),
+            host: host.map(str::to_string),
+            result: result.to_string(),
+            details: details.to_string(),
+        };
+        let events = [
+            event(now - 7200, 1, "@alice", "wol", Some("server"), "ok", ""),
+            event(now - 3600, 2, "Bob Smith", "shutdown", Some("nas"), "error", "sudo: a password is required (код 1)"),
+            event(now - 60, 1, "@alice", "shutdown", Some("server"), "ok", ""),
+            event(now - 30, 2, "Bob Smith", "hostkey_approve", None, "denied", ""),
+        ];
+        for e in &events {
+            store.record_audit(e).unwrap();
+        }
+        let select = |args: &str| store.audit_events(&audit::parse_query(args, now).unwrap().filter).unwrap();
+        assert_eq!(select(""), events.iter().rev().cloned().collect::<Vec<_>>());
+        assert_eq!(select("user=ALICE"), vec![events[2].clone(), events[0].clone()]);
+        assert_eq!(select("user=2 host=nas"), vec![events[1].clone()]);
+        assert_eq!(select("since=90m until=1m"), vec![events[1].clone()]);
+        assert_eq!(select("limit=1"), vec![events[3].clone()]);
+        drop(store);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            audit::render(&events[1..2]),
+            "📜 Журнал действий (UTC), записей: 1\n\n❌ 2024-05-01 11:00:00 Bob Smith shutdown nas — sudo: a password is required (код 1)"
+        );
+        assert_eq!(audit::render(&[]), "📜 Записей не найдено.");
+
+        let tricky = event(now, 3, "Eve, \"the\" tester", "wol", Some("server"), "ok", "line1\nline2");
+        assert_eq!(
+            audit::export(std::slice::from_ref(&tricky), ExportFormat::Csv),
+            "time,user_id,user_name,action,host,result,details\n\
+             2024-05-01 12:00:00,3,\"Eve, \"\"the\"\" tester\",wol,server,ok,\"line1\nline2\"\n"
+        );
+        let jsonl = audit::export(&[tricky, events[3].clone()], ExportFormat::Jsonl);
+        let lines = jsonl.lines().map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap()).collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["details"], "line1\nline2");
+        assert_eq!(lines[1]["host"], serde_json::Value::Null);
+        assert_eq!(audit::file_name(ExportFormat::Csv, now), "audit-20240501-1200.csv");
+
+        assert_eq!(audit::user_name(&test_user()), "@testuser");
+        let mut nameless = test_user();
+        nameless.username = None;
+        assert_eq!(audit::user_name(&nameless), "Test User");
+
+        println!("✅ Журнал действий записывается, фильтруется и выгружается");
+    }
+
+    #[test]
+    fn test_user_roles() {
+        assert!(Role::Admin > Role::Operator && Role::Operator > Role::Viewer);
+        assert!(Role::Viewer.allows(Permission::View));
+        assert!(!Role::Viewer.allows(Permission::Wake));
+        assert!(Role::Operator.allows(Permission::Wake));
+        assert!(!Role::Operator.allows(Permission::Shutdown));
+        assert!(Role::Admin.allows(Permission::Manage));
+        assert_eq!(access::permission_for("status"), Some(Permission::View));
+        assert_eq!(access::permission_for("wol"), Some(Permission::Wake));
+        assert_eq!(access::permission_for("shutdown_yes"), Some(Permission::Shutdown));
+        assert_eq!(access::permission_for("hostkey_ok"), Some(Permission::Manage));
+        assert_eq!(access::permission_for("unknown"), None);
+
+        let base = r#"
+            bot_token = "t"
+            [hosts.desk]
+            mac = "aa:bb:cc:dd:ee:ff"
+            wol = { transport = "udp" }
+            ssh = { host = "desk.lan", user = "me", key = "/keys/desk" }
+        "#;
+        let parse = |users: &str, env: &[(&str, &str)]| {
+            let table = format!("{}\n{}", users, base).parse::<toml::Table>().unwrap();
+            Config::from_sources(table, &env_map(env))
+        };
+
+        // Без ролей все пользователи — администраторы, как раньше
+        let config = parse("users.alice = { id = 1 }\nusers.bob = { id = 2 }", &[]).unwrap();
+        assert_eq!(config.admins, vec![1, 2]);
+        assert!(config.warnings.is_empty(), "{:?}", config.warnings);
+
+        // Только ADMIN_USERS: остальные остаются админами, оповещения получает отмеченный
+        let config = parse("users.alice = { id = 1 }\nusers.bob = { id = 2 }", &[("ADMIN_USERS", "2")]).unwrap();
+        assert_eq!(config.admins, vec![2]);
+        assert_eq!(access::user_role(&config, 1), Some(Role::Admin));
+        assert!(config.warnings.is_empty(), "{:?}", config.warnings);
+
+        // С ролями ниже admin пользователи без роли становятся операторами; admin = true — то же, что role = "admin"
+        let config = parse(
+            "users.alice = { id = 1, admin = true }\nusers.bob = { id = 2 }\nusers.carol = { id = 3, role = \"viewer\" }",
+            &[("OPERATOR_USERS", "4"), ("VIEWER_USERS", "5")],
+        )
+        .unwrap();
+        assert_eq!(config.admins, vec![1]);
+        assert_eq!(access::user_role(&config, 1), Some(Role::Admin));
+        assert_eq!(access::user_role(&config, 2), Some(Role::Operator));
+        assert_eq!(access::user_role(&config, 3), Some(Role::Viewer));
+        assert_eq!(access::user_role(&config, 4), Some(Role::Operator));
+        assert_eq!(access::user_role(&config, 5), Some(Role::Viewer));
+        assert_eq!(access::user_role(&config, 6), None);
+        // О пользователях, потерявших права админа из-за чужой роли, предупреждаем явно
+        assert_eq!(config.warnings.len(), 1, "{:?}", config.warnings);
+        assert!(config.warnings[0].starts_with("users: у bob роль не задана"), "{}", config.warnings[0]);
+        assert!(access::allows(&config, 2, Permission::Wake));
+        assert!(!access::allows(&config, 3, Permission::Wake));
+
+        // Зритель видит только статус, оператор может включить, но не выключить
+        let kb = main_keyboard(&config, &config.hosts[0], 3);
+        assert_eq!(kb.inline_keyboard.len(), 1);
+        if let teloxide::types::InlineKeyboardButtonKind::CallbackData(data) = &kb.inline_keyboard[0][0].kind {
+            assert_eq!(data, "status:desk");
+        } else {
+            panic!("Ожидался CallbackData для кнопки status");
+        }
+        let kb = main_keyboard(&config, &config.hosts[0], 2);
+        assert_eq!(kb.inline_keyboard[0].len(), 1);
+        if let teloxide::types::InlineKeyboardButtonKind::CallbackData(data) = &kb.inline_keyboard[0][0].kind {
+            assert_eq!(data, "wol:desk");
+        } else {
+            panic!("Ожидался CallbackData для кнопки WOL");
+        }
+
+        let err = parse("users.alice = { id = 1, admin = true, role = \"viewer\" }\nusers.bob = { id = 2, role = \"owner\" }", &[])
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("users.alice.admin: admin = true противоречит role = \"viewer\""), "{}", err);
+        assert!(err.contains("users.bob.role: неизвестная роль 'owner'"), "{}", err);
+
+        println!("✅ Роли пользователей разбираются и ограничивают действия");
+    }
+
+    #[test]
+    fn test_host_acl() {
+        let table = r#"
+            bot_token = "t"
+            users.alice = { id = 1, role = "admin" }
+            users.bob = { id = 2, role = "operator" }
+            users.carol = { id = 3, role = "viewer" }
+            users.dave = { id = 4, role = "operator" }
+
+            [groups]
+            family = ["bob", 3]
+
+            [hosts.build]
+            mac = "aa:bb:cc:dd:ee:01"
+            wol = { transport = "udp" }
+            ssh = { host = "build.lan", user = "me", key = "/keys/build" }
+
+            [hosts.nas]
+            mac = "aa:bb:cc:dd:ee:02"
+            wol = { transport = "udp" }
+            ssh = { host = "nas.lan", user = "me", key = "/keys/nas" }
+            acl = []
+
+            [hosts.media]
+            mac = "aa:bb:cc:dd:ee:03"
+            wol = { transport = "udp" }
+            ssh = { host = "media.lan", user = "me", key = "/keys/media" }
+            acl = [
+                { groups = ["family"], allow = ["wake"] },
+                { users = ["dave"], allow = ["view"] },
+            ]
+        "#
+        .parse::<toml::Table>()
+        .unwrap();
+        let config = Config::from_sources(table.clone(), &env_map(&[])).unwrap();
+        let media = config.host("media").unwrap();
+        assert_eq!(
+            media.acl,
+            Some(vec![
+                AclEntry { users: vec![2, 3], permissions: vec![Permission::Wake] },
+                AclEntry { users: vec![4], permissions: vec![Permission::View] },
+            ])
+        );
+
+        let visible = |user| access::visible_hosts(&config, user).iter().map(|h| h.id.clone()).collect::<Vec<_>>();
+        assert_eq!(visible(1), vec!["build", "nas", "media"]);
+        assert_eq!(visible(2), vec!["build", "media"]);
+        assert_eq!(visible(4), vec!["build", "media"]);
+
+        // Список доступа сужает права роли, но не расширяет их; админов он не ограничивает
+        assert!(access::allows_on(&config, 2, media, Permission::Wake));
+        assert!(!access::allows_on(&config, 3, media, Permission::Wake));
+        assert!(!access::allows_on(&config, 4, media, Permission::Wake));
+        assert!(access::allows_on(&config, 4, media, Permission::View));
+        assert!(access::allows_on(&config, 1, config.host("nas").unwrap(), Permission::Shutdown));
+        assert!(!access::acl_allows(&config, 2, config.host("nas").unwrap(), Permission::View));
+
+        // Подробности ошибки видны только тем, кому открыт её хост
+        let (_, details_id) = record_error(config.host("nas").unwrap(), &anyhow::anyhow!("диск отвалился"));
+        let details_id = details_id.to_string();
+        assert!(matches!(error_details(&config, 2, &details_id), ErrorDetails::Denied(host) if host.id == "nas"));
+        assert!(matches!(
+            error_details(&config, 1, &details_id),
+            ErrorDetails::Found { host, details } if host.id == "nas" && details.contains("диск отвалился")
+        ));
+        assert!(matches!(error_details(&config, 1, "0"), ErrorDetails::Missing));
+
+        // Список хостов и меню строятся только из доступного
+        let kb = hosts_keyboard(&config, 2);
+        assert_eq!(kb.inline_keyboard.len(), 2);
+        let kb = main_keyboard(&config, media, 4);
+        assert_eq!(kb.inline_keyboard.len(), 2);
+        if let teloxide::types::InlineKeyboardButtonKind::CallbackData(data) = &kb.inline_keyboard[0][0].kind {
+            assert_eq!(data, "status:media");
+        } else {
+            panic!("Ожидался CallbackData для кнопки status");
+        }
+
+        let mut broken = table;
+        broken["groups"]["family"] = toml::Value::Array(vec!["eve".into()]);
+        broken["hosts"]["nas"]["acl"] = toml::Value::Array(vec![
+            toml::toml! { groups = ["admins"] allow = ["reboot"] }.into(),
+            toml::toml! { users = ["bob"] }.into(),
+        ]);
+        let err = Config::from_sources(broken, &env_map(&[])).err().unwrap().to_string();
+        assert!(err.contains("groups.family: пользователь \"eve\" не описан в users"), "{}", err);
+        assert!(err.contains("hosts.nas.acl[0].groups: группа \"admins\" не описана в groups"), "{}", err);
+        assert!(err.contains("hosts.nas.acl[0].allow: неизвестное действие \"reboot\""), "{}", err);
+        assert!(err.contains("hosts.nas.acl[1].allow: обязательный ключ отсутствует"), "{}", err);
+
+        println!("✅ Списки доступа к хостам разбираются и фильтруют меню");
+    }
+
+    #[test]
+    fn test_access_requests() {
+        let dir = std::env::temp_dir().join(format!("wol-bot-access-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join("bot.db");
+        let path = path.to_str().unwrap();
+        let store = Store::open(path).unwrap();
+
+        // Запрос ждёт решения в базе; обработать его можно только один раз
+        let request = store::PendingOp {
+            id: 0,
+            kind: access::ACCESS_REQUEST.to_string(),
+            host: None,
+            requested_by: 555001,
+            created_at: 100,
+            expires_at: None,
+            payload: "@newbie".to_string(),
+        };
+        let id = store.add_pending_op(&request).unwrap();
+        assert_eq!(store.pending_op(id).unwrap(), Some(store::PendingOp { id, ..request.clone() }));
+        assert_eq!(store.pending_ops(access::ACCESS_REQUEST).unwrap().len(), 1);
+        assert!(store.pending_ops("other").unwrap().is_empty());
+        assert!(store.remove_pending_op(id).unwrap());
+        assert!(!store.remove_pending_op(id).unwrap());
+        assert_eq!(store.pending_op(id).unwrap(), None);
+
+        // Одобренный пользователь получает доступ сразу, без перезапуска
+        let config = test_config();
+        assert!(!is_allowed(&config, Some(555001)));
+        access::grant(&store, 555001, "@newbie", Role::Operator, 123456789).unwrap();
+        access::grant(&store, 555002, "@lead", Role::Admin, 123456789).unwrap();
+        assert!(is_allowed(&config, Some(555001)));
+        assert_eq!(access::user_role(&config, 555001), Some(Role::Operator));
+        assert!(access::allows(&config, 555001, Permission::Wake));
+        assert!(!access::allows(&config, 555001, Permission::Shutdown));
+        assert_eq!(admin_ids(&config), vec![123456789, 555002]);
+        drop(store);
+
+        // ...и сохраняет его после перезапуска
+        let store = Store::open(path).unwrap();
+        let granted = store.granted_users().unwrap();
+        assert_eq!(granted.len(), 2);
+        assert_eq!((granted[0].user_id, granted[0].role.as_str(), granted[0].granted_by), (555001, "operator", 123456789));
+        drop(store);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // Выбор роли и решение админа
+        assert_eq!(access::permission_for("access_ok"), Some(Permission::Manage));
+        let kb = access_request_keyboard(7, Role::Operator);
+        let labels = kb.inline_keyboard[0].iter().map(|b| b.text.as_str()).collect::<Vec<_>>();
+        assert_eq!(labels, vec!["○ viewer", "● operator", "○ admin"]);
+        let callbacks = kb.inline_keyboard[1]
+            .iter()
+            .filter_map(|b| match &b.kind {
+                teloxide::types::InlineKeyboardButtonKind::CallbackData(data) => Some(data.as_str()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(callbacks, vec!["access_ok:7:operator", "access_no:7"]);
+
+        assert!(check_access_request_cooldown(555003));
+        assert!(!check_access_request_cooldown(555003));
+
+        println!("✅ Запросы доступа сохраняются, одобренные пользователи получают роль");
+    }
+
+    #[test]
+    fn test_totp() {
+        // Векторы RFC 6238 для SHA1 (последние 6 из 8 цифр)
+        let secret = b"12345678901234567890";
+        for (time, expected) in [(59, "287082"), (1111111109, "081804"), (1234567890, "005924"), (2000000000, "279037")] {
+            assert_eq!(totp::code(secret, totp::step_at(time)), expected, "время {}", time);
+        }
+        assert_eq!(totp::encode_secret(secret), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+        assert_eq!(totp::decode_secret("gezd gnbv gy3t qojq gezd gnbv gy3t qojq").unwrap(), secret.to_vec());
+        assert_eq!(totp::decode_secret("GEZ1"), None);
+        assert_eq!(totp::new_secret().unwrap().len(), 20);
+        assert_ne!(totp::new_secret().unwrap(), totp::new_secret().unwrap());
+
+        // Соседний шаг принимается, уже использованный и далёкий — нет
+        assert_eq!(totp::check(secret, "287082", 59 + 30, None), Some(1));
+        assert_eq!(totp::check(secret, "287082", 59, Some(1)), None);
+        assert_eq!(totp::check(secret, "287082", 59 + 90, None), None);
+        assert_eq!(totp::parse_code(" 123 456 "), Some("123456".to_string()));
+        assert_eq!(totp::parse_code("12345"), None);
+        assert_eq!(totp::parse_code("/start"), None);
+        assert_eq!(
+            totp::otpauth_uri("Wake Bot", "alice", secret),
+            "otpauth://totp/Wake%20Bot:alice?secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ&issuer=Wake%20Bot&algorithm=SHA1&digits=6&period=30"
+        );
+
+        // У каждого действия под кодом своё имя; просмотр и управление под код не ставятся
+        assert_eq!(totp_action_names(Permission::Wake), Some(("wol", "включить")));
+        assert_eq!(totp_action_names(Permission::Shutdown), Some(("shutdown", "выключить")));
+        assert_eq!(totp_action_names(Permission::View), None);
+        assert_eq!(totp_action_names(Permission::Manage), None);
+
+        // Один и тот же код нельзя использовать дважды
+        let dir = std::env::temp_dir().join(format!("wol-bot-totp-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join("bot.db");
+        let store = Store::open(path.to_str().unwrap()).unwrap();
+        let now = 1_700_000_000;
+        assert_eq!(totp::secret(&store, 7).unwrap(), None);
+        assert!(!totp::verify(&store, 7, "000000", now).unwrap());
+        totp::enroll(&store, 7, secret, totp::step_at(now)).unwrap();
+        assert_eq!(totp::secret(&store, 7).unwrap(), Some(secret.to_vec()));
+        assert!(!totp::verify(&store, 7, &totp::code(secret, totp::step_at(now)), now).unwrap());
+        let next = totp::code(secret, totp::step_at(now) + 1);
+        assert!(totp::verify(&store, 7, &next, now + 30).unwrap());
+        assert!(!totp::verify(&store, 7, &next, now + 30).unwrap());
+        totp::disable(&store, 7).unwrap();
+        assert_eq!(totp::secret(&store, 7).unwrap(), None);
+        drop(store);
+
+        // Неверные коды блокируют ввод до конца окна, и блокировка переживает перезапуск
+        let store = Store::open(path.to_str().unwrap()).unwrap();
+        for left in (1..5).rev() {
+            assert_eq!(totp::record_failure(&store, 1, now).unwrap(), left);
+        }
+        assert_eq!(totp::locked_for(&store, 1, now).unwrap(), None);
+        assert_eq!(totp::record_failure(&store, 1, now).unwrap(), 0);
+        drop(store);
+        let store = Store::open(path.to_str().unwrap()).unwrap();
+        assert_eq!(totp::locked_for(&store, 1, now + 60).unwrap(), Some(Duration::from_secs(540)));
+        assert_eq!(totp::locked_for(&store, 2, now).unwrap(), None);
+        assert_eq!(totp::locked_for(&store, 1, now + 601).unwrap(), None);
+        totp::record_failure(&store, 1, now).unwrap();
+        totp::reset_failures(&store, 1).unwrap();
+        assert_eq!(totp::locked_for(&store, 1, now).unwrap(), None);
+
+        // Ожидание кода тоже хранится в базе; новое заменяет прежнее, забрать его можно один раз
+        let action = totp::Pending::Action {
+            permission: Permission::Shutdown,
+            host: "desk".to_string(),
+            chat: teloxide::types::ChatId(-100),
+            message: MessageId(42),
+        };
+        totp::set_pending(&store, 1, totp::Pending::Enroll { secret: secret.to_vec() }, now).unwrap();
+        totp::set_pending(&store, 1, action.clone(), now).unwrap();
+        drop(store);
+        let store = Store::open(path.to_str().unwrap()).unwrap();
+        assert!(totp::has_pending(&store, 1).unwrap());
+        assert!(!totp::has_pending(&store, 2).unwrap());
+        assert_eq!(totp::take_pending(&store, 1, now + 10).unwrap(), Some((action, true)));
+        assert_eq!(totp::take_pending(&store, 1, now + 10).unwrap(), None);
+        totp::set_pending(&store, 1, totp::Pending::Disable, now).unwrap();
+        assert_eq!(totp::take_pending(&store, 1, now + 121).unwrap(), Some((totp::Pending::Disable, false)));
+        totp::set_pending(&store, 1, totp::Pending::Enroll { secret: secret.to_vec() }, now).unwrap();
+        totp::cancel_pending(&store, 1).unwrap();
+        assert!(!totp::has_pending(&store, 1).unwrap());
+        drop(store);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let table = r#"
+            bot_token = "t"
+            users.alice = { id = 1 }
+            totp = { actions = ["wake", "shutdown"], issuer = "Home" }
+            [hosts.desk]
+            mac = "aa:bb:cc:dd:ee:ff"
+            wol = { transport = "udp" }
+            ssh = { host = "desk.lan", user = "me", key = "/keys/desk" }
+        "#
+        .parse::<toml::Table>()
+        .unwrap();
+        let config = Config::from_sources(table.clone(), &env_map(&[])).unwrap();
+        assert_eq!(config.totp.actions, vec![Permission::Wake, Permission::Shutdown]);
+        assert_eq!(config.totp.issuer, "Home");
+        let config = Config::from_sources(table.clone(), &env_map(&[("TOTP_ACTIONS", "")])).unwrap();
+        assert!(config.totp.actions.is_empty());
+        let err = Config::from_sources(table, &env_map(&[("TOTP_ACTIONS", "shutdown,reboot")])).err().unwrap().to_string();
+        assert!(err.contains("totp.actions (из TOTP_ACTIONS): неизвестное действие 'reboot'"), "{}", err);
+        assert_eq!(test_config().totp.actions, vec![Permission::Shutdown]);
+
+        println!("✅ Коды TOTP проверяются по RFC 6238, повторы и перебор отсекаются");
+    }
+
+    #[test]
+    fn test_two_person_approval() {
+        let policy = approval::ApprovalPolicy { quorum: 2, window: Duration::from_secs(600) };
+        let start = 1_700_000_000;
+        let new = || approval::Approval::new("shutdown", "server", test_user(), None, vec![1, 2, 3], policy, start);
+
+        // Свой запрос, чужие админы и повторный голос не засчитываются
+        let mut request = new();
+        assert!(request.vote(123456789, "@testuser", true, start).is_err());
+        assert!(request.vote(4, "@mallory", true, start).is_err());
+        assert_eq!(request.vote(1, "@alice", true, start), Ok(approval::Status::Waiting));
+        assert!(request.vote(1, "@alice", true, start).is_err());
+        let progress = request.progress("Выключение Server", start + 60);
+        assert!(progress.contains("Выключение Server ждёт подтверждения админов: 1 из 2"), "{}", progress);
+        assert!(progress.contains("✅ @alice"));
+        assert!(progress.contains("Осталось 9 мин"), "{}", progress);
+        assert_eq!(request.vote(2, "@bob", true, start), Ok(approval::Status::Approved));
+        assert_eq!(request.approver_names(), "@alice, @bob");
+
+        // Одного отказа достаточно, чтобы отклонить запрос
+        let mut request = new();
+        request.vote(1, "@alice", true, start).unwrap();
+        assert_eq!(request.vote(2, "@bob", false, start), Ok(approval::Status::Rejected { by: "@bob".to_string() }));
+
+        // После окна голоса уже не считаются
+        let mut request = new();
+        assert_eq!(request.vote(1, "@alice", true, start + 600), Ok(approval::Status::Expired));
+
+        // Решённый запрос убирается из ожидающих, незавершённый остаётся
+        let dir = std::env::temp_dir().join(format!("wol-bot-approval-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join("bot.db");
+        let store = Store::open(path.to_str().unwrap()).unwrap();
+        let id = approval::open(&store, &new()).unwrap();
+        assert!(matches!(approval::vote(&store, id, 1, "@alice", true, start).unwrap(), Some((_, Ok(approval::Status::Waiting)))));
+        assert!(matches!(approval::vote(&store, id, 1, "@alice", true, start).unwrap(), Some((_, Err(_)))));
+        approval::add_notice(&store, id, (teloxide::types::ChatId(1), MessageId(10))).unwrap();
+
+        // Голоса, срок и сообщения переживают перезапуск, номер запроса выдаёт база
+        drop(store);
+        let store = Store::open(path.to_str().unwrap()).unwrap();
+        let request = approval::get(&store, id).unwrap().unwrap();
+        assert_eq!(request.approvals, vec![(1, "@alice".to_string())]);
+        assert_eq!(request.deadline, start + 600);
+        assert_eq!(request.notices, vec![(teloxide::types::ChatId(1), MessageId(10))]);
+        assert_eq!(request.requester.id, test_user().id);
+        let (request, result) = approval::vote(&store, id, 3, "@carol", true, start).unwrap().unwrap();
+        assert_eq!(result, Ok(approval::Status::Approved));
+        assert_eq!(request.approvals.len(), 2);
+        assert!(approval::get(&store, id).unwrap().is_none());
+        assert!(approval::vote(&store, id, 2, "@bob", true, start).unwrap().is_none());
+        let next = approval::open(&store, &new()).unwrap();
+        assert!(next > id);
+
+        // Запросы прошлого запуска забираются целиком, чтобы истечь при старте
+        let stale = approval::take_all(&store).unwrap();
+        assert_eq!(stale.iter().map(|(id, _)| *id).collect::<Vec<_>>(), vec![next]);
+        assert!(approval::take_all(&store).unwrap().is_empty());
+        drop(store);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let table = r#"
+            bot_token = "t"
+            users.alice = { id = 1 }
+            [hosts.nas]
+            mac = "aa:bb:cc:dd:ee:ff"
+            wol = { transport = "udp" }
+            ssh = { host = "nas.lan", user = "me", key = "/keys/nas" }
+            approval = { quorum = 2 }
+            [hosts.desk]
+            mac = "aa:bb:cc:dd:ee:00"
+            wol = { transport = "udp" }
+            ssh = { host = "desk.lan", user = "me", key = "/keys/desk" }
+        "#
+        .parse::<toml::Table>()
+        .unwrap();
+        let config = Config::from_sources(table.clone(), &env_map(&[])).unwrap();
+        assert_eq!(
+            config.host("nas").unwrap().approval,
+            Some(approval::ApprovalPolicy { quorum: 2, window: Duration::from_secs(600) })
+        );
+        assert_eq!(config.host("desk").unwrap().approval, None);
+        let config =
+            Config::from_sources(table.clone(), &env_map(&[("HOST_DESK_APPROVAL_WINDOW", "120")])).unwrap();
+        assert_eq!(
+            config.host("desk").unwrap().approval,
+            Some(approval::ApprovalPolicy { quorum: 1, window: Duration::from_secs(120) })
+        );
+        let err = Config::from_sources(table, &env_map(&[("HOST_NAS_APPROVAL_QUORUM", "0")])).err().unwrap().to_string();
+        assert!(err.contains("hosts.nas.approval.quorum (из HOST_NAS_APPROVAL_QUORUM): значение 0 вне диапазона"), "{}", err);
+
+        println!("✅ Выключение защищённых хостов ждёт кворума других админов");
+    }
+
     #[tokio::test]
     async fn test_improved_timeouts() {
         let config = test_config();
         
         // Проверяем что таймауты установлены в разумные значения
-        assert!(config.ssh_timeout >= Duration::from_secs(5), "SSH таймаут должен быть >= 5 сек");
+        assert!(config.ssh_timeouts.connect >= Duration::from_secs(5), "SSH таймаут должен быть >= 5 сек");
         assert!(config.nc_timeout >= Duration::from_secs(3), "NC таймаут должен быть >= 3 сек");
         
         println!("✅ Таймауты настроены корректно: SSH={:?}, NC={:?}", 
-                config.ssh_timeout, config.nc_timeout);
+                config.ssh_timeouts.connect, config.nc_timeout);
     }
 }
\ No newline at end of file
@@ -0,0 +1,302 @@
+// Второй фактор TOTP (RFC 6238) для опасных действий: секреты пользователей, проверка кодов
+// и ограничение числа неверных попыток
+
+use std::time::Duration;
+
+use anyhow::Result;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use teloxide::types::{ChatId, MessageId};
+
+use crate::{
+    access::Permission,
+    store::{PendingOp, Store},
+};
+
+// Длина кода и шаг по времени — значения по умолчанию у всех приложений-аутентификаторов
+pub const DIGITS: usize = 6;
+const STEP_SECS: i64 = 30;
+// Принимаем и соседние шаги: часы телефона могут немного расходиться с сервером
+const SKEW_STEPS: i64 = 1;
+const SECRET_BYTES: usize = 20;
+// Сколько ждём код после нажатия кнопки
+pub const CODE_TIMEOUT: Duration = Duration::from_secs(120);
+// Неверные коды: после MAX_FAILURES за FAILURE_WINDOW ввод блокируется до конца окна
+const MAX_FAILURES: usize = 5;
+const FAILURE_WINDOW: Duration = Duration::from_secs(600);
+
+// Настройки пользователя в базе: секрет в base32 и последний принятый шаг (против повтора кода)
+const SECRET_PREF: &str = "totp_secret";
+const STEP_PREF: &str = "totp_step";
+// Время неверных кодов за окно через запятую: блокировка переживает перезапуск бота
+const FAILURES_PREF: &str = "totp_failures";
+// Ожидание кода хранится в pending_ops, по одной записи на пользователя
+const PENDING_KIND: &str = "totp";
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TotpConfig {
+    // Действия, перед которыми пользователь с включённым TOTP вводит код
+    pub actions: Vec<Permission>,
+    // Подпись записи в приложении-аутентификаторе
+    pub issuer: String,
+}
+
+impl Default for TotpConfig {
+    fn default() -> Self {
+        TotpConfig { actions: vec![Permission::Shutdown], issuer: "WakeOnLanBot".to_string() }
+    }
+}
+
+// Что ждёт кода от пользователя
+#[derive(Clone, Debug, PartialEq)]
+pub enum Pending {
+    // Включение TOTP: первый код подтверждает, что секрет попал в приложение
+    Enroll { secret: Vec<u8> },
+    Disable,
+    // Действие с хостом, сообщение с кнопками которого обновляется по ходу
+    Action { permission: Permission, host: String, chat: ChatId, message: MessageId },
+}
+
+pub fn new_secret() -> Result<Vec<u8>> {
+    let mut secret = vec![0u8; SECRET_BYTES];
+    getrandom::getrandom(&mut secret).map_err(|e| anyhow::anyhow!("не удалось получить случайные байты: {}", e))?;
+    Ok(secret)
+}
+
+// Base32 без выравнивания (RFC 4648), как его ждут приложения-аутентификаторы
+pub fn encode_secret(secret: &[u8]) -> String {
+    let mut out = String::new();
+    let (mut buffer, mut bits) = (0u32, 0);
+    for &byte in secret {
+        buffer = (buffer << 8) | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32_ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    out
+}
+
+pub fn decode_secret(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in text.chars().filter(|c| !c.is_whitespace() && *c != '=') {
+        let value = BASE32_ALPHABET.iter().position(|&a| a as char == c.to_ascii_uppercase())? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+// HOTP (RFC 4226): HMAC-SHA1 от номера шага и динамическое усечение до DIGITS цифр
+pub fn code(secret: &[u8], step: i64) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC принимает ключ любой длины");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let value = u32::from_be_bytes([hash[offset] & 0x7f, hash[offset + 1], hash[offset + 2], hash[offset + 3]]);
+    format!("{:0width$}", value % 10u32.pow(DIGITS as u32), width = DIGITS)
+}
+
+pub fn step_at(time: i64) -> i64 {
+    time.div_euclid(STEP_SECS)
+}
+
+// Шаг, которому соответствует код, если он верен и новее уже использованного
+pub fn check(secret: &[u8], input: &str, now: i64, last_step: Option<i64>) -> Option<i64> {
+    let current = step_at(now);
+    (current - SKEW_STEPS..=current + SKEW_STEPS)
+        .filter(|step| last_step.is_none_or(|last| *step > last))
+        .find(|step| code(secret, *step) == input)
+}
+
+// Шесть цифр; пробел посередине («123 456») допускаем, как его показывают приложения
+pub fn parse_code(text: &str) -> Option<String> {
+    let code = text.trim().replace(' ', "");
+    (code.len() == DIGITS && code.chars().all(|c| c.is_ascii_digit())).then_some(code)
+}
+
+// Ссылка для добавления секрета в приложение-аутентификатор
+pub fn otpauth_uri(issuer: &str, account: &str, secret: &[u8]) -> String {
+    let escape = |text: &str| {
+        text.bytes()
+            .map(|b| match b {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+                _ => format!("%{:02X}", b),
+            })
+            .collect::<String>()
+    };
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        escape(issuer),
+        escape(account),
+        encode_secret(secret),
+        escape(issuer),
+        DIGITS,
+        STEP_SECS
+    )
+}
+
+// ------------------------------------------------------------------
+// Секреты пользователей
+
+pub fn secret(store: &Store, user_id: i64) -> Result<Option<Vec<u8>>> {
+    Ok(store.pref(user_id, SECRET_PREF)?.and_then(|text| decode_secret(&text)))
+}
+
+// Сохраняет подтверждённый секрет; шаг подтверждающего кода второй раз не примем
+pub fn enroll(store: &Store, user_id: i64, secret: &[u8], step: i64) -> Result<()> {
+    store.set_pref(user_id, SECRET_PREF, &encode_secret(secret))?;
+    store.set_pref(user_id, STEP_PREF, &step.to_string())?;
+    Ok(())
+}
+
+pub fn disable(store: &Store, user_id: i64) -> Result<()> {
+    store.remove_pref(user_id, SECRET_PREF)?;
+    store.remove_pref(user_id, STEP_PREF)?;
+    Ok(())
+}
+
+// Проверяет код пользователя с включённым TOTP и запоминает его шаг
+pub fn verify(store: &Store, user_id: i64, input: &str, now: i64) -> Result<bool> {
+    let Some(secret) = secret(store, user_id)? else {
+        return Ok(false);
+    };
+    let last_step = store.pref(user_id, STEP_PREF)?.and_then(|step| step.parse::<i64>().ok());
+    match check(&secret, input, now, last_step) {
+        Some(step) => {
+            store.set_pref(user_id, STEP_PREF, &step.to_string())?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+// ------------------------------------------------------------------
+// Ожидание кода и неверные попытки
+
+fn encode_pending(pending: &Pending) -> (Option<String>, String) {
+    let (host, payload) = match pending {
+        Pending::Enroll { secret } => (None, serde_json::json!({ "kind": "enroll", "secret": encode_secret(secret) })),
+        Pending::Disable => (None, serde_json::json!({ "kind": "disable" })),
+        Pending::Action { permission, host, chat, message } => (
+            Some(host.clone()),
+            serde_json::json!({
+                "kind": "action",
+                "permission": permission.to_string(),
+                "chat": chat.0,
+                "message": message.0,
+            }),
+        ),
+    };
+    (host, payload.to_string())
+}
+
+fn decode_pending(op: &PendingOp) -> Option<Pending> {
+    let payload = serde_json::from_str::<serde_json::Value>(&op.payload).ok()?;
+    match payload["kind"].as_str()? {
+        "enroll" => Some(Pending::Enroll { secret: decode_secret(payload["secret"].as_str()?)? }),
+        "disable" => Some(Pending::Disable),
+        "action" => Some(Pending::Action {
+            permission: Permission::from_name(payload["permission"].as_str()?)?,
+            host: op.host.clone()?,
+            chat: ChatId(payload["chat"].as_i64()?),
+            message: MessageId(payload["message"].as_i64()?.try_into().ok()?),
+        }),
+        _ => None,
+    }
+}
+
+fn pending_op(store: &Store, user_id: u64) -> Result<Option<PendingOp>> {
+    Ok(store.pending_ops(PENDING_KIND)?.into_iter().find(|op| op.requested_by == user_id as i64))
+}
+
+// Новое ожидание заменяет прежнее
+pub fn set_pending(store: &Store, user_id: u64, pending: Pending, now: i64) -> Result<()> {
+    cancel_pending(store, user_id)?;
+    let (host, payload) = encode_pending(&pending);
+    store.add_pending_op(&PendingOp {
+        id: 0,
+        kind: PENDING_KIND.to_string(),
+        host,
+        requested_by: user_id as i64,
+        created_at: now,
+        expires_at: Some(now + CODE_TIMEOUT.as_secs() as i64),
+        payload,
+    })?;
+    Ok(())
+}
+
+pub fn has_pending(store: &Store, user_id: u64) -> Result<bool> {
+    Ok(pending_op(store, user_id)?.is_some())
+}
+
+// Забирает ожидание; false во втором поле — время на ввод истекло
+pub fn take_pending(store: &Store, user_id: u64, now: i64) -> Result<Option<(Pending, bool)>> {
+    let Some(op) = pending_op(store, user_id)? else {
+        return Ok(None);
+    };
+    // Запись мог забрать параллельный обработчик
+    if !store.remove_pending_op(op.id)? {
+        return Ok(None);
+    }
+    match decode_pending(&op) {
+        Some(pending) => Ok(Some((pending, op.expires_at.is_none_or(|expires| now < expires)))),
+        None => {
+            log::warn!("Повреждённое ожидание кода TOTP {} пользователя {} удалено", op.id, user_id);
+            Ok(None)
+        }
+    }
+}
+
+pub fn cancel_pending(store: &Store, user_id: u64) -> Result<()> {
+    while let Some(op) = pending_op(store, user_id)? {
+        store.remove_pending_op(op.id)?;
+    }
+    Ok(())
+}
+
+// Неверные коды пользователя за последнее окно, от старых к новым
+fn recent_failures(store: &Store, user_id: u64, now: i64) -> Result<Vec<i64>> {
+    let window = FAILURE_WINDOW.as_secs() as i64;
+    Ok(store
+        .pref(user_id as i64, FAILURES_PREF)?
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|at| at.parse::<i64>().ok())
+        .filter(|at| now - at < window)
+        .collect())
+}
+
+// Сколько ещё ждать, если неверных кодов за окно набралось слишком много
+pub fn locked_for(store: &Store, user_id: u64, now: i64) -> Result<Option<Duration>> {
+    let failures = recent_failures(store, user_id, now)?;
+    if failures.len() < MAX_FAILURES {
+        return Ok(None);
+    }
+    let since_first = Duration::from_secs(failures.first().map_or(0, |first| now - first).max(0) as u64);
+    Ok(Some(FAILURE_WINDOW.saturating_sub(since_first)))
+}
+
+// Возвращает, сколько попыток осталось до блокировки
+pub fn record_failure(store: &Store, user_id: u64, now: i64) -> Result<usize> {
+    let mut failures = recent_failures(store, user_id, now)?;
+    failures.push(now);
+    let text = failures.iter().map(|at| at.to_string()).collect::<Vec<_>>().join(",");
+    store.set_pref(user_id as i64, FAILURES_PREF, &text)?;
+    Ok(MAX_FAILURES.saturating_sub(failures.len()))
+}
+
+pub fn reset_failures(store: &Store, user_id: u64) -> Result<()> {
+    store.remove_pref(user_id as i64, FAILURES_PREF)
+}
@@ -0,0 +1,309 @@
+// Собственные SSH-туннели вместо внешнего autossh: открываем, следим и переподключаем
+
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use russh::{client, Channel};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+
+use crate::{ssh, Config, SshTarget};
+
+// Пауза перед первым переподключением; дальше удваивается до MAX_BACKOFF
+const MIN_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+// Как часто проверять, что сессия туннеля ещё отвечает
+const HEALTH_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    // Как ssh -L: бот слушает listen и ведёт соединения через SSH-сервер на connect
+    Local,
+    // Как ssh -R: SSH-сервер слушает listen и ведёт соединения через бота на connect
+    Remote,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TunnelConfig {
+    pub name: String,
+    // SSH-сервер, через который идёт туннель
+    pub ssh: SshTarget,
+    pub direction: Direction,
+    pub listen: (String, u16),
+    pub connect: (String, u16),
+}
+
+// host:port, IPv6 — в квадратных скобках: [fd00::1]:22
+pub fn parse_address(address: &str) -> Result<(String, u16)> {
+    let (host, port) = address
+        .rsplit_once(':')
+        .ok_or_else(|| anyhow::anyhow!("адрес '{}' должен быть в виде host:port", address))?;
+    let port = port
+        .parse::<u16>()
+        .ok()
+        .filter(|p| *p != 0)
+        .ok_or_else(|| anyhow::anyhow!("некорректный порт в адресе '{}'", address))?;
+    let host = ssh::strip_brackets(host);
+    if host.is_empty() || host.contains(['[', ']']) {
+        anyhow::bail!("некорректный хост в адресе '{}'", address);
+    }
+    Ok((host.to_string(), port))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TunnelState {
+    Connecting,
+    Up { since: Instant },
+    Down { reason: String, since: Instant },
+}
+
+// Переход туннеля, о котором надо сказать админам
+#[derive(Clone, Debug)]
+pub enum TunnelEvent {
+    Down { name: String, reason: String },
+    Recovered { name: String, downtime: Duration },
+}
+
+impl fmt::Display for TunnelEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TunnelEvent::Down { name, reason } => write!(f, "🔴 Туннель {} упал: {}", name, reason),
+            TunnelEvent::Recovered { name, downtime } => {
+                write!(f, "🟢 Туннель {} восстановлен после {}", name, format_duration(*downtime))
+            }
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref STATES: Mutex<HashMap<String, TunnelState>> = Mutex::new(HashMap::new());
+    static ref EVENTS: (
+        mpsc::UnboundedSender<TunnelEvent>,
+        Mutex<Option<mpsc::UnboundedReceiver<TunnelEvent>>>,
+    ) = {
+        let (tx, rx) = mpsc::unbounded_channel();
+        (tx, Mutex::new(Some(rx)))
+    };
+}
+
+// Получатель событий о туннелях; забрать его можно один раз
+pub fn take_events() -> Option<mpsc::UnboundedReceiver<TunnelEvent>> {
+    EVENTS.1.lock().unwrap().take()
+}
+
+pub fn state(name: &str) -> Option<TunnelState> {
+    STATES.lock().unwrap().get(name).cloned()
+}
+
+fn set_state(name: &str, state: TunnelState) {
+    STATES.lock().unwrap().insert(name.to_string(), state);
+}
+
+pub fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    match secs {
+        0..=59 => format!("{} с", secs),
+        60..=3599 => format!("{} мин", secs / 60),
+        3600..=86399 => format!("{} ч {} мин", secs / 3600, secs % 3600 / 60),
+        _ => format!("{} д {} ч", secs / 86400, secs % 86400 / 3600),
+    }
+}
+
+// Строка для вывода статуса: «🟢 server — работает 2 ч 5 мин»
+pub fn describe(name: &str, state: Option<&TunnelState>) -> String {
+    match state {
+        None | Some(TunnelState::Connecting) => format!("🟡 {} — подключается", name),
+        Some(TunnelState::Up { since }) => {
+            format!("🟢 {} — работает {}", name, format_duration(since.elapsed()))
+        }
+        Some(TunnelState::Down { reason, since }) => format!(
+            "🔴 {} — нет соединения {}: {}",
+            name,
+            format_duration(since.elapsed()),
+            reason
+        ),
+    }
+}
+
+// Запускает по задаче-надсмотрщику на каждый туннель из конфигурации
+pub fn start(config: Arc<Config>) {
+    for tunnel in config.tunnels.clone() {
+        set_state(&tunnel.name, TunnelState::Connecting);
+        tokio::spawn(supervise(config.clone(), tunnel));
+    }
+}
+
+async fn supervise(config: Arc<Config>, tunnel: TunnelConfig) {
+    let mut backoff = MIN_BACKOFF;
+    // Локальный порт держим и между переподключениями, чтобы его никто не занял
+    let mut listener: Option<TcpListener> = None;
+    let mut down_since: Option<Instant> = None;
+
+    loop {
+        // Туннель считается поднятым, только когда проброс порта уже открыт
+        let on_up = || {
+            log::info!("Туннель '{}' поднят", tunnel.name);
+            set_state(&tunnel.name, TunnelState::Up { since: Instant::now() });
+            if let Some(since) = down_since.take() {
+                emit(TunnelEvent::Recovered { name: tunnel.name.clone(), downtime: since.elapsed() });
+            }
+            backoff = MIN_BACKOFF;
+        };
+        let result = connect_and_serve(&config, &tunnel, &mut listener, on_up).await;
+
+        let reason = match result {
+            Ok(()) => "сессия закрыта".to_string(),
+            Err(e) => format!("{:#}", e),
+        };
+        log::warn!("Туннель '{}' не работает: {}; повтор через {:?}", tunnel.name, reason, backoff);
+        set_state(&tunnel.name, TunnelState::Down { reason: reason.clone(), since: Instant::now() });
+        // Оповещаем один раз на падение, а не на каждую неудачную попытку
+        if down_since.is_none() {
+            down_since = Some(Instant::now());
+            emit(TunnelEvent::Down { name: tunnel.name.clone(), reason });
+        }
+
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+fn emit(event: TunnelEvent) {
+    if EVENTS.0.send(event).is_err() {
+        log::warn!("Некому доставить событие о туннеле");
+    }
+}
+
+async fn bind((host, port): &(String, u16)) -> Result<TcpListener> {
+    TcpListener::bind((host.as_str(), *port))
+        .await
+        .map_err(|e| anyhow::anyhow!("не удалось занять {}:{}: {}", host, port, e))
+}
+
+async fn connect_and_serve(
+    config: &Config,
+    tunnel: &TunnelConfig,
+    listener: &mut Option<TcpListener>,
+    on_up: impl FnOnce(),
+) -> Result<()> {
+    if tunnel.direction == Direction::Local && listener.is_none() {
+        *listener = Some(bind(&tunnel.listen).await?);
+    }
+    let mut conn = ssh::establish_ssh_connection(&tunnel.ssh, &config.ssh_timeouts, &config.host_keys).await?;
+
+    let result = serve(config, tunnel, &mut conn, listener.as_ref(), on_up).await;
+    conn.close().await;
+    result
+}
+
+// Обслуживает соединения туннеля, пока сессия жива. Возвращается только с ошибкой.
+async fn serve(
+    config: &Config,
+    tunnel: &TunnelConfig,
+    conn: &mut ssh::Connection,
+    listener: Option<&TcpListener>,
+    on_up: impl FnOnce(),
+) -> Result<()> {
+    let timeouts = &config.ssh_timeouts;
+
+    // Удалённый порт открывает сервер; при потере сессии он закрывается вместе с ней
+    if tunnel.direction == Direction::Remote {
+        let (host, port) = (&tunnel.listen.0, tunnel.listen.1);
+        let forward = conn.handle.tcpip_forward(host.as_str(), port as u32);
+        let bound = match tokio::time::timeout(timeouts.exec, forward).await {
+            Ok(Ok(bound)) => bound,
+            Ok(Err(e)) => anyhow::bail!("сервер не открыл порт {}:{}: {}", host, port, e),
+            Err(_) => anyhow::bail!(
+                "сервер не открыл порт {}:{}: нет ответа за {} с",
+                host,
+                port,
+                timeouts.exec.as_secs()
+            ),
+        };
+        // Для заданного порта сервер не сообщает его номер
+        let bound = if bound == 0 { port as u32 } else { bound };
+        log::info!("Туннель '{}': сервер слушает {}:{}", tunnel.name, host, bound);
+    }
+
+    on_up();
+
+    let (connect_host, connect_port) = (tunnel.connect.0.clone(), tunnel.connect.1);
+    let mut health = tokio::time::interval(HEALTH_INTERVAL);
+    health.reset();
+
+    loop {
+        tokio::select! {
+            accepted = accept(listener) => {
+                let (socket, peer) = accepted?;
+                let channel = conn.handle.channel_open_direct_tcpip(
+                    connect_host.as_str(),
+                    connect_port as u32,
+                    peer.ip().to_string(),
+                    peer.port() as u32,
+                );
+                match tokio::time::timeout(timeouts.connect, channel).await {
+                    Ok(Ok(channel)) => {
+                        tokio::spawn(pipe(socket, channel));
+                    }
+                    Ok(Err(e)) => log::warn!(
+                        "Туннель '{}': не удалось открыть канал до {}:{}: {}",
+                        tunnel.name,
+                        connect_host,
+                        connect_port,
+                        e
+                    ),
+                    Err(_) => log::warn!(
+                        "Туннель '{}': канал до {}:{} не открылся за {} с",
+                        tunnel.name,
+                        connect_host,
+                        connect_port,
+                        timeouts.connect.as_secs()
+                    ),
+                }
+            }
+            channel = conn.forwarded.recv() => {
+                let channel = channel.ok_or_else(|| anyhow::anyhow!("сервер перестал принимать соединения"))?;
+                let (name, host, timeout) = (tunnel.name.clone(), connect_host.clone(), timeouts.connect);
+                tokio::spawn(async move {
+                    match ssh::connect_tcp(&host, connect_port, timeout).await {
+                        Ok(socket) => pipe(socket, channel).await,
+                        Err(e) => log::warn!("Туннель '{}': {}", name, e),
+                    }
+                });
+            }
+            // Открытие служебного канала проверяет, что сервер на том конце ещё отвечает
+            _ = health.tick() => {
+                match tokio::time::timeout(timeouts.exec, conn.handle.channel_open_session()).await {
+                    Ok(Ok(channel)) => {
+                        let _ = channel.close().await;
+                    }
+                    Ok(Err(e)) => anyhow::bail!("сервер не отвечает: {}", e),
+                    Err(_) => anyhow::bail!("сервер не отвечает {} с", timeouts.exec.as_secs()),
+                }
+            }
+        }
+    }
+}
+
+// Следующее входящее соединение на локальный порт; без порта (ssh -R) ждёт вечно
+async fn accept(listener: Option<&TcpListener>) -> Result<(TcpStream, std::net::SocketAddr)> {
+    match listener {
+        Some(listener) => Ok(listener.accept().await?),
+        None => std::future::pending().await,
+    }
+}
+
+// Перекладывает байты между сокетом и каналом, пока одна из сторон не закроется
+async fn pipe(mut socket: TcpStream, channel: Channel<client::Msg>) {
+    let mut stream = channel.into_stream();
+    if let Err(e) = tokio::io::copy_bidirectional(&mut socket, &mut stream).await {
+        log::debug!("Соединение туннеля закрыто: {}", e);
+    }
+}
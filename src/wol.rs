@@ -0,0 +1,343 @@
+// Отправка Wake-on-LAN: через команду на роутере или magic packet напрямую по UDP
+
+use std::{
+    future::Future,
+    net::{Ipv4Addr, SocketAddrV4, UdpSocket},
+    pin::Pin,
+    time::Duration,
+};
+
+use anyhow::Result;
+
+use crate::{ssh, Config, HostConfig};
+
+pub const DEFAULT_UDP_PORT: u16 = 9;
+// Мост LAN в OpenWrt; у RouterOS общего имени нет, там интерфейс задаётся явно
+pub const DEFAULT_INTERFACE: &str = "br-lan";
+
+pub type WakeFuture<'a> = Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>>;
+
+// Общий интерфейс способов разбудить хост
+pub trait WolBackend {
+    fn wake<'a>(&'a self, config: &'a Config, host: &'a HostConfig) -> WakeFuture<'a>;
+}
+
+// Способ доставки magic packet до хоста
+#[derive(Clone, Debug, PartialEq)]
+pub enum WolTransport {
+    // SSH на роутер и запуск команды там
+    Router(RouterCommand),
+    // Широковещательный UDP-пакет прямо из бота (бот в той же сети)
+    Udp(UdpSender),
+}
+
+impl WolTransport {
+    pub fn backend(&self) -> &dyn WolBackend {
+        match self {
+            WolTransport::Router(command) => command,
+            WolTransport::Udp(sender) => sender,
+        }
+    }
+}
+
+// Повторная отправка magic packet, если хост не поднялся
+#[derive(Clone, Debug, PartialEq)]
+pub struct WakeRetry {
+    // Сколько всего пакетов отправить (1 — без повторов)
+    pub attempts: u32,
+    // Сколько ждать после первого пакета, прежде чем отправить повторный
+    pub grace_period: Duration,
+    // Пауза между последующими повторами
+    pub interval: Duration,
+}
+
+impl Default for WakeRetry {
+    fn default() -> Self {
+        WakeRetry {
+            attempts: 3,
+            grace_period: Duration::from_secs(60),
+            interval: Duration::from_secs(60),
+        }
+    }
+}
+
+impl WakeRetry {
+    // Моменты отправки пакетов относительно первого: 0, grace, grace + interval, ...
+    pub fn schedule(&self) -> Vec<Duration> {
+        (0..self.attempts)
+            .map(|n| match n {
+                0 => Duration::ZERO,
+                n => self.grace_period + self.interval * (n - 1),
+            })
+            .collect()
+    }
+
+    // Когда сдаваться: boot_timeout после последнего пакета
+    pub fn deadline(&self, boot_timeout: Duration) -> Duration {
+        self.schedule().last().copied().unwrap_or_default() + boot_timeout
+    }
+}
+
+// Шаблоны команд для разных прошивок роутеров
+#[derive(Clone, Debug, PartialEq)]
+pub enum CommandTemplate {
+    // OpenWrt с пакетом etherwake
+    Etherwake,
+    // busybox ether-wake
+    EtherWake,
+    // perl-скрипт wakeonlan (интерфейс не используется)
+    Wakeonlan,
+    // MikroTik RouterOS
+    Mikrotik,
+    // OpenWrt через rpcd (ubus file exec)
+    Ubus,
+    // Свой шаблон с подстановками {mac}, {interface} и {password}
+    Custom(String),
+}
+
+const PLACEHOLDERS: [&str; 3] = ["mac", "interface", "password"];
+
+impl CommandTemplate {
+    pub fn from_name(name: &str, template: Option<String>) -> Result<Self> {
+        Ok(match name {
+            "etherwake" => CommandTemplate::Etherwake,
+            "ether-wake" => CommandTemplate::EtherWake,
+            "wakeonlan" => CommandTemplate::Wakeonlan,
+            "mikrotik" => CommandTemplate::Mikrotik,
+            "ubus" => CommandTemplate::Ubus,
+            "custom" => {
+                let template = template.ok_or_else(|| anyhow::anyhow!("для command = \"custom\" нужен template"))?;
+                validate_template(&template)?;
+                CommandTemplate::Custom(template)
+            }
+            other => anyhow::bail!(
+                "неизвестная команда '{}', допустимы etherwake, ether-wake, wakeonlan, mikrotik, ubus и custom",
+                other
+            ),
+        })
+    }
+
+    // Интерфейс, если в конфигурации он не указан; None — указать обязательно
+    pub fn default_interface(&self) -> Option<&'static str> {
+        match self {
+            CommandTemplate::Mikrotik => None,
+            _ => Some(DEFAULT_INTERFACE),
+        }
+    }
+
+    // Умеет ли команда передавать пароль SecureOn
+    pub fn supports_password(&self) -> bool {
+        match self {
+            CommandTemplate::Etherwake | CommandTemplate::EtherWake | CommandTemplate::Ubus => true,
+            CommandTemplate::Wakeonlan | CommandTemplate::Mikrotik => false,
+            CommandTemplate::Custom(template) => template.contains("{password}"),
+        }
+    }
+
+    fn pattern(&self, with_password: bool) -> &str {
+        match (self, with_password) {
+            (CommandTemplate::Etherwake, false) => "etherwake -i {interface} {mac}",
+            (CommandTemplate::Etherwake, true) => "etherwake -i {interface} -p {password} {mac}",
+            (CommandTemplate::EtherWake, false) => "ether-wake -i {interface} {mac}",
+            (CommandTemplate::EtherWake, true) => "ether-wake -i {interface} -p {password} {mac}",
+            (CommandTemplate::Wakeonlan, _) => "wakeonlan {mac}",
+            (CommandTemplate::Mikrotik, _) => "/tool wol interface={interface} mac={mac}",
+            (CommandTemplate::Ubus, false) => {
+                r#"ubus call file exec '{"command":"/usr/bin/etherwake","params":["-i","{interface}","{mac}"]}'"#
+            }
+            (CommandTemplate::Ubus, true) => {
+                r#"ubus call file exec '{"command":"/usr/bin/etherwake","params":["-i","{interface}","-p","{password}","{mac}"]}'"#
+            }
+            (CommandTemplate::Custom(template), _) => template,
+        }
+    }
+
+    // Подставляет только проверенные значения: MAC и пароль в каноническом виде и имя
+    // интерфейса из безопасного набора символов, так что в команду не попадут кавычки или ';'
+    pub fn render(&self, mac: &str, interface: &str, password: Option<&SecureOn>) -> Result<String> {
+        let mac = format_mac(&parse_mac(mac)?);
+        if !is_valid_interface(interface) {
+            anyhow::bail!("некорректное имя интерфейса '{}'", interface);
+        }
+        if password.is_some() && !self.supports_password() {
+            anyhow::bail!("команда не поддерживает пароль SecureOn");
+        }
+        let password = password.map(SecureOn::to_string);
+
+        let pattern = self.pattern(password.is_some());
+        let mut command = String::new();
+        let mut rest = pattern;
+        while let Some(start) = rest.find('{') {
+            command.push_str(&rest[..start]);
+            let tail = &rest[start + 1..];
+            let value = match tail.find('}').map(|end| (&tail[..end], end)) {
+                Some(("mac", end)) => Some((mac.as_str(), end)),
+                Some(("interface", end)) => Some((interface, end)),
+                Some(("password", end)) => match &password {
+                    Some(password) => Some((password.as_str(), end)),
+                    None => anyhow::bail!("шаблон использует {{password}}, но пароль SecureOn не задан"),
+                },
+                _ => None,
+            };
+            match value {
+                Some((value, end)) => {
+                    command.push_str(value);
+                    rest = &tail[end + 1..];
+                }
+                // Фигурные скобки, не являющиеся подстановкой (JSON в ubus), копируем как есть
+                None => {
+                    command.push('{');
+                    rest = tail;
+                }
+            }
+        }
+        command.push_str(rest);
+        Ok(command)
+    }
+}
+
+// Шаблон должен содержать {mac} и не ссылаться на неизвестные подстановки
+fn validate_template(template: &str) -> Result<()> {
+    let placeholders = regex::Regex::new(r"\{([A-Za-z_]+)\}").unwrap();
+    for cap in placeholders.captures_iter(template) {
+        if !PLACEHOLDERS.contains(&&cap[1]) {
+            anyhow::bail!(
+                "неизвестная подстановка '{{{}}}', допустимы {{mac}}, {{interface}} и {{password}}",
+                &cap[1]
+            );
+        }
+    }
+    if !template.contains("{mac}") {
+        anyhow::bail!("шаблон должен содержать {{mac}}");
+    }
+    Ok(())
+}
+
+pub fn is_valid_interface(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 32
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '@'))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RouterCommand {
+    pub template: CommandTemplate,
+    pub interface: String,
+}
+
+impl WolBackend for RouterCommand {
+    fn wake<'a>(&'a self, config: &'a Config, host: &'a HostConfig) -> WakeFuture<'a> {
+        Box::pin(async move {
+            let router = host
+                .router
+                .as_ref()
+                .ok_or_else(|| anyhow::anyhow!("для хоста '{}' не задан роутер", host.id))?;
+
+            let command = self.template.render(&host.mac, &self.interface, host.secureon.as_ref())?;
+            // Пароль SecureOn в логи и сообщения об ошибках не попадает
+            let display = match &host.secureon {
+                Some(password) => command.replace(&password.to_string(), "***"),
+                None => command.clone(),
+            };
+
+            ssh::exec(config, router, &command, &display).await?;
+            Ok(())
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct UdpSender {
+    pub broadcast: Ipv4Addr,
+    pub port: u16,
+}
+
+impl WolBackend for UdpSender {
+    fn wake<'a>(&'a self, _config: &'a Config, host: &'a HostConfig) -> WakeFuture<'a> {
+        Box::pin(async move { send_udp(&host.mac, host.secureon.as_ref(), self.broadcast, self.port) })
+    }
+}
+
+pub fn parse_mac(mac: &str) -> Result<[u8; 6]> {
+    let parts = mac.split([':', '-']).collect::<Vec<_>>();
+    if parts.len() != 6 {
+        anyhow::bail!("MAC-адрес '{}' должен состоять из 6 байт", mac);
+    }
+
+    let mut bytes = [0u8; 6];
+    for (byte, part) in bytes.iter_mut().zip(parts) {
+        if part.len() != 2 {
+            anyhow::bail!("некорректный байт '{}' в MAC-адресе '{}'", part, mac);
+        }
+        *byte = u8::from_str_radix(part, 16)
+            .map_err(|_| anyhow::anyhow!("некорректный байт '{}' в MAC-адресе '{}'", part, mac))?;
+    }
+    Ok(bytes)
+}
+
+pub fn format_mac(mac: &[u8; 6]) -> String {
+    mac.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(":")
+}
+
+// Пароль SecureOn: 4 байта (записывается как IPv4) или 6 байт (как MAC)
+#[derive(Clone, Debug, PartialEq)]
+pub struct SecureOn(Vec<u8>);
+
+impl SecureOn {
+    pub fn parse(password: &str) -> Result<Self> {
+        if let Ok(ip) = password.parse::<Ipv4Addr>() {
+            return Ok(SecureOn(ip.octets().to_vec()));
+        }
+        if let Ok(bytes) = parse_mac(password) {
+            return Ok(SecureOn(bytes.to_vec()));
+        }
+        anyhow::bail!(
+            "пароль SecureOn должен быть 4 байтами (1.2.3.4) или 6 байтами (aa:bb:cc:dd:ee:ff)"
+        )
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+// Формат, который понимают etherwake и ether-wake в ключе -p
+impl std::fmt::Display for SecureOn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0.as_slice() {
+            [a, b, c, d] => write!(f, "{}.{}.{}.{}", a, b, c, d),
+            bytes => {
+                let hex = bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>();
+                write!(f, "{}", hex.join(":"))
+            }
+        }
+    }
+}
+
+// 6 байт 0xFF и затем 16 повторов MAC — итого 102 байта, плюс пароль SecureOn, если задан
+pub fn magic_packet(mac: &[u8; 6], password: Option<&SecureOn>) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(108);
+    packet.extend_from_slice(&[0xFF; 6]);
+    for _ in 0..16 {
+        packet.extend_from_slice(mac);
+    }
+    if let Some(password) = password {
+        packet.extend_from_slice(password.bytes());
+    }
+    packet
+}
+
+pub fn send_udp(mac: &str, password: Option<&SecureOn>, broadcast: Ipv4Addr, port: u16) -> Result<()> {
+    let packet = magic_packet(&parse_mac(mac)?, password);
+
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_broadcast(true)?;
+
+    let target = SocketAddrV4::new(broadcast, port);
+    log::info!("Отправляем magic packet для {} на {}", mac, target);
+    let sent = socket.send_to(&packet, target)?;
+    if sent != packet.len() {
+        anyhow::bail!("отправлено {} из {} байт magic packet", sent, packet.len());
+    }
+    Ok(())
+}